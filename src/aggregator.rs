@@ -1,72 +1,402 @@
-use crate::types::{Sample, Report, FunctionStats};
+use crate::types::{Sample, Report, FunctionStats, StackFrame, TimedSample};
+use crate::calltree::CallTree;
+use crate::demangle::{format_name, NameStyle};
+use crate::modules::ModuleAddress;
 use crate::symbols::SymbolResolver;
+use crate::symcache::InlineFrame;
+use anyhow::{Context, Result};
+use clap::ValueEnum;
 use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::io::BufRead;
+use std::path::Path;
+
+/// What the entries of a report stand for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum Granularity {
+    /// One entry per function
+    #[default]
+    Function,
+    /// One entry per source line of a function
+    Line,
+    /// One entry per instruction
+    Address,
+    /// One entry per binary (executable or shared library)
+    Module,
+}
+
+/// Where the code of a frame comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum FrameKind {
+    /// Machine code of a binary running in user space
+    #[default]
+    User,
+    /// Only read back from folded stacks: samples are taken through ptrace,
+    /// which never stops a thread in the kernel
+    Kernel,
+    /// Code generated at run time, named through perf maps or jitdump
+    Jit,
+    /// A function inlined into its caller, which has no frame of its own
+    Inlined,
+    /// A function run by the CPython interpreter
+    Python,
+}
+
+impl FrameKind {
+    /// The suffix flamegraph.pl and inferno use to colour these frames
+    fn suffix(self) -> &'static str {
+        match self {
+            FrameKind::Kernel => "_[k]",
+            FrameKind::Jit => "_[j]",
+            FrameKind::Inlined => "_[i]",
+            FrameKind::User | FrameKind::Python => "",
+        }
+    }
+}
+
+/// One report entry: a function, source line, instruction or binary
+#[derive(Debug, Clone, Default)]
+pub struct FrameKey {
+    pub name: String,
+    pub file: Option<String>,
+    pub line: Option<u32>,
+    /// Offset of the instruction in its binary
+    pub address: Option<u64>,
+    /// How the frame is shown; not part of what it is, so a function inlined at
+    /// one call site and called at another is still one entry
+    pub kind: FrameKind,
+}
+
+impl PartialEq for FrameKey {
+    fn eq(&self, other: &Self) -> bool {
+        (&self.name, &self.file, self.line, self.address) == (&other.name, &other.file, other.line, other.address)
+    }
+}
+
+impl Eq for FrameKey {}
+
+impl Hash for FrameKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (&self.name, &self.file, self.line, self.address).hash(state);
+    }
+}
+
+impl FrameKey {
+    pub fn function(name: impl Into<String>) -> Self {
+        Self { name: name.into(), ..Default::default() }
+    }
+
+    /// The frame as it appears in folded stacks, e.g. `parse (parser.c:42)`, with
+    /// `_[k]`, `_[j]` or `_[i]` appended for kernel, JIT and inlined frames.
+    pub fn label(&self) -> String {
+        let mut label = self.name.clone();
+        if let Some(address) = self.address {
+            label.push_str(&format!(" [0x{:x}]", address));
+        }
+        match (&self.file, self.line) {
+            (Some(file), Some(line)) => label.push_str(&format!(" ({}:{})", file, line)),
+            (Some(file), None) => label.push_str(&format!(" ({})", file)),
+            _ => {}
+        }
+        label.push_str(self.kind.suffix());
+        label
+    }
+
+    /// The frame with its name shown in `style`. Frames are keyed by the name
+    /// found in the binary, so overloads and template instances stay apart;
+    /// the style only applies to what a report shows.
+    pub fn styled(&self, style: NameStyle) -> FrameKey {
+        match self.kind {
+            // Python names are not linkage names
+            FrameKind::Python => self.clone(),
+            _ => FrameKey { name: format_name(&self.name, style), ..self.clone() },
+        }
+    }
+
+    /// Reads back a frame written by [`FrameKey::label`].
+    pub fn parse(label: &str) -> Self {
+        let mut key = FrameKey::default();
+        let mut rest = label;
+        for kind in [FrameKind::Kernel, FrameKind::Jit, FrameKind::Inlined] {
+            if let Some(stripped) = rest.strip_suffix(kind.suffix()) {
+                key.kind = kind;
+                rest = stripped;
+                break;
+            }
+        }
+        if let Some(start) = rest.strip_suffix(')').and_then(|r| r.rfind(" (")) {
+            let source = &rest[start + 2..rest.len() - 1];
+            match source.rsplit_once(':').and_then(|(file, line)| Some((file, line.parse().ok()?))) {
+                Some((file, line)) => (key.file, key.line) = (Some(file.to_string()), Some(line)),
+                None => key.file = Some(source.to_string()),
+            }
+            rest = &rest[..start];
+        }
+        if let Some(start) = rest.strip_suffix(']').and_then(|r| r.rfind(" [0x")) {
+            if let Ok(address) = u64::from_str_radix(&rest[start + 4..rest.len() - 1], 16) {
+                key.address = Some(address);
+                rest = &rest[..start];
+            }
+        }
+        key.name = rest.to_string();
+        key
+    }
+}
 
 pub struct Aggregator {
-    pub(crate) counts: HashMap<String, usize>,
+    /// Events counted in samples that stopped in each frame
+    pub(crate) counts: HashMap<FrameKey, usize>,
+    /// Number of samples that stopped in each frame
+    pub(crate) sample_counts: HashMap<FrameKey, usize>,
+    /// Weight of each call stack, root first
+    pub(crate) stacks: HashMap<Vec<FrameKey>, usize>,
+    samples: Vec<Sample>,
+    timeline: Vec<TimedSample>,
     resolver: SymbolResolver,
+    name_style: NameStyle,
+    granularity: Granularity,
+}
+
+impl Default for Aggregator {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Aggregator {
     pub fn new() -> Self {
+        Self::with_resolver(SymbolResolver::new())
+    }
+
+    pub fn with_resolver(resolver: SymbolResolver) -> Self {
         Self {
             counts: HashMap::new(),
-            resolver: SymbolResolver::new(),
+            sample_counts: HashMap::new(),
+            stacks: HashMap::new(),
+            samples: Vec::new(),
+            timeline: Vec::new(),
+            resolver,
+            name_style: NameStyle::default(),
+            granularity: Granularity::default(),
         }
     }
 
-    pub(crate) fn fold_stack(&mut self, pid: u32, ip: u64) -> String {
-        match self.resolver.resolve(pid, ip) {
-            Ok(info) => {
-                if let Some(name) = info.function {
-                    name
-                } else {
-                    format!("unknown_0x{:x}", ip)
+    /// Sets how function names are shown in the report; fully demangled by default.
+    pub fn with_name_style(mut self, style: NameStyle) -> Self {
+        self.name_style = style;
+        self
+    }
+
+    /// Sets what samples are grouped by; functions by default.
+    pub fn with_granularity(mut self, granularity: Granularity) -> Self {
+        self.granularity = granularity;
+        self
+    }
+
+    /// Returns the frames of one native stack entry, innermost first: the
+    /// function at the address, then the functions it was inlined into.
+    fn frame_keys(&mut self, pid: u32, address: u64, location: Option<ModuleAddress>) -> Vec<FrameKey> {
+        let location = location.or_else(|| self.resolver.locate(pid, address));
+        if self.granularity == Granularity::Module {
+            let module = location.and_then(|location| self.resolver.module(location.module));
+            return vec![FrameKey::function(match module {
+                Some(module) => match Path::new(&module.name).file_name() {
+                    Some(file_name) => file_name.to_string_lossy().into_owned(),
+                    None => module.name.clone(),
+                },
+                None => "[unknown]".to_string(),
+            })];
+        }
+
+        // Only JIT code resolves without a binary
+        let (info, kind) = match location {
+            Some(location) => (self.resolver.resolve_location(location).ok(), FrameKind::User),
+            None => (self.resolver.resolve_jit(pid, address), FrameKind::Jit),
+        };
+        let Some(info) = info.filter(|info| info.function.is_some()) else {
+            return vec![FrameKey::function(format!("unknown_0x{:x}", address))];
+        };
+
+        let innermost = InlineFrame { function: info.function, file: info.file, line: info.line };
+        let chain_len = info.inlined_into.len() + 1;
+        std::iter::once(innermost)
+            .chain(info.inlined_into)
+            .enumerate()
+            .map(|(i, frame)| {
+                let mut key = FrameKey::function(match frame.function {
+                    Some(name) => name,
+                    None => format!("unknown_0x{:x}", address),
+                });
+                // All but the outermost function of the chain were inlined
+                key.kind = if i + 1 < chain_len { FrameKind::Inlined } else { kind };
+                if self.granularity != Granularity::Function {
+                    key.file = frame.file;
+                    key.line = frame.line;
                 }
+                if self.granularity == Granularity::Address {
+                    key.address = Some(location.map_or(address, |location| location.offset));
+                }
+                key
+            })
+            .collect()
+    }
+
+    fn python_frame_key(&self, function: &str, file: &str, line: Option<u32>) -> FrameKey {
+        let key = match self.granularity {
+            Granularity::Function => FrameKey::function(function),
+            Granularity::Line | Granularity::Address => {
+                FrameKey { name: function.to_string(), file: Some(file.to_string()), line, ..Default::default() }
             }
-            Err(_) => format!("unknown_0x{:x}", ip),
-        }
+            Granularity::Module => return FrameKey::function(file),
+        };
+        FrameKey { kind: FrameKind::Python, ..key }
     }
 
-    pub fn process_samples(&mut self, samples: Vec<Sample>) {
-        if samples.len() < 2 {
-            for sample in samples {
-                let key = self.fold_stack(sample.pid as u32, sample.instruction_pointer);
-                *self.counts.entry(key).or_insert(0) += 1;
+    /// Returns the frames of a sample, outermost first.
+    pub fn sample_frames(&mut self, sample: &Sample) -> Vec<FrameKey> {
+        let pid = sample.pid as u32;
+        if sample.stack.is_empty() {
+            let mut frames = self.frame_keys(pid, sample.instruction_pointer, sample.location);
+            frames.reverse();
+            return frames;
+        }
+        let mut frames = Vec::new();
+        for frame in &sample.stack {
+            match frame {
+                StackFrame::Native { address, location } => frames.extend(self.frame_keys(pid, *address, *location)),
+                StackFrame::Python { function, file, line } => frames.push(self.python_frame_key(function, file, *line)),
             }
+        }
+        frames.reverse();
+        frames
+    }
+
+    /// Adds a sample, weighted by the events counted since the previous sample of its thread.
+    fn add(&mut self, sample: &Sample) {
+        let frames = self.sample_frames(sample);
+        self.add_stack(&frames, sample.period as usize, 1);
+        self.timeline.push(TimedSample {
+            timestamp: sample.timestamp,
+            pid: sample.pid,
+            tid: sample.tid,
+            thread_name: sample.thread_name.clone(),
+            weight: sample.period as usize,
+            stack: frames,
+        });
+    }
+
+    /// Adds `samples` samples of `frames` (outermost first) weighing `weight` events in all.
+    fn add_stack(&mut self, frames: &[FrameKey], weight: usize, samples: usize) {
+        let Some(leaf) = frames.last() else {
             return;
+        };
+        *self.counts.entry(leaf.clone()).or_insert(0) += weight;
+        *self.sample_counts.entry(leaf.clone()).or_insert(0) += samples;
+        *self.stacks.entry(frames.to_vec()).or_insert(0) += weight;
+    }
+
+    /// Adds the stacks of a folded file (`frame;frame;frame count` lines, as
+    /// written by `--output stacks.folded`, inferno or stackcollapse-perf.pl).
+    ///
+    /// Folded files don't tell samples and events apart; each count is taken as both.
+    pub fn read_folded(&mut self, reader: impl BufRead) -> Result<()> {
+        for (number, line) in reader.lines().enumerate() {
+            let line = line?;
+            let line = line.trim_end();
+            if line.is_empty() {
+                continue;
+            }
+            let (stack, count) = line
+                .rsplit_once(' ')
+                .and_then(|(stack, count)| Some((stack, count.parse::<usize>().ok()?)))
+                .with_context(|| format!("Line {}: expected `frame;frame count`, got {:?}", number + 1, line))?;
+            let frames: Vec<FrameKey> = stack.split(';').map(FrameKey::parse).collect();
+            self.add_stack(&frames, count, count);
         }
+        Ok(())
+    }
 
-        for i in 1..samples.len() {
-            let prev = &samples[i-1];
-            let curr = &samples[i];
-            let delta = curr.value.saturating_sub(prev.value);
-            
-            let key = self.fold_stack(curr.pid as u32, curr.instruction_pointer);
-            // We use delta as the weight. If delta is 0 (e.g. no cycles occurred), we don't add anything.
-            // However, to ensure wall-clock sampling still works if delta is 0 but it took time, 
-            // we could add a minimum of 1, but for hardware counters delta is usually the point.
-            *self.counts.entry(key).or_insert(0) += delta as usize;
+    pub fn process_samples(&mut self, samples: Vec<Sample>) {
+        for sample in &samples {
+            self.add(sample);
         }
+        self.samples.extend(samples);
     }
 
-    pub fn generate_report(&self) -> Report {
-        let total_samples = self.counts.values().sum();
+    /// The resolver the samples were symbolized with, for exporters that need
+    /// more than the report (addresses, mappings, inline frames).
+    pub fn resolver_mut(&mut self) -> &mut SymbolResolver {
+        &mut self.resolver
+    }
+
+    /// How function names are shown in the report.
+    pub fn name_style(&self) -> NameStyle {
+        self.name_style
+    }
+
+    /// Builds the report of everything added since the last report. Every
+    /// accumulator moves into it rather than being copied, so the next report
+    /// starts over from nothing.
+    pub fn generate_report(&mut self) -> Report {
+        let counts = std::mem::take(&mut self.counts);
+        let sample_counts = std::mem::take(&mut self.sample_counts);
+        let stacks = std::mem::take(&mut self.stacks);
+        let name_style = self.name_style;
+        let style = |frame: &FrameKey| frame.styled(name_style);
+        let total_events = counts.values().sum();
+        // Frames are only merged by the names shown once they are styled, so
+        // every output agrees on what is one function
+        let mut call_tree = CallTree::new();
+        let mut folded: HashMap<String, usize> = HashMap::new();
+        for (stack, count) in &stacks {
+            let stack: Vec<FrameKey> = stack.iter().map(style).collect();
+            call_tree.add(&stack, *count);
+            *folded.entry(stack.iter().map(FrameKey::label).collect::<Vec<_>>().join(";")).or_insert(0) += count;
+        }
+        let mut own: HashMap<FrameKey, (usize, usize)> = HashMap::new();
+        for (key, count) in &counts {
+            own.entry(style(key)).or_default().0 += count;
+        }
+        for (key, samples) in &sample_counts {
+            own.entry(style(key)).or_default().1 += samples;
+        }
+        let mut totals = call_tree.totals();
+        // Functions that only ever called others have no self cost, but still get a row
+        for (key, (count, _)) in &own {
+            totals.entry(key.clone()).or_insert(*count);
+        }
         let mut stats = Vec::new();
-        for (name, count) in &self.counts {
-            let percentage = (*count as f64 / total_samples as f64) * 100.0;
+        for (key, total_count) in totals {
+            let (count, samples) = own.get(&key).copied().unwrap_or_default();
+            let percentage = (count as f64 / total_events as f64) * 100.0;
             stats.push(FunctionStats {
-                name: name.clone(),
-                count: *count,
+                name: key.name,
+                total_count,
+                file: key.file,
+                line: key.line,
+                address: key.address,
+                count,
+                samples,
                 percentage,
             });
         }
-        stats.sort_by(|a, b| b.count.cmp(&a.count));
-        let folded_stacks: Vec<String> = self.counts.keys().cloned().collect();
+        stats.sort_by_key(|s| std::cmp::Reverse((s.count, s.total_count)));
+        let mut folded_stacks: Vec<(String, usize)> = folded.into_iter().collect();
+        folded_stacks.sort();
+        let timeline = std::mem::take(&mut self.timeline)
+            .into_iter()
+            .map(|sample| TimedSample { stack: sample.stack.iter().map(style).collect(), ..sample })
+            .collect();
         Report {
-            total_samples,
+            total_events,
+            sample_count: sample_counts.values().sum(),
             stats,
             folded_stacks,
+            samples: std::mem::take(&mut self.samples),
+            timeline,
+            call_tree,
+            memory: None,
+            metadata: None,
         }
     }
 }
@@ -78,12 +408,12 @@ mod tests {
     #[test]
     fn test_aggregation_counts() {
             let mut agg = Aggregator::new();
-            agg.counts.insert("main".to_string(), 10);
-            agg.counts.insert("foo".to_string(), 5);
+            agg.counts.insert(FrameKey::function("main"), 10);
+            agg.counts.insert(FrameKey::function("foo"), 5);
 
             let report = agg.generate_report();
             
-            assert_eq!(report.total_samples, 15);
+            assert_eq!(report.total_events, 15);
             assert_eq!(report.stats[0].name, "main");
             assert_eq!(report.stats[0].count, 10);
             assert_eq!(report.stats[1].name, "foo");
@@ -94,8 +424,97 @@ mod tests {
         fn test_unknown_folding() {
             let mut agg = Aggregator::new();
             let pid = std::process::id(); 
-            let folded = agg.fold_stack(pid, 0xdeadbeef);
+            let folded = agg.frame_keys(pid, 0xdeadbeef, None)[0].label();
             assert!(folded.contains("unknown"));
             assert!(folded.contains("deadbeef"));
         }
+
+        #[test]
+        fn test_frame_key_labels() {
+            assert_eq!(FrameKey::function("main").label(), "main");
+            let line = FrameKey { name: "parse".into(), file: Some("parser.c".into()), line: Some(42), ..Default::default() };
+            assert_eq!(line.label(), "parse (parser.c:42)");
+            let address = FrameKey { address: Some(0x1a2b), ..line.clone() };
+            assert_eq!(address.label(), "parse [0x1a2b] (parser.c:42)");
+            let inlined = FrameKey { kind: FrameKind::Inlined, ..FrameKey::function("helper") };
+            assert_eq!(inlined.label(), "helper_[i]");
+
+            for key in [line, address, inlined, FrameKey::function("(anonymous namespace)::run")] {
+                let parsed = FrameKey::parse(&key.label());
+                assert_eq!((parsed.kind, parsed), (key.kind, key));
+            }
+        }
+
+        #[test]
+        fn test_read_folded() {
+            let folded = "main;parse (parser.c:42);lex_[i] 3\nmain;eval 5\n\nmain;parse (parser.c:42) 2\n";
+            let mut agg = Aggregator::new();
+            agg.read_folded(folded.as_bytes()).unwrap();
+            let report = agg.generate_report();
+            assert_eq!(report.total_events, 10);
+            assert_eq!(report.stats[0].name, "eval");
+            // lex is inlined into parse, but is still the same function as when called
+            agg.read_folded("main;lex 1\n".as_bytes()).unwrap();
+            let lex = agg.generate_report().stats.into_iter().filter(|s| s.name == "lex").count();
+            assert_eq!(lex, 1);
+            // Callers without self cost get a row too
+            let main = report.stats.iter().find(|s| s.name == "main").unwrap();
+            assert_eq!((main.count, main.total_count), (0, 10));
+            assert_eq!(report.callers_callees("parse").total_count, 5);
+            assert_eq!(
+                report.folded_stacks,
+                vec![
+                    ("main;eval".to_string(), 5),
+                    ("main;parse (parser.c:42)".to_string(), 2),
+                    ("main;parse (parser.c:42);lex_[i]".to_string(), 3),
+                ]
+            );
+            assert!(agg.read_folded("main;eval five".as_bytes()).is_err());
+        }
+
+        #[test]
+        fn test_name_style_merges_overloads() {
+            let mut agg = Aggregator::new().with_name_style(NameStyle::Simplified);
+            agg.read_folded("main;_Z5parsei 3\nmain;_Z5parsed 2\n".as_bytes()).unwrap();
+            let report = agg.generate_report();
+            // Overloads share their short name, hence one row as in the flame graph
+            let rows: Vec<(&str, usize, usize)> =
+                report.stats.iter().map(|s| (s.name.as_str(), s.count, s.total_count)).collect();
+            assert_eq!(rows, [("parse", 5, 5), ("main", 0, 5)]);
+            assert_eq!(report.folded_stacks, vec![("main;parse".to_string(), 5)]);
+
+            let report = Aggregator::new().generate_report();
+            assert!(report.stats.is_empty());
+            let mut agg = Aggregator::new();
+            agg.read_folded("main;_Z5parsei 3\n".as_bytes()).unwrap();
+            assert_eq!(agg.generate_report().stats[0].name, "parse(int)");
+        }
+
+        #[test]
+        fn test_reports_start_over() {
+            let sample = |timestamp| Sample {
+                value: 0,
+                pid: std::process::id() as i32,
+                tid: std::process::id() as i32,
+                thread_name: None,
+                period: 4,
+                timestamp,
+                instruction_pointer: 0xdeadbeef,
+                location: None,
+                stack: Vec::new(),
+            };
+            let mut agg = Aggregator::new();
+            agg.process_samples(vec![sample(1), sample(2)]);
+            let first = agg.generate_report();
+            assert_eq!((first.total_events, first.sample_count), (8, 2));
+            assert_eq!((first.samples.len(), first.timeline.len()), (2, 2));
+
+            // The next report has what came since, and nothing of the first one
+            agg.process_samples(vec![sample(3)]);
+            let second = agg.generate_report();
+            assert_eq!((second.total_events, second.sample_count), (4, 1));
+            assert_eq!((second.samples.len(), second.timeline.len()), (1, 1));
+            assert_eq!(second.stats[0].total_count, 4);
+            assert_eq!(second.folded_stacks[0].1, 4);
+        }
     }
@@ -0,0 +1,197 @@
+use anyhow::{bail, Result};
+use iced_x86::{Decoder, DecoderOptions, Formatter, IntelFormatter};
+use std::collections::HashMap;
+use std::fs;
+
+use crate::demangle::{format_name, NameStyle};
+use crate::modules::ModuleAddress;
+use crate::symbols::SymbolResolver;
+use crate::types::{Sample, StackFrame};
+
+/// Cost of one source line of an annotated function
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceLine {
+    pub line: u32,
+    /// `None` when the source file could not be read
+    pub text: Option<String>,
+    pub count: usize,
+}
+
+/// Cost of one instruction of an annotated function
+#[derive(Debug, Clone, PartialEq)]
+pub struct AnnotatedInstruction {
+    /// Link-time address
+    pub address: u64,
+    pub text: String,
+    /// Line of the annotated function the instruction belongs to
+    pub line: Option<u32>,
+    pub count: usize,
+}
+
+/// Where the samples of one function landed, by source line and by instruction
+#[derive(Debug, Clone)]
+pub struct Annotation {
+    pub function: String,
+    pub file: Option<String>,
+    /// Weight of the samples in the function; line and instruction percentages are relative to it
+    pub count: usize,
+    /// Weight of all samples of the profile
+    pub total: usize,
+    pub lines: Vec<SourceLine>,
+    pub instructions: Vec<AnnotatedInstruction>,
+}
+
+/// Collects where samples landed, to annotate one function with them.
+pub struct Annotator {
+    resolver: SymbolResolver,
+    function: String,
+    name_style: NameStyle,
+    /// Weight of the innermost frame of samples, by instruction
+    hits: HashMap<ModuleAddress, usize>,
+    total: usize,
+}
+
+impl Annotator {
+    /// `function` is matched against names in any [`NameStyle`].
+    pub fn new(resolver: SymbolResolver, function: impl Into<String>) -> Self {
+        Self { resolver, function: function.into(), name_style: NameStyle::default(), hits: HashMap::new(), total: 0 }
+    }
+
+    /// Sets how the function name is shown in the annotation.
+    pub fn with_name_style(mut self, style: NameStyle) -> Self {
+        self.name_style = style;
+        self
+    }
+
+    pub fn process_samples(&mut self, samples: Vec<Sample>) {
+        for sample in &samples {
+            self.add(sample);
+        }
+    }
+
+    fn add(&mut self, sample: &Sample) {
+        let weight = sample.period as usize;
+        self.total += weight;
+        let location = match sample.stack.first() {
+            Some(StackFrame::Native { location, .. }) => *location,
+            Some(StackFrame::Python { .. }) => None,
+            None => sample.location,
+        };
+        if let Some(location) =
+            location.or_else(|| self.resolver.locate(sample.pid as u32, sample.instruction_pointer))
+        {
+            *self.hits.entry(location).or_insert(0) += weight;
+        }
+    }
+
+    /// Whether the code at `location` is the function, or was inlined from it.
+    fn is_target(&mut self, location: ModuleAddress) -> bool {
+        let Ok(info) = self.resolver.resolve_location(location) else {
+            return false;
+        };
+        let mut names = info.function.into_iter().chain(info.inlined_into.into_iter().filter_map(|f| f.function));
+        names.any(|name| {
+            [NameStyle::Raw, NameStyle::Full, NameStyle::Simplified]
+                .into_iter()
+                .any(|style| format_name(&name, style) == self.function)
+        })
+    }
+
+    /// Disassembles the function and spreads its samples over its lines and instructions.
+    ///
+    /// A function that was inlined is shown as part of the function it was
+    /// inlined into.
+    pub fn annotate(&mut self) -> Result<Annotation> {
+        // A name can exist in several binaries; take the copy most samples landed in
+        let mut hits: Vec<(ModuleAddress, usize)> = self.hits.iter().map(|(l, c)| (*l, *c)).collect();
+        hits.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+        let Some(hottest) = hits.into_iter().map(|(location, _)| location).find(|l| self.is_target(*l)) else {
+            bail!("No samples in function {:?}", self.function);
+        };
+        let code = self.resolver.function_code(hottest)?;
+
+        let mut decoder = Decoder::with_ip(64, &code.bytes, code.address, DecoderOptions::NONE);
+        let mut formatter = IntelFormatter::new();
+        let mut instructions = Vec::new();
+        let mut files = Vec::new();
+        let mut function = None;
+        for instruction in decoder.iter() {
+            let location =
+                ModuleAddress { module: hottest.module, offset: code.offset + (instruction.ip() - code.address) };
+            // Inlined code is attributed to the line of the call it was inlined for
+            let (file, line) = match self.resolver.resolve_location(location) {
+                Ok(info) => {
+                    if function.is_none() {
+                        function = info.inlined_into.last().and_then(|f| f.function.clone()).or(info.function.clone());
+                    }
+                    match info.inlined_into.last() {
+                        Some(outer) => (outer.file.clone(), outer.line),
+                        None => (info.file, info.line),
+                    }
+                }
+                Err(_) => (None, None),
+            };
+            files.push(file);
+            let mut text = String::new();
+            formatter.format(&instruction, &mut text);
+            instructions.push(AnnotatedInstruction {
+                address: instruction.ip(),
+                text,
+                line,
+                count: self.hits.get(&location).copied().unwrap_or(0),
+            });
+        }
+
+        // The function's own file is where its first instruction is
+        let file = files.iter().flatten().next().cloned();
+        for (instruction, instruction_file) in instructions.iter_mut().zip(&files) {
+            if *instruction_file != file {
+                instruction.line = None;
+            }
+        }
+        let lines = source_lines(file.as_deref(), &instructions);
+        Ok(Annotation {
+            function: match function {
+                Some(name) => format_name(&name, self.name_style),
+                None => self.function.clone(),
+            },
+            file,
+            count: instructions.iter().map(|i| i.count).sum(),
+            total: self.total,
+            lines,
+            instructions,
+        })
+    }
+}
+
+/// Sums instruction costs per line, over the lines the function's code spans.
+fn source_lines(file: Option<&str>, instructions: &[AnnotatedInstruction]) -> Vec<SourceLine> {
+    let numbers = instructions.iter().filter_map(|i| i.line).filter(|&line| line > 0);
+    let (Some(first), Some(last)) = (numbers.clone().min(), numbers.max()) else {
+        return Vec::new();
+    };
+    let text = file.and_then(|file| fs::read_to_string(file).ok());
+    let text: Vec<&str> = text.as_deref().map(|t| t.lines().collect()).unwrap_or_default();
+    (first..=last)
+        .map(|line| SourceLine {
+            line,
+            text: text.get(line as usize - 1).map(|t| t.to_string()),
+            count: instructions.iter().filter(|i| i.line == Some(line)).map(|i| i.count).sum(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_source_lines() {
+        let instruction = |line, count| AnnotatedInstruction { address: 0, text: String::new(), line, count };
+        let instructions = [instruction(Some(12), 5), instruction(None, 1), instruction(Some(10), 2), instruction(Some(12), 3)];
+        let lines = source_lines(None, &instructions);
+        let costs: Vec<(u32, usize)> = lines.iter().map(|l| (l.line, l.count)).collect();
+        assert_eq!(costs, vec![(10, 2), (11, 0), (12, 8)]);
+        assert!(lines.iter().all(|l| l.text.is_none()));
+    }
+}
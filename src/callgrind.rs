@@ -0,0 +1,126 @@
+use anyhow::Result;
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+
+use crate::calltree::CallTree;
+use crate::types::Report;
+
+/// A function as callgrind sees it: a name in a file
+type Function = (String, Option<String>);
+
+/// Costs of one function, by line
+#[derive(Default)]
+struct FunctionCosts {
+    self_cost: BTreeMap<u32, usize>,
+    /// Calls made from a line to a function (entered at a line): how many
+    /// distinct call chains went through the call, and their inclusive cost
+    calls: BTreeMap<(u32, Function, u32), (usize, usize)>,
+}
+
+/// Gives names and files `(id) name` the first time and `(id)` afterwards,
+/// the name compression callgrind files use.
+#[derive(Default)]
+struct Names {
+    ids: HashMap<String, usize>,
+}
+
+impl Names {
+    fn get(&mut self, name: &str) -> String {
+        match self.ids.get(name) {
+            Some(id) => format!("({})", id),
+            None => {
+                let id = self.ids.len() + 1;
+                self.ids.insert(name.to_string(), id);
+                format!("({}) {}", id, name)
+            }
+        }
+    }
+}
+
+/// Sums the call tree into per-line self costs and inclusive call edges.
+fn function_costs(tree: &CallTree) -> BTreeMap<Function, FunctionCosts> {
+    let mut functions: BTreeMap<Function, FunctionCosts> = BTreeMap::new();
+    let mut pending = tree.children(CallTree::ROOT);
+    while let Some(id) = pending.pop() {
+        let node = tree.node(id);
+        let function = (node.frame.name.clone(), node.frame.file.clone());
+        let line = node.frame.line.unwrap_or(0);
+        let costs = functions.entry(function).or_default();
+        if node.self_count > 0 {
+            *costs.self_cost.entry(line).or_insert(0) += node.self_count;
+        }
+        for child in tree.children(id) {
+            let callee = &tree.node(child).frame;
+            let key = (line, (callee.name.clone(), callee.file.clone()), callee.line.unwrap_or(0));
+            let call = costs.calls.entry(key).or_insert((0, 0));
+            call.0 += 1;
+            call.1 += tree.node(child).total_count;
+            pending.push(child);
+        }
+    }
+    functions
+}
+
+/// Writes the call tree in callgrind's format, for KCachegrind and QCachegrind.
+///
+/// Positions are source lines, so lines are only known with `--granularity line`
+/// or `address`; otherwise every cost is on line 0. Sampling doesn't count calls,
+/// so each distinct call chain through a call counts as one call.
+pub fn write_callgrind(report: &Report, event: &str, out: &mut impl Write) -> Result<()> {
+    // Event names are identifiers
+    let event: String = event.chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }).collect();
+    writeln!(out, "# callgrind format")?;
+    writeln!(out, "version: 1")?;
+    writeln!(out, "creator: rrstat {}", env!("CARGO_PKG_VERSION"))?;
+    if let Some(metadata) = &report.metadata {
+        writeln!(out, "pid: {}", metadata.pid)?;
+        if let Some(command) = &metadata.command {
+            writeln!(out, "cmd: {}", command)?;
+        }
+    }
+    writeln!(out, "positions: line")?;
+    writeln!(out, "events: {}", event)?;
+    writeln!(out, "summary: {}", report.call_tree.node(CallTree::ROOT).total_count)?;
+
+    let mut files = Names::default();
+    let mut names = Names::default();
+    for ((name, file), costs) in function_costs(&report.call_tree) {
+        writeln!(out)?;
+        writeln!(out, "fl={}", files.get(file.as_deref().unwrap_or("???")))?;
+        writeln!(out, "fn={}", names.get(&name))?;
+        for (line, cost) in &costs.self_cost {
+            writeln!(out, "{} {}", line, cost)?;
+        }
+        for ((line, (callee, callee_file), callee_line), (calls, cost)) in &costs.calls {
+            if *callee_file != file {
+                writeln!(out, "cfi={}", files.get(callee_file.as_deref().unwrap_or("???")))?;
+            }
+            writeln!(out, "cfn={}", names.get(callee))?;
+            writeln!(out, "calls={} {}", calls, callee_line)?;
+            writeln!(out, "{} {}", line, cost)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aggregator::Aggregator;
+
+    #[test]
+    fn test_callgrind() {
+        let folded = "main (app.c:10);parse (parse.c:5) 3\nmain (app.c:12);parse (parse.c:7) 1\nmain (app.c:12) 2\n";
+        let mut aggregator = Aggregator::new();
+        aggregator.read_folded(folded.as_bytes()).unwrap();
+        let mut out = Vec::new();
+        write_callgrind(&aggregator.generate_report(), "cpu-clock", &mut out).unwrap();
+        let text = String::from_utf8(out).unwrap();
+
+        assert!(text.starts_with("# callgrind format\nversion: 1\n"));
+        assert!(text.contains("events: cpu_clock\nsummary: 6\n"));
+        let main = "fl=(1) app.c\nfn=(1) main\n12 2\ncfi=(2) parse.c\ncfn=(2) parse\ncalls=1 5\n10 3\ncfi=(2)\ncfn=(2)\ncalls=1 7\n12 1\n";
+        assert!(text.contains(main), "{}", text);
+        assert!(text.contains("fl=(2)\nfn=(2)\n5 3\n7 1\n"), "{}", text);
+    }
+}
@@ -0,0 +1,314 @@
+use std::collections::HashMap;
+
+use crate::aggregator::FrameKey;
+
+/// Index of a node in a [`CallTree`]
+pub type NodeId = usize;
+
+/// A frame reached through one particular chain of calls
+#[derive(Debug, Clone)]
+pub struct CallNode {
+    pub frame: FrameKey,
+    /// Weight of the samples whose stack ended here
+    pub self_count: usize,
+    /// Weight of the samples whose stack went through here
+    pub total_count: usize,
+    parent: Option<NodeId>,
+    children: HashMap<FrameKey, NodeId>,
+}
+
+/// Direct callers and callees of a function, with its self and total cost
+#[derive(Debug, Clone, PartialEq)]
+pub struct CallerCallee {
+    pub self_count: usize,
+    pub total_count: usize,
+    /// Cost of the function when called from each caller, highest first
+    pub callers: Vec<(FrameKey, usize)>,
+    /// Total cost of each function it calls, highest first
+    pub callees: Vec<(FrameKey, usize)>,
+}
+
+/// Call stacks merged into a tree, root first.
+///
+/// Recursion makes a function appear several times on one stack; the views of
+/// a function only start at its outermost calls so every sample is counted once.
+#[derive(Debug, Clone)]
+pub struct CallTree {
+    nodes: Vec<CallNode>,
+}
+
+impl Default for CallTree {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CallTree {
+    pub const ROOT: NodeId = 0;
+
+    pub fn new() -> Self {
+        let root = CallNode {
+            frame: FrameKey::function("[root]"),
+            self_count: 0,
+            total_count: 0,
+            parent: None,
+            children: HashMap::new(),
+        };
+        Self { nodes: vec![root] }
+    }
+
+    /// Adds a call stack, outermost frame first, ending in the sampled frame.
+    pub fn add(&mut self, stack: &[FrameKey], weight: usize) {
+        let node = self.insert(Self::ROOT, stack, weight);
+        self.nodes[node].self_count += weight;
+    }
+
+    /// Adds `weight` along `path` below `parent` and returns the last node.
+    fn insert(&mut self, parent: NodeId, path: &[FrameKey], weight: usize) -> NodeId {
+        self.nodes[parent].total_count += weight;
+        let mut node = parent;
+        for frame in path {
+            node = self.child(node, frame);
+            self.nodes[node].total_count += weight;
+        }
+        node
+    }
+
+    /// Finds or creates the child of `parent` for `frame`.
+    fn child(&mut self, parent: NodeId, frame: &FrameKey) -> NodeId {
+        if let Some(&child) = self.nodes[parent].children.get(frame) {
+            return child;
+        }
+        let child = self.nodes.len();
+        self.nodes.push(CallNode {
+            frame: frame.clone(),
+            self_count: 0,
+            total_count: 0,
+            parent: Some(parent),
+            children: HashMap::new(),
+        });
+        self.nodes[parent].children.insert(frame.clone(), child);
+        child
+    }
+
+    /// Number of nodes, the root included.
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn node(&self, id: NodeId) -> &CallNode {
+        &self.nodes[id]
+    }
+
+    /// Children of a node, most expensive first.
+    pub fn children(&self, id: NodeId) -> Vec<NodeId> {
+        let mut children: Vec<NodeId> = self.nodes[id].children.values().copied().collect();
+        children.sort_by_key(|&child| (std::cmp::Reverse(self.nodes[child].total_count), child));
+        children
+    }
+
+    /// Frames from the node up to (not including) the root.
+    fn ancestry(&self, id: NodeId) -> impl Iterator<Item = NodeId> + '_ {
+        std::iter::successors(Some(id), |&node| self.nodes[node].parent).take_while(|&node| node != Self::ROOT)
+    }
+
+    /// Nodes matching `matches` that are not called from another matching node.
+    fn outermost(&self, matches: &impl Fn(&FrameKey) -> bool) -> Vec<NodeId> {
+        (1..self.nodes.len())
+            .filter(|&id| matches(&self.nodes[id].frame))
+            .filter(|&id| !self.ancestry(id).skip(1).any(|node| matches(&self.nodes[node].frame)))
+            .collect()
+    }
+
+    /// Total cost of matching frames, counting each sample once.
+    pub fn total_count(&self, matches: impl Fn(&FrameKey) -> bool) -> usize {
+        self.outermost(&matches).iter().map(|&id| self.nodes[id].total_count).sum()
+    }
+
+    /// Total cost of every frame, counting each sample once.
+    pub fn totals(&self) -> HashMap<FrameKey, usize> {
+        let mut totals = HashMap::new();
+        let mut on_stack = HashMap::new();
+        self.collect_totals(Self::ROOT, &mut on_stack, &mut totals);
+        totals
+    }
+
+    fn collect_totals<'a>(
+        &'a self,
+        id: NodeId,
+        on_stack: &mut HashMap<&'a FrameKey, usize>,
+        totals: &mut HashMap<FrameKey, usize>,
+    ) {
+        for &child in self.nodes[id].children.values() {
+            let node = &self.nodes[child];
+            let depth = on_stack.entry(&node.frame).or_insert(0);
+            if *depth == 0 {
+                *totals.entry(node.frame.clone()).or_insert(0) += node.total_count;
+            }
+            *depth += 1;
+            self.collect_totals(child, on_stack, totals);
+            *on_stack.get_mut(&node.frame).unwrap() -= 1;
+        }
+    }
+
+    /// The bottom-up view: the same samples with every stack reversed, so the
+    /// top level holds the functions samples stopped in, with their self cost.
+    pub fn inverted(&self) -> CallTree {
+        let mut inverted = CallTree::new();
+        for id in 1..self.nodes.len() {
+            let weight = self.nodes[id].self_count;
+            if weight > 0 {
+                let path: Vec<FrameKey> = self.ancestry(id).map(|node| self.nodes[node].frame.clone()).collect();
+                inverted.add(&path, weight);
+            }
+        }
+        inverted
+    }
+
+    /// The top-down view of a function: everything it called, merged over all
+    /// the places it was called from.
+    pub fn callees_of(&self, matches: impl Fn(&FrameKey) -> bool) -> CallTree {
+        let mut tree = CallTree::new();
+        for id in self.outermost(&matches) {
+            self.merge_into(&mut tree, CallTree::ROOT, id);
+        }
+        tree
+    }
+
+    /// Copies the subtree of `id` below `parent` of `tree`.
+    fn merge_into(&self, tree: &mut CallTree, parent: NodeId, id: NodeId) {
+        let node = &self.nodes[id];
+        let copy = tree.child(parent, &node.frame);
+        tree.nodes[copy].self_count += node.self_count;
+        tree.nodes[copy].total_count += node.total_count;
+        if parent == CallTree::ROOT {
+            tree.nodes[parent].total_count += node.total_count;
+        }
+        for &child in node.children.values() {
+            self.merge_into(tree, copy, child);
+        }
+    }
+
+    /// The bottom-up view of a function: the chains of callers leading to it.
+    pub fn callers_of(&self, matches: impl Fn(&FrameKey) -> bool) -> CallTree {
+        let mut tree = CallTree::new();
+        for id in self.outermost(&matches) {
+            let path: Vec<FrameKey> = self.ancestry(id).map(|node| self.nodes[node].frame.clone()).collect();
+            tree.insert(CallTree::ROOT, &path, self.nodes[id].total_count);
+        }
+        tree
+    }
+
+    /// Direct callers and callees of a function.
+    pub fn caller_callee(&self, matches: impl Fn(&FrameKey) -> bool) -> CallerCallee {
+        let outermost = self.outermost(&matches);
+        let mut callers: HashMap<FrameKey, usize> = HashMap::new();
+        let mut callees: HashMap<FrameKey, usize> = HashMap::new();
+        for &id in &outermost {
+            let node = &self.nodes[id];
+            if let Some(parent) = node.parent.filter(|&parent| parent != Self::ROOT) {
+                *callers.entry(self.nodes[parent].frame.clone()).or_insert(0) += node.total_count;
+            }
+            for &child in node.children.values() {
+                let child = &self.nodes[child];
+                *callees.entry(child.frame.clone()).or_insert(0) += child.total_count;
+            }
+        }
+        let sorted = |map: HashMap<FrameKey, usize>| {
+            let mut entries: Vec<(FrameKey, usize)> = map.into_iter().collect();
+            entries.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.name.cmp(&b.0.name)));
+            entries
+        };
+        CallerCallee {
+            self_count: (1..self.nodes.len())
+                .filter(|&id| matches(&self.nodes[id].frame))
+                .map(|id| self.nodes[id].self_count)
+                .sum(),
+            total_count: outermost.iter().map(|&id| self.nodes[id].total_count).sum(),
+            callers: sorted(callers),
+            callees: sorted(callees),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stack(names: &str) -> Vec<FrameKey> {
+        names.split(';').map(FrameKey::function).collect()
+    }
+
+    fn named(name: &'static str) -> impl Fn(&FrameKey) -> bool {
+        move |frame| frame.name == name
+    }
+
+    fn sample_tree() -> CallTree {
+        let mut tree = CallTree::new();
+        tree.add(&stack("main;parse;lex"), 3);
+        tree.add(&stack("main;parse"), 1);
+        tree.add(&stack("main;eval;parse;lex"), 2);
+        tree.add(&stack("main;eval"), 4);
+        tree
+    }
+
+    #[test]
+    fn test_self_and_total() {
+        let tree = sample_tree();
+        assert_eq!(tree.node(CallTree::ROOT).total_count, 10);
+        let main = tree.children(CallTree::ROOT)[0];
+        assert_eq!((tree.node(main).self_count, tree.node(main).total_count), (0, 10));
+        let names: Vec<&str> = tree.children(main).iter().map(|&id| tree.node(id).frame.name.as_str()).collect();
+        assert_eq!(names, vec!["eval", "parse"]);
+        assert_eq!(tree.total_count(named("parse")), 6);
+        assert_eq!(tree.total_count(named("lex")), 5);
+        let totals = tree.totals();
+        assert_eq!(totals[&FrameKey::function("main")], 10);
+        assert_eq!(totals[&FrameKey::function("parse")], 6);
+        assert_eq!(totals[&FrameKey::function("eval")], 6);
+    }
+
+    #[test]
+    fn test_views() {
+        let tree = sample_tree();
+
+        let view = tree.caller_callee(named("parse"));
+        assert_eq!((view.self_count, view.total_count), (1, 6));
+        assert_eq!(view.callers, vec![(FrameKey::function("main"), 4), (FrameKey::function("eval"), 2)]);
+        assert_eq!(view.callees, vec![(FrameKey::function("lex"), 5)]);
+
+        let callees = tree.callees_of(named("parse"));
+        let parse = callees.children(CallTree::ROOT)[0];
+        assert_eq!(callees.node(CallTree::ROOT).total_count, 6);
+        assert_eq!((callees.node(parse).self_count, callees.node(parse).total_count), (1, 6));
+        let lex = callees.children(parse)[0];
+        assert_eq!((callees.node(lex).self_count, callees.node(lex).total_count), (5, 5));
+
+        let callers = tree.callers_of(named("lex"));
+        let lex = callers.children(CallTree::ROOT)[0];
+        let parse = callers.children(lex)[0];
+        let chains: Vec<(&str, usize)> =
+            callers.children(parse).iter().map(|&id| (callers.node(id).frame.name.as_str(), callers.node(id).total_count)).collect();
+        assert_eq!(chains, vec![("main", 3), ("eval", 2)]);
+
+        let inverted = tree.inverted();
+        let leaves: Vec<(&str, usize)> = inverted
+            .children(CallTree::ROOT)
+            .iter()
+            .map(|&id| (inverted.node(id).frame.name.as_str(), inverted.node(id).total_count))
+            .collect();
+        assert_eq!(leaves, vec![("lex", 5), ("eval", 4), ("parse", 1)]);
+    }
+
+    #[test]
+    fn test_recursion_counted_once() {
+        let mut tree = CallTree::new();
+        tree.add(&stack("main;walk;walk;walk"), 2);
+        tree.add(&stack("main;walk"), 1);
+        let view = tree.caller_callee(named("walk"));
+        assert_eq!((view.self_count, view.total_count), (3, 3));
+        assert_eq!(view.callers, vec![(FrameKey::function("main"), 3)]);
+        assert_eq!(view.callees, vec![(FrameKey::function("walk"), 2)]);
+        assert_eq!(tree.totals()[&FrameKey::function("walk")], 3);
+    }
+}
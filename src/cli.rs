@@ -1,11 +1,31 @@
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use perf_event::events::{Hardware, Event, Software};
 use anyhow::{bail, Result};
+use std::path::PathBuf;
+
+use crate::aggregator::Granularity;
+use crate::demangle::NameStyle;
+use crate::report::ReportFormat;
 
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 pub struct Args {
+    /// Absent only when a subcommand is given
+    #[command(flatten)]
+    pub profile: Option<ProfileArgs>,
+
+    #[command(flatten)]
+    pub report: ReportArgs,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// Options for sampling a process and naming what was sampled
+#[derive(clap::Args, Debug)]
+pub struct ProfileArgs {
     #[arg(short, long)]
     pub pid: i32,
 
@@ -14,6 +34,93 @@ pub struct Args {
 
     #[arg(short, long, default_value = "1000")]
     pub duration: u64,
+
+    /// Directory for symbolization results reused across runs (default: ~/.cache/rrstat)
+    #[arg(long)]
+    pub symbol_cache_dir: Option<PathBuf>,
+
+    /// Size limit of the symbol cache directory in MiB
+    #[arg(long, default_value = "512")]
+    pub symbol_cache_size: u64,
+
+    /// Resolve everything from the binaries, without reading or writing the symbol cache
+    #[arg(long)]
+    pub no_symbol_cache: bool,
+
+    /// How to show function names: as in the binary, fully demangled, or without
+    /// template arguments, argument lists and Rust hashes
+    #[arg(long, value_enum, default_value_t = NameStyle::Full)]
+    pub demangle: NameStyle,
+
+    /// What to aggregate samples by: functions, source lines, instructions or binaries
+    /// (`annotate` always breaks its function down by line and instruction)
+    #[arg(long, value_enum, default_value_t = Granularity::Function)]
+    pub granularity: Granularity,
+
+    /// Add a summary of the target's memory (RSS/PSS per mapping, from smaps) to the report
+    #[arg(long)]
+    pub memory: bool,
+}
+
+/// Options for what is shown and written once samples are aggregated
+#[derive(clap::Args, Debug)]
+pub struct ReportArgs {
+    /// How to print the report: tables, or JSON/CSV for scripts
+    #[arg(long, value_enum, default_value_t = ReportFormat::Text)]
+    pub format: ReportFormat,
+
+    /// Also show the callers and callees of this function, and the call trees
+    /// below and above it
+    #[arg(long)]
+    pub focus: Option<String>,
+
+    /// Also write the profile to this file; the format follows the extension
+    /// (`.svg`: interactive flame graph, `.folded`: folded stacks, `.pb.gz`: pprof,
+    /// `.speedscope.json`: speedscope, `.firefox.json`: Firefox Profiler, `callgrind.out*`: KCachegrind,
+    /// `.html`: self-contained report).
+    /// Can be given several times
+    #[arg(short, long)]
+    pub output: Vec<PathBuf>,
+
+    /// Draw flame graphs as icicle graphs, growing down from the root at the top
+    #[arg(long)]
+    pub icicle: bool,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Profile the target, then show the source of one function with the share
+    /// of samples on each line
+    Annotate {
+        /// Function to annotate, as named in the report
+        function: String,
+
+        /// Also show the disassembly with the share of samples on each instruction
+        #[arg(long)]
+        disassemble: bool,
+
+        #[command(flatten)]
+        profile: ProfileArgs,
+    },
+    /// Show a live, continuously updated view of the functions the target is
+    /// running, like `perf top`; `--duration` is not used
+    Top {
+        /// Milliseconds between screen updates
+        #[arg(long, default_value = "1000")]
+        refresh: u64,
+
+        #[command(flatten)]
+        profile: ProfileArgs,
+    },
+    /// Show the report of a folded-stack file (`frame;frame;frame count` lines),
+    /// e.g. one written earlier with `--output stacks.folded`
+    Report {
+        /// Folded-stack file to read
+        input: PathBuf,
+
+        #[command(flatten)]
+        report: ReportArgs,
+    },
 }
 
 pub fn parse_event(event_name: &str) -> Result<Event> {
@@ -40,6 +147,42 @@ mod tests {
         assert!(event.is_ok());
     }
 
+    #[test]
+    fn test_subcommands() {
+        let args = Args::parse_from(["rrstat", "--pid", "42", "--granularity", "line", "-o", "a.svg", "-o", "b.svg", "--format", "json"]);
+        assert_eq!(args.report.format, ReportFormat::Json);
+        assert_eq!(args.profile.map(|p| (p.pid, p.granularity)), Some((42, Granularity::Line)));
+        assert_eq!(args.report.output, vec![PathBuf::from("a.svg"), PathBuf::from("b.svg")]);
+        assert!(args.command.is_none());
+
+        let args = Args::parse_from(["rrstat", "annotate", "hot_function", "--pid", "42", "--disassemble"]);
+        match args.command {
+            Some(Command::Annotate { function, disassemble, profile }) => {
+                assert_eq!(function, "hot_function");
+                assert!(disassemble);
+                assert_eq!(profile.pid, 42);
+            }
+            _ => panic!("annotate was not parsed"),
+        }
+        let args = Args::parse_from(["rrstat", "top", "-p", "42", "--refresh", "500", "--granularity", "module"]);
+        match args.command {
+            Some(Command::Top { refresh, profile }) => {
+                assert_eq!((refresh, profile.pid, profile.granularity), (500, 42, Granularity::Module))
+            }
+            _ => panic!("top was not parsed"),
+        }
+        let args = Args::parse_from(["rrstat", "report", "stacks.folded", "--focus", "parse"]);
+        match args.command {
+            Some(Command::Report { input, report }) => {
+                assert_eq!(input, PathBuf::from("stacks.folded"));
+                assert_eq!(report.focus.as_deref(), Some("parse"));
+                assert_eq!(report.format, ReportFormat::Text);
+            }
+            _ => panic!("report was not parsed"),
+        }
+        assert!(Args::try_parse_from(["rrstat", "annotate", "hot_function"]).is_err());
+    }
+
     #[test]
     fn test_parse_invalid() {
         let event = parse_event("invalid-event-name");
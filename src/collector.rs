@@ -2,24 +2,52 @@ use std::sync::{Arc, atomic::{AtomicBool, Ordering}};
 use std::thread;
 use std::time::{Duration, Instant};
 use perf_event::Counter;
+use perf_event::events::Event;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::fs;
 
 use crate::types::Sample;
 use crate::ringbuffer::RingBuffer;
+use crate::memory;
+use crate::modules::{AddressNormalizer, ModuleTable};
+use crate::mmap_events::MmapWatcher;
+use crate::profiler::PerfCounter;
+use crate::python::PythonUnwinder;
+use crate::unwind::{NativeUnwinder, Registers};
 
 use libc;
 
-fn read_instruction_pointer(pid: i32) -> u64 {
+/// Average time between two samples of the same thread. Each wait is drawn
+/// between half and one and a half of it, so samples don't keep landing at the
+/// same points of periodic work (or of each second, for the heatmap).
+pub const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Wait before the next poll, from a xorshift generator's `state`
+fn poll_delay(state: &mut u64) -> Duration {
+    *state ^= *state << 13;
+    *state ^= *state >> 7;
+    *state ^= *state << 17;
+    let jitter = *state % POLL_INTERVAL.as_micros() as u64;
+    POLL_INTERVAL / 2 + Duration::from_micros(jitter)
+}
+
+/// Stops the thread, hands its registers to `inspect` and lets it continue.
+///
+/// Everything that reads the target's stack has to happen inside `inspect`,
+/// while the stack cannot change under us.
+fn with_stopped_thread<T>(pid: i32, inspect: impl FnOnce(&libc::user_regs_struct) -> T) -> Option<T> {
     unsafe {
         // attach to the process to stop it
         if libc::ptrace(libc::PTRACE_ATTACH, pid, 0, 0) < 0 {
-            return 0;
+            return None;
         }
 
-        // wait for the process to stop
+        // wait for the process to stop; __WALL is needed to wait for threads other than the main one
         let mut status = 0;
-        if libc::waitpid(pid, &mut status, 0) < 0 {
+        if libc::waitpid(pid, &mut status, libc::__WALL) < 0 {
             libc::ptrace(libc::PTRACE_DETACH, pid, 0, 0);
-            return 0;
+            return None;
         }
 
         let mut regs: libc::user_regs_struct = std::mem::zeroed();
@@ -30,47 +58,192 @@ fn read_instruction_pointer(pid: i32) -> u64 {
             &mut regs as *mut _ as *mut libc::c_void,
         );
 
+        let result = (res >= 0).then(|| inspect(&regs));
         libc::ptrace(libc::PTRACE_DETACH, pid, 0, 0);
-
-        if res < 0 {
-            return 0;
-        }
-        regs.rip
+        result
     }
 }
 
-fn parse_sample(value: u64, pid: i32, start_time: Instant, ip: u64) -> Sample {
+fn parse_sample(value: u64, period: u64, pid: i32, tid: i32, start_time: Instant, ip: u64) -> Sample {
     Sample {
         value,
         pid,
+        tid,
+        thread_name: fs::read_to_string(format!("/proc/{}/task/{}/comm", pid, tid)).ok().map(|name| name.trim_end().to_string()),
+        period,
         timestamp: start_time.elapsed().as_millis() as u64,
         instruction_pointer: ip,
+        location: None,
+        stack: Vec::new(),
     }
 }
 
-pub struct Collector {
+/// Threads of the target, read from `/proc/<pid>/task`
+pub fn list_threads(pid: i32) -> std::io::Result<Vec<i32>> {
+    let mut tids = Vec::new();
+    for entry in fs::read_dir(format!("/proc/{}/task", pid))? {
+        if let Some(tid) = entry?.file_name().to_str().and_then(|name| name.parse().ok()) {
+            tids.push(tid);
+        }
+    }
+    tids.sort_unstable();
+    Ok(tids)
+}
+
+/// Counter of one thread, and its reading at the thread's previous sample
+struct ThreadCounter {
     counter: Counter,
+    last: u64,
+}
+
+pub struct Collector {
+    threads: HashMap<i32, ThreadCounter>,
+    /// Event counted on threads besides the main one; `None` samples the main thread only
+    event: Option<Event>,
     buffer: Arc<RingBuffer>,
     running: Arc<AtomicBool>,
     pid: i32,
+    normalizer: AddressNormalizer,
+    /// Watchers of the threads mmap events could be opened for
+    mmap_watchers: HashMap<i32, MmapWatcher>,
+    unwinder: NativeUnwinder,
+    python: Option<PythonUnwinder>,
 }
 
 /// Collector polls the counter and pushes samples to the ring buffer
 impl Collector {
+    /// `counter` counts the events of the main thread, `pid`.
     pub fn new(counter: Counter, buffer: Arc<RingBuffer>, running: Arc<AtomicBool>, pid: i32) -> Self {
-        Self { counter, buffer, running, pid }
+        // Snapshot the mappings up front; mmap events (where permitted) keep them current
+        let mut normalizer = AddressNormalizer::new(ModuleTable::global());
+        let _ = normalizer.track(pid as u32);
+        let mmap_watchers = MmapWatcher::new(pid).map(|watcher| HashMap::from([(pid, watcher)])).unwrap_or_default();
+        // Python stacks are only read from CPython processes
+        let python = PythonUnwinder::attach(pid as u32).ok().flatten();
+        Self {
+            threads: HashMap::from([(pid, ThreadCounter { counter, last: 0 })]),
+            event: None,
+            buffer,
+            running,
+            pid,
+            normalizer,
+            mmap_watchers,
+            unwinder: NativeUnwinder::new(ModuleTable::global()),
+            python,
+        }
+    }
+
+    /// Also samples the other threads of the process, each with its own counter of `event`.
+    pub fn with_threads(mut self, event: Event) -> Self {
+        self.event = Some(event);
+        self
+    }
+
+    /// Starts counting and watching mmaps on threads that appeared since the
+    /// last poll, and forgets those that exited.
+    fn refresh_threads(&mut self) {
+        let Ok(tids) = list_threads(self.pid) else {
+            return;
+        };
+        self.mmap_watchers.retain(|tid, _| tids.binary_search(tid).is_ok());
+        for &tid in &tids {
+            if let Entry::Vacant(entry) = self.mmap_watchers.entry(tid) {
+                if let Ok(watcher) = MmapWatcher::new(tid) {
+                    entry.insert(watcher);
+                }
+            }
+        }
+
+        let Some(event) = &self.event else {
+            return;
+        };
+        self.threads.retain(|tid, _| tids.binary_search(tid).is_ok());
+        for tid in tids {
+            if let Entry::Vacant(entry) = self.threads.entry(tid) {
+                // Threads can exit before we get to them
+                if let Ok(mut counter) = PerfCounter::new(tid, event.clone()) {
+                    if counter.enable().is_ok() {
+                        entry.insert(ThreadCounter { counter: counter.counter, last: 0 });
+                    }
+                }
+            }
+        }
+    }
+
+    /// Takes a sample of every thread that counted events since its previous one.
+    ///
+    /// The sample is weighted by those events, so threads are compared fairly
+    /// however much each of them ran.
+    fn sample_threads(&mut self, start_time: Instant) {
+        let pid = self.pid as u32;
+        let mut tids: Vec<i32> = self.threads.keys().copied().collect();
+        tids.sort_unstable();
+        for tid in tids {
+            let thread = self.threads.get_mut(&tid).unwrap();
+            let Ok(value) = thread.counter.read() else {
+                continue;
+            };
+            let period = value.saturating_sub(thread.last);
+            thread.last = value;
+            // A thread that did not run has nothing to attribute
+            if period == 0 {
+                continue;
+            }
+
+            let (normalizer, unwinder, python) = (&mut self.normalizer, &mut self.unwinder, &self.python);
+            let stopped = with_stopped_thread(tid, |regs| {
+                let regs = Registers { ip: regs.rip, sp: regs.rsp, bp: regs.rbp };
+                let native = unwinder.unwind(
+                    regs,
+                    |address| normalizer.normalize(pid, address),
+                    |address| memory::read_u64(pid, address).ok(),
+                );
+                let stack = match python.as_ref().map(|python| (python, python.stack(tid as u32))) {
+                    Some((python, Ok(segments))) => python.merge(native, segments),
+                    _ => native,
+                };
+                (regs.ip, stack)
+            });
+            let (ip, stack) = stopped.unwrap_or_default();
+            let mut sample = parse_sample(value, period, self.pid, tid, start_time, ip);
+            // Normalize while the mappings still exist, so symbolization is per binary
+            sample.location = self.normalizer.normalize(pid, ip);
+            sample.stack = stack;
+            self.buffer.push(sample);
+        }
+    }
+
+    /// Applies the mmaps the threads made since the last poll; if any of them
+    /// were lost, the mappings are read from `/proc` again instead.
+    fn apply_mmap_events(&mut self) {
+        let pid = self.pid as u32;
+        let mut lost = false;
+        for watcher in self.mmap_watchers.values_mut() {
+            let records = watcher.poll();
+            lost |= records.lost;
+            for mapping in records.mappings {
+                self.normalizer.apply_mmap(pid, mapping);
+            }
+        }
+        if lost {
+            // Keep the last snapshot if the process is gone
+            let _ = self.normalizer.track(pid);
+        }
     }
 
    /// New thread collects samples, while main can handle Ctrl+C -> it's unblocked
     pub fn spawn(mut self) -> thread::JoinHandle<()> {
         thread::spawn(move || {
             let start_time = Instant::now();
+            // Any non-zero seed will do; the time keeps runs from repeating each other
+            let mut rng = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(1, |elapsed| elapsed.as_nanos() as u64 | 1);
             while self.running.load(Ordering::Relaxed) {
-                thread::sleep(Duration::from_millis(100));
-                let val = self.counter.read().unwrap();
-                let ip = read_instruction_pointer(self.pid);
-                let sample = parse_sample(val, self.pid, start_time, ip);
-                self.buffer.push(sample);
+                thread::sleep(poll_delay(&mut rng));
+                self.apply_mmap_events();
+                self.refresh_threads();
+                self.sample_threads(start_time);
             }
         })
     }
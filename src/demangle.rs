@@ -0,0 +1,148 @@
+use clap::ValueEnum;
+
+/// How function names appear in reports
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum NameStyle {
+    /// Linkage names as found in the binary, e.g. `_ZNSt6vectorIiSaIiEE9push_backERKi`
+    Raw,
+    /// Demangled with template arguments, argument lists and Rust hashes
+    #[default]
+    Full,
+    /// Demangled without template arguments, argument lists and Rust hashes
+    Simplified,
+}
+
+/// Formats a linkage name (mangled or not) in the given style.
+pub fn format_name(name: &str, style: NameStyle) -> String {
+    match style {
+        NameStyle::Raw => name.to_string(),
+        NameStyle::Full => demangle(name),
+        NameStyle::Simplified => simplify(&demangle(name)),
+    }
+}
+
+/// Demangles C++ and Rust names; anything else is returned unchanged.
+pub fn demangle(name: &str) -> String {
+    // Only mangled names are handed to the demanglers: cpp_demangle reads a
+    // plain C name like `f` as a type and turns it into `float`
+    if !name.starts_with("_Z") && !name.starts_with("_R") {
+        return name.to_string();
+    }
+    // addr2line drops the hash of Rust names, which the full style keeps
+    match rustc_demangle::try_demangle(name) {
+        Ok(demangled) => demangled.to_string(),
+        Err(_) => addr2line::demangle(name, gimli::DW_LANG_C_plus_plus).unwrap_or_else(|| name.to_string()),
+    }
+}
+
+/// Shortens a demangled name to what is needed to recognize the function:
+/// `std::vector<int, std::allocator<int> >::push_back(int const&)` becomes
+/// `std::vector::push_back`, `core::ptr::drop_in_place::h0123456789abcdef`
+/// becomes `core::ptr::drop_in_place` and Go's `pkg.(*List[int]).Len` becomes
+/// `pkg.(*List[...]).Len`.
+pub fn simplify(name: &str) -> String {
+    let name = strip_rust_hash(name);
+    let name = strip_arguments(name);
+    strip_generics(name)
+}
+
+/// Removes the `::h<16 hex digits>` suffix of legacy Rust symbols.
+fn strip_rust_hash(name: &str) -> &str {
+    match name.rsplit_once("::h") {
+        Some((path, hash)) if hash.len() == 16 && hash.chars().all(|c| c.is_ascii_hexdigit()) => path,
+        _ => name,
+    }
+}
+
+/// Removes a trailing C++ parameter list and its qualifiers, e.g. `(int) const`.
+fn strip_arguments(name: &str) -> &str {
+    let trimmed = name
+        .trim_end_matches(" const")
+        .trim_end_matches(" volatile")
+        .trim_end_matches(" &&")
+        .trim_end_matches(" &");
+    if !trimmed.ends_with(')') {
+        return name;
+    }
+    // Find the '(' matching the final ')'
+    let mut depth = 0;
+    for (i, c) in trimmed.char_indices().rev() {
+        match c {
+            ')' => depth += 1,
+            '(' => {
+                depth -= 1;
+                if depth == 0 {
+                    // `(anonymous namespace)` alone is not a parameter list
+                    return if i == 0 { name } else { &trimmed[..i] };
+                }
+            }
+            _ => {}
+        }
+    }
+    name
+}
+
+/// Removes C++ template arguments and Rust generics, and collapses Go type
+/// parameters to `[...]`.
+///
+/// `<` only opens a group after an identifier, so qualified paths like
+/// `<Foo as Bar>::baz` and operators like `operator<` are kept.
+fn strip_generics(name: &str) -> String {
+    let mut out = String::with_capacity(name.len());
+    let mut chars = name.chars().peekable();
+    while let Some(c) = chars.next() {
+        let after_identifier = out.ends_with(|p: char| p.is_alphanumeric() || p == '_') && !out.ends_with("operator");
+        let close = match c {
+            '<' if after_identifier => '>',
+            '[' if after_identifier => ']',
+            _ => {
+                out.push(c);
+                continue;
+            }
+        };
+        let mut depth = 1;
+        for inner in chars.by_ref() {
+            if inner == c {
+                depth += 1;
+            } else if inner == close {
+                depth -= 1;
+                if depth == 0 {
+                    break;
+                }
+            }
+        }
+        if c == '[' {
+            out.push_str("[...]");
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_name_styles() {
+        let cpp = "_ZNSt6vectorIiSaIiEE9push_backERKi";
+        assert_eq!(format_name(cpp, NameStyle::Raw), cpp);
+        assert_eq!(format_name(cpp, NameStyle::Full), "std::vector<int, std::allocator<int> >::push_back(int const&)");
+        assert_eq!(format_name(cpp, NameStyle::Simplified), "std::vector::push_back");
+
+        let rust = "_ZN4core3ptr13drop_in_place17h0123456789abcdefE";
+        assert_eq!(format_name(rust, NameStyle::Full), "core::ptr::drop_in_place::h0123456789abcdef");
+        assert_eq!(format_name(rust, NameStyle::Simplified), "core::ptr::drop_in_place");
+
+        assert_eq!(format_name("main", NameStyle::Simplified), "main");
+        assert_eq!(format_name("f", NameStyle::Full), "f");
+    }
+
+    #[test]
+    fn test_simplify_keeps_what_identifies_the_function() {
+        assert_eq!(simplify("(anonymous namespace)::parse(char const*) const"), "(anonymous namespace)::parse");
+        assert_eq!(simplify("<alloc::vec::Vec<T> as core::ops::Drop>::drop"), "<alloc::vec::Vec as core::ops::Drop>::drop");
+        assert_eq!(simplify("operator<(Foo const&, Foo const&)"), "operator<");
+        assert_eq!(simplify("std::function<void (int)>::operator()(int) const"), "std::function::operator()");
+        assert_eq!(simplify("k8s.io/client-go/gentype.(*Client[go.shape.*uint8]).Watch"), "k8s.io/client-go/gentype.(*Client[...]).Watch");
+    }
+}
@@ -0,0 +1,295 @@
+use anyhow::{Context, Result};
+use object::{Object, ObjectSection, ObjectSegment, ObjectSymbol, SectionKind, SymbolKind};
+use std::fs;
+use std::path::Path;
+
+/// A function symbol taken from an ELF symbol table
+#[derive(Debug, Clone)]
+pub struct ElfSymbol {
+    pub address: u64,
+    pub size: u64,
+    pub name: String,
+}
+
+/// Symbols without a size never cover more than this many bytes
+const MAX_UNSIZED_SYMBOL: u64 = 4096;
+
+/// Function symbols of a binary sorted by address.
+///
+/// Used as a fallback when DWARF has no function for an address, which is the
+/// normal case for stripped distro binaries.
+#[derive(Debug, Default)]
+pub struct SymbolTable {
+    symbols: Vec<ElfSymbol>,
+    /// Address ranges of the sections holding code
+    sections: Vec<(u64, u64)>,
+}
+
+impl SymbolTable {
+    /// Collects `.symtab` and `.dynsym` of the object, plus the `.symtab` embedded
+    /// in its MiniDebugInfo section if there is one. A MiniDebugInfo section that
+    /// can't be read is skipped, leaving the object's own symbols.
+    pub fn from_object(object: &object::File) -> Self {
+        let mut table = Self::default();
+        table.add_symbols(object);
+
+        if let Ok(Some(data)) = mini_debug_info(object) {
+            if let Ok(embedded) = object::File::parse(&*data) {
+                table.add_symbols(&embedded);
+            }
+        }
+
+        table.finish();
+        table
+    }
+
+    fn add_symbols(&mut self, object: &object::File) {
+        for section in object.sections() {
+            if section.kind() == SectionKind::Text && section.size() > 0 {
+                self.sections.push((section.address(), section.address() + section.size()));
+            }
+        }
+        for symbol in object.symbols().chain(object.dynamic_symbols()) {
+            if symbol.kind() != SymbolKind::Text || symbol.address() == 0 {
+                continue;
+            }
+            if let Ok(name) = symbol.name() {
+                if name.is_empty() {
+                    continue;
+                }
+                self.symbols.push(ElfSymbol {
+                    address: symbol.address(),
+                    size: symbol.size(),
+                    name: name.to_string(),
+                });
+            }
+        }
+    }
+
+    /// Sorts the symbols and drops duplicates (the same function usually appears
+    /// in both `.symtab` and `.dynsym`).
+    ///
+    /// Symbols with a size of zero (hand-written assembly, `_init`) are given one
+    /// up to the next symbol, but never past the end of their section or beyond
+    /// [`MAX_UNSIZED_SYMBOL`] bytes, so they don't claim unrelated code.
+    fn finish(&mut self) {
+        self.symbols.sort_by(|a, b| a.address.cmp(&b.address).then(b.size.cmp(&a.size)));
+        self.symbols.dedup_by_key(|s| s.address);
+        for i in 0..self.symbols.len() {
+            if self.symbols[i].size != 0 {
+                continue;
+            }
+            let address = self.symbols[i].address;
+            let mut end = address.saturating_add(MAX_UNSIZED_SYMBOL);
+            if let Some(next) = self.symbols.get(i + 1) {
+                end = end.min(next.address);
+            }
+            if let Some(&(_, section_end)) = self.sections.iter().find(|&&(start, end)| (start..end).contains(&address)) {
+                end = end.min(section_end);
+            }
+            self.symbols[i].size = end - address;
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.symbols.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &ElfSymbol> {
+        self.symbols.iter()
+    }
+
+    /// Finds the function containing `address`.
+    pub fn lookup(&self, address: u64) -> Option<&ElfSymbol> {
+        let idx = self.symbols.partition_point(|s| s.address <= address);
+        let symbol = self.symbols.get(idx.checked_sub(1)?)?;
+        if address < symbol.address + symbol.size {
+            Some(symbol)
+        } else {
+            None
+        }
+    }
+}
+
+/// A `PT_LOAD` segment: the file range that gets mapped, and where it was linked to
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LoadSegment {
+    pub offset: u64,
+    pub file_size: u64,
+    pub vaddr: u64,
+}
+
+/// Translates file offsets (what `/proc/<pid>/maps` gives us) into the link-time
+/// virtual addresses used by symbol tables and DWARF.
+///
+/// The two only coincide when a segment's vaddr equals its file offset, which is
+/// not the case for non-PIE executables, prelinked libraries or custom linker scripts.
+#[derive(Debug, Default)]
+pub struct LoadSegments {
+    segments: Vec<LoadSegment>,
+}
+
+impl LoadSegments {
+    pub fn new(mut segments: Vec<LoadSegment>) -> Self {
+        segments.retain(|s| s.file_size > 0);
+        segments.sort_by_key(|s| s.offset);
+        Self { segments }
+    }
+
+    pub fn from_object(object: &object::File) -> Self {
+        Self::new(
+            object
+                .segments()
+                .map(|segment| {
+                    let (offset, file_size) = segment.file_range();
+                    LoadSegment { offset, file_size, vaddr: segment.address() }
+                })
+                .collect(),
+        )
+    }
+
+    /// Returns `None` for offsets outside every segment, e.g. section headers.
+    /// Objects without program headers are taken to be linked at offset zero.
+    pub fn offset_to_vaddr(&self, offset: u64) -> Option<u64> {
+        if self.segments.is_empty() {
+            return Some(offset);
+        }
+        let idx = self.segments.partition_point(|s| s.offset <= offset);
+        let segment = self.segments.get(idx.checked_sub(1)?)?;
+        (offset < segment.offset + segment.file_size).then(|| offset - segment.offset + segment.vaddr)
+    }
+
+    /// The inverse of [`Self::offset_to_vaddr`], e.g. to find where a global variable is mapped.
+    pub fn vaddr_to_offset(&self, vaddr: u64) -> Option<u64> {
+        if self.segments.is_empty() {
+            return Some(vaddr);
+        }
+        self.segments
+            .iter()
+            .find(|s| vaddr >= s.vaddr && vaddr < s.vaddr + s.file_size)
+            .map(|s| vaddr - s.vaddr + s.offset)
+    }
+}
+
+/// Reads the GNU build-id note of a binary without loading anything else.
+pub fn read_build_id(path: &Path) -> Result<Option<Vec<u8>>> {
+    let file = fs::File::open(path).with_context(|| format!("Failed to open binary {:?}", path))?;
+    let data = unsafe { memmap2::Mmap::map(&file)? };
+    let object = object::File::parse(&*data)?;
+    Ok(object.build_id()?.map(|id| id.to_vec()))
+}
+
+/// Decompresses the xz-compressed ELF stored in `.gnu_debugdata` (MiniDebugInfo).
+pub fn mini_debug_info(object: &object::File) -> Result<Option<Vec<u8>>> {
+    let section = match object.section_by_name(".gnu_debugdata") {
+        Some(section) => section,
+        None => return Ok(None),
+    };
+    let compressed = section.data()?;
+    let mut data = Vec::new();
+    lzma_rs::xz_decompress(&mut &*compressed, &mut data)
+        .map_err(|e| anyhow::anyhow!("Failed to decompress .gnu_debugdata: {:?}", e))?;
+    Ok(Some(data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::maps::{Mapping, MappingKind};
+
+    fn table(symbols: &[(u64, u64, &str)]) -> SymbolTable {
+        let mut table = SymbolTable {
+            symbols: symbols
+                .iter()
+                .map(|&(address, size, name)| ElfSymbol { address, size, name: name.to_string() })
+                .collect(),
+            sections: vec![(0x1000, 0x2000), (0x3000, 0x6000)],
+        };
+        table.finish();
+        table
+    }
+
+    fn segments() -> LoadSegments {
+        // Non-PIE x86-64 layout, then a library prelinked high up
+        LoadSegments::new(vec![
+            LoadSegment { offset: 0x1000, file_size: 0x2345, vaddr: 0x401000 },
+            LoadSegment { offset: 0, file_size: 0x6f8, vaddr: 0x400000 },
+            LoadSegment { offset: 0x3e10, file_size: 0x220, vaddr: 0x404e10 },
+        ])
+    }
+
+    #[test]
+    fn test_offset_to_vaddr() {
+        let segments = segments();
+        assert_eq!(segments.offset_to_vaddr(0x10), Some(0x400010));
+        assert_eq!(segments.offset_to_vaddr(0x1136), Some(0x401136));
+        assert_eq!(segments.offset_to_vaddr(0x3e18), Some(0x404e18));
+        // Padding between segments and data past the end belong to no segment
+        assert_eq!(segments.offset_to_vaddr(0x800), None);
+        assert_eq!(segments.offset_to_vaddr(0x4030), None);
+
+        let prelinked = LoadSegments::new(vec![LoadSegment { offset: 0x28000, file_size: 0x17d000, vaddr: 0x3c7a628000 }]);
+        assert_eq!(prelinked.offset_to_vaddr(0x29c40), Some(0x3c7a629c40));
+        assert_eq!(LoadSegments::default().offset_to_vaddr(0x1234), Some(0x1234));
+        assert_eq!(segments.vaddr_to_offset(0x404e18), Some(0x3e18));
+        assert_eq!(segments.vaddr_to_offset(0x405100), None);
+    }
+
+    #[test]
+    fn test_mapping_to_vaddr() {
+        // The executable segment as the kernel maps it: page-aligned start and offset
+        let text_mapping = |start: u64| Mapping {
+            start,
+            end: start + 0x3000,
+            perms: "r-xp".to_string(),
+            offset: 0x1000,
+            dev_major: 8,
+            dev_minor: 1,
+            inode: 1234,
+            kind: MappingKind::File,
+            pathname: "/usr/bin/app".to_string(),
+            deleted: false,
+        };
+        for (start, address) in [(0x401000, 0x401136), (0x7f3a00001000, 0x7f3a00001136)] {
+            let mapping = text_mapping(start);
+            let offset = address - mapping.start + mapping.offset;
+            assert_eq!(offset, 0x1136);
+            assert_eq!(segments().offset_to_vaddr(offset), Some(0x401136));
+        }
+    }
+
+    #[test]
+    fn test_lookup_by_range() {
+        let table = table(&[(0x2000, 0x10, "bar"), (0x1000, 0x100, "foo")]);
+        assert_eq!(table.lookup(0x1000).unwrap().name, "foo");
+        assert_eq!(table.lookup(0x10ff).unwrap().name, "foo");
+        assert!(table.lookup(0x1100).is_none());
+        assert!(table.lookup(0xfff).is_none());
+        assert_eq!(table.lookup(0x2008).unwrap().name, "bar");
+    }
+
+    #[test]
+    fn test_zero_sized_symbol_extends_to_next() {
+        let table = table(&[
+            (0x1000, 0, "asm_entry"),
+            (0x1000, 0x20, "asm_entry"),
+            (0x1800, 0, "_init"),
+            (0x1c00, 0, "_start"),
+            (0x3000, 0, "other"),
+        ]);
+        assert_eq!(table.len(), 4);
+        assert_eq!(table.lookup(0x1010).unwrap().name, "asm_entry");
+        assert!(table.lookup(0x1020).is_none());
+        // Up to the next symbol, then to the end of the section
+        assert_eq!(table.lookup(0x1bff).unwrap().name, "_init");
+        assert_eq!(table.lookup(0x1fff).unwrap().name, "_start");
+        assert!(table.lookup(0x2000).is_none());
+        // And never more than a page
+        assert_eq!(table.lookup(0x3fff).unwrap().name, "other");
+        assert!(table.lookup(0x4000).is_none());
+    }
+}
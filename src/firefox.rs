@@ -0,0 +1,268 @@
+use anyhow::Result;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::Write;
+
+use crate::aggregator::{FrameKey, FrameKind};
+use crate::collector::POLL_INTERVAL;
+use crate::speedscope::thread_title;
+use crate::types::{Report, TimedSample};
+
+/// Version of the Firefox Profiler's processed format written here; the
+/// profiler upgrades older versions when it loads them.
+const PROCESSED_PROFILE_VERSION: u32 = 47;
+/// Milliseconds between samples of a thread, on average
+const INTERVAL_MS: u64 = POLL_INTERVAL.as_millis() as u64;
+
+/// Categories frames are coloured by, in the order of `meta.categories`
+const CATEGORIES: [(&str, &str); 6] = [
+    ("Other", "grey"),
+    ("Native", "blue"),
+    ("Kernel", "orange"),
+    ("JIT", "green"),
+    ("Inlined", "lightblue"),
+    ("Python", "yellow"),
+];
+
+fn category(kind: FrameKind) -> usize {
+    match kind {
+        FrameKind::User => 1,
+        FrameKind::Kernel => 2,
+        FrameKind::Jit => 3,
+        FrameKind::Inlined => 4,
+        FrameKind::Python => 5,
+    }
+}
+
+/// The tables of one thread: every frame, function and stack is stored once
+/// and referred to by index, the way the processed format lays them out.
+#[derive(Default)]
+struct ThreadTables {
+    strings: Vec<String>,
+    string_ids: HashMap<String, usize>,
+    funcs: Vec<(usize, Option<usize>)>,
+    func_ids: HashMap<(String, Option<String>), usize>,
+    frames: Vec<(usize, Option<u32>, usize)>,
+    frame_ids: HashMap<FrameKey, usize>,
+    stacks: Vec<(Option<usize>, usize)>,
+    stack_ids: HashMap<(Option<usize>, usize), usize>,
+    times: Vec<u64>,
+    sample_stacks: Vec<Option<usize>>,
+    weights: Vec<usize>,
+}
+
+impl ThreadTables {
+    fn string(&mut self, text: &str) -> usize {
+        if let Some(&id) = self.string_ids.get(text) {
+            return id;
+        }
+        self.strings.push(text.to_string());
+        self.string_ids.insert(text.to_string(), self.strings.len() - 1);
+        self.strings.len() - 1
+    }
+
+    fn frame(&mut self, frame: &FrameKey) -> usize {
+        if let Some(&id) = self.frame_ids.get(frame) {
+            return id;
+        }
+        let key = (frame.name.clone(), frame.file.clone());
+        let func = match self.func_ids.get(&key) {
+            Some(&func) => func,
+            None => {
+                let name = self.string(&frame.name);
+                let file = frame.file.as_deref().map(|file| self.string(file));
+                self.funcs.push((name, file));
+                self.func_ids.insert(key, self.funcs.len() - 1);
+                self.funcs.len() - 1
+            }
+        };
+        self.frames.push((func, frame.line, category(frame.kind)));
+        self.frame_ids.insert(frame.clone(), self.frames.len() - 1);
+        self.frames.len() - 1
+    }
+
+    /// Stack of the frames, outermost first
+    fn stack(&mut self, frames: &[FrameKey]) -> Option<usize> {
+        let mut prefix = None;
+        for frame in frames {
+            let frame = self.frame(frame);
+            let id = match self.stack_ids.get(&(prefix, frame)) {
+                Some(&id) => id,
+                None => {
+                    self.stacks.push((prefix, frame));
+                    self.stack_ids.insert((prefix, frame), self.stacks.len() - 1);
+                    self.stacks.len() - 1
+                }
+            };
+            prefix = Some(id);
+        }
+        prefix
+    }
+
+    /// Adds a sample standing for `weight` samples; the profiler has no weight
+    /// type for event counts, so samples are counted rather than their events.
+    fn add(&mut self, time: u64, frames: &[FrameKey], weight: usize) {
+        let stack = self.stack(frames);
+        self.times.push(time);
+        self.sample_stacks.push(stack);
+        self.weights.push(weight);
+    }
+
+    fn to_json(&self, name: &str, pid: i32, tid: i32, is_main: bool) -> Value {
+        let frame_category: Vec<usize> = self.frames.iter().map(|f| f.2).collect();
+        let stack_category: Vec<usize> = self.stacks.iter().map(|s| self.frames[s.1].2).collect();
+        let nulls = |len: usize| vec![Value::Null; len];
+        json!({
+            "name": name,
+            "processType": "default",
+            "processName": format!("pid {}", pid),
+            "processStartupTime": 0,
+            "processShutdownTime": null,
+            "registerTime": 0,
+            "unregisterTime": null,
+            "pausedRanges": [],
+            "isMainThread": is_main,
+            "pid": pid.to_string(),
+            "tid": tid,
+            "samples": {
+                "length": self.times.len(),
+                "stack": self.sample_stacks,
+                "time": self.times,
+                "weight": self.weights,
+                "weightType": "samples",
+            },
+            "markers": {
+                "length": 0, "category": [], "data": [], "endTime": [], "name": [], "phase": [], "startTime": [],
+            },
+            "stackTable": {
+                "length": self.stacks.len(),
+                "prefix": self.stacks.iter().map(|s| s.0).collect::<Vec<_>>(),
+                "frame": self.stacks.iter().map(|s| s.1).collect::<Vec<_>>(),
+                "category": stack_category,
+                "subcategory": vec![0; self.stacks.len()],
+            },
+            "frameTable": {
+                "length": self.frames.len(),
+                "address": vec![-1; self.frames.len()],
+                "inlineDepth": vec![0; self.frames.len()],
+                "category": frame_category,
+                "subcategory": vec![0; self.frames.len()],
+                "func": self.frames.iter().map(|f| f.0).collect::<Vec<_>>(),
+                "nativeSymbol": nulls(self.frames.len()),
+                "innerWindowID": vec![0; self.frames.len()],
+                "implementation": nulls(self.frames.len()),
+                "line": self.frames.iter().map(|f| f.1).collect::<Vec<_>>(),
+                "column": nulls(self.frames.len()),
+            },
+            "funcTable": {
+                "length": self.funcs.len(),
+                "name": self.funcs.iter().map(|f| f.0).collect::<Vec<_>>(),
+                "isJS": vec![false; self.funcs.len()],
+                "relevantForJS": vec![false; self.funcs.len()],
+                "resource": vec![-1; self.funcs.len()],
+                "fileName": self.funcs.iter().map(|f| f.1).collect::<Vec<_>>(),
+                "lineNumber": nulls(self.funcs.len()),
+                "columnNumber": nulls(self.funcs.len()),
+            },
+            "resourceTable": { "length": 0, "lib": [], "name": [], "host": [], "type": [] },
+            "nativeSymbols": { "length": 0, "libIndex": [], "address": [], "name": [], "functionSize": [] },
+            "stringArray": self.strings,
+        })
+    }
+}
+
+/// Writes the report in the Firefox Profiler's processed format, one thread
+/// per sampled thread with its samples on the time axis of the run.
+pub fn write_firefox(report: &Report, name: &str, out: &mut impl Write) -> Result<()> {
+    let mut threads = Vec::new();
+    if report.timeline.is_empty() {
+        // Folded stacks have no times; lay them out one after the other, each
+        // counting as many samples as its count says, since that is all there is
+        let mut tables = ThreadTables::default();
+        for (i, (stack, count)) in report.folded_stacks.iter().enumerate() {
+            let frames: Vec<FrameKey> = stack.split(';').map(FrameKey::parse).collect();
+            tables.add(i as u64 * INTERVAL_MS, &frames, *count);
+        }
+        threads.push(tables.to_json("all threads", 0, 0, true));
+    }
+    for thread in report.threads() {
+        let mut tables = ThreadTables::default();
+        for sample in &thread.samples {
+            let TimedSample { timestamp, stack, .. } = sample;
+            tables.add(*timestamp, stack, 1);
+        }
+        threads.push(tables.to_json(&thread_title(&thread), thread.pid, thread.tid, thread.tid == thread.pid));
+    }
+
+    let categories: Vec<Value> = CATEGORIES
+        .iter()
+        .map(|(name, color)| json!({ "name": name, "color": color, "subcategories": ["Other"] }))
+        .collect();
+    let profile = json!({
+        "meta": {
+            "interval": INTERVAL_MS,
+            "startTime": 0,
+            "processType": 0,
+            "product": name,
+            "stackwalk": 1,
+            "version": 27,
+            "preprocessedProfileVersion": PROCESSED_PROFILE_VERSION,
+            "symbolicated": true,
+            "categories": categories,
+            "markerSchema": [],
+        },
+        "libs": [],
+        "pages": [],
+        "counters": [],
+        "threads": threads,
+    });
+    serde_json::to_writer(&mut *out, &profile)?;
+    writeln!(out)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_thread_tables() {
+        let stack = |names: &str| names.split(';').map(FrameKey::function).collect::<Vec<_>>();
+        let mut tables = ThreadTables::default();
+        tables.add(0, &stack("main;parse"), 3);
+        tables.add(100, &stack("main;parse;lex"), 1);
+        tables.add(200, &stack("main;eval"), 2);
+        let thread = tables.to_json("worker", 1, 2, false);
+
+        assert_eq!(thread["samples"]["stack"], json!([1, 2, 3]));
+        assert_eq!(thread["samples"]["weight"], json!([3, 1, 2]));
+        assert_eq!(thread["stackTable"]["prefix"], json!([null, 0, 1, 0]));
+        assert_eq!(thread["stackTable"]["frame"], json!([0, 1, 2, 3]));
+        assert_eq!(thread["stringArray"], json!(["main", "parse", "lex", "eval"]));
+        assert_eq!(thread["stackTable"]["category"], json!([1, 1, 1, 1]));
+        for table in ["stackTable", "frameTable", "funcTable"] {
+            let length = thread[table]["length"].as_u64().unwrap() as usize;
+            for (_, column) in thread[table].as_object().unwrap().iter().filter(|(key, _)| *key != "length") {
+                assert_eq!(column.as_array().unwrap().len(), length, "{}", table);
+            }
+        }
+    }
+
+    #[test]
+    fn test_samples_weigh_one() {
+        let mut report = crate::aggregator::Aggregator::new().generate_report();
+        let sample = |timestamp, weight| TimedSample {
+            timestamp,
+            pid: 1,
+            tid: 1,
+            thread_name: None,
+            weight,
+            stack: vec![FrameKey::function("main")],
+        };
+        report.timeline = vec![sample(0, 300), sample(100, 5)];
+        let mut out = Vec::new();
+        write_firefox(&report, "test", &mut out).unwrap();
+        let profile: Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(profile["threads"][0]["samples"]["weight"], json!([1, 1]));
+    }
+}
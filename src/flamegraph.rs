@@ -0,0 +1,312 @@
+use anyhow::Result;
+use std::io::Write;
+
+use crate::aggregator::FrameKind;
+use crate::calltree::{CallTree, NodeId};
+
+const WIDTH: f64 = 1200.0;
+const FRAME_HEIGHT: f64 = 16.0;
+const SIDE_PAD: f64 = 10.0;
+/// Room for the title and the search/reset buttons
+const TOP_PAD: f64 = 48.0;
+/// Room for the details line
+const BOTTOM_PAD: f64 = 30.0;
+/// Frames narrower than this many pixels are left out
+const MIN_WIDTH: f64 = 0.1;
+const FONT_SIZE: f64 = 12.0;
+const FONT_WIDTH: f64 = 0.59;
+
+/// Renders a [`CallTree`] as a self-contained interactive SVG flame graph:
+/// click to zoom, search by regex, and hover for counts and percentages.
+pub struct FlameGraph<'a> {
+    tree: &'a CallTree,
+    title: String,
+    icicle: bool,
+}
+
+/// A frame placed on the graph; `x` and `width` are fractions of the root's width
+struct Frame {
+    node: NodeId,
+    depth: usize,
+    x: f64,
+    width: f64,
+}
+
+impl<'a> FlameGraph<'a> {
+    pub fn new(tree: &'a CallTree) -> Self {
+        Self { tree, title: "Flame Graph".to_string(), icicle: false }
+    }
+
+    pub fn with_title(mut self, title: impl Into<String>) -> Self {
+        self.title = title.into();
+        self
+    }
+
+    /// Grows the graph downwards from the root at the top, instead of upwards.
+    pub fn with_icicle(mut self, icicle: bool) -> Self {
+        self.icicle = icicle;
+        self
+    }
+
+    /// Lays out the frames wide enough to be drawn, root first.
+    fn frames(&self) -> Vec<Frame> {
+        let total = self.tree.node(CallTree::ROOT).total_count.max(1) as f64;
+        let mut frames = Vec::new();
+        let mut pending = vec![Frame { node: CallTree::ROOT, depth: 0, x: 0.0, width: 1.0 }];
+        while let Some(frame) = pending.pop() {
+            // Children are ordered by name, as flamegraph.pl does, so graphs of similar runs line up
+            let mut children = self.tree.children(frame.node);
+            children.sort_by_cached_key(|&child| self.tree.node(child).frame.label());
+            let mut x = frame.x;
+            for child in children {
+                let width = self.tree.node(child).total_count as f64 / total;
+                if width * (WIDTH - 2.0 * SIDE_PAD) >= MIN_WIDTH {
+                    pending.push(Frame { node: child, depth: frame.depth + 1, x, width });
+                }
+                x += width;
+            }
+            frames.push(frame);
+        }
+        frames
+    }
+
+    pub fn write(&self, out: &mut impl Write) -> Result<()> {
+        let frames = self.frames();
+        let depth = frames.iter().map(|f| f.depth).max().unwrap_or(0) + 1;
+        let height = TOP_PAD + depth as f64 * FRAME_HEIGHT + BOTTOM_PAD;
+        let total = self.tree.node(CallTree::ROOT).total_count;
+
+        writeln!(out, r#"<?xml version="1.0" standalone="no"?>"#)?;
+        writeln!(
+            out,
+            r#"<svg version="1.1" width="{WIDTH}" height="{height}" viewBox="0 0 {WIDTH} {height}" xmlns="http://www.w3.org/2000/svg">"#
+        )?;
+        writeln!(out, "<style>{}</style>", STYLE)?;
+        writeln!(out, "<script><![CDATA[{}]]></script>", SCRIPT)?;
+        writeln!(out, r#"<rect width="100%" height="100%" fill="rgb(248,248,248)"/>"#)?;
+        writeln!(out, r#"<text id="title" x="{}" y="24" text-anchor="middle">{}</text>"#, WIDTH / 2.0, escape(&self.title))?;
+        writeln!(out, r#"<text id="reset" class="button" x="{SIDE_PAD}" y="24">Reset Zoom</text>"#)?;
+        writeln!(out, r#"<text id="search" class="button" x="{}" y="24" text-anchor="end">Search</text>"#, WIDTH - SIDE_PAD)?;
+        writeln!(out, r#"<text id="details" x="{SIDE_PAD}" y="{}"> </text>"#, height - 10.0)?;
+        writeln!(out, r#"<text id="matched" x="{}" y="{}" text-anchor="end"> </text>"#, WIDTH - SIDE_PAD, height - 10.0)?;
+
+        writeln!(out, r#"<g id="frames">"#)?;
+        for frame in &frames {
+            let node = self.tree.node(frame.node);
+            let (label, kind) = if frame.node == CallTree::ROOT {
+                ("all".to_string(), None)
+            } else {
+                (node.frame.label(), Some(node.frame.kind))
+            };
+            let percentage = node.total_count as f64 * 100.0 / total.max(1) as f64;
+            let y = if self.icicle {
+                TOP_PAD + frame.depth as f64 * FRAME_HEIGHT
+            } else {
+                height - BOTTOM_PAD - (frame.depth + 1) as f64 * FRAME_HEIGHT
+            };
+            let x = SIDE_PAD + frame.x * (WIDTH - 2.0 * SIDE_PAD);
+            let width = frame.width * (WIDTH - 2.0 * SIDE_PAD);
+            let name = escape(&label);
+            writeln!(
+                out,
+                r#"<g class="frame" data-name="{name}" data-x="{}" data-w="{}" data-d="{}"><title>{name} ({} events, {:.2}%)</title><rect x="{x:.2}" y="{y:.1}" width="{width:.2}" height="{}" fill="{}" rx="2"/><text x="{:.2}" y="{:.1}">{}</text></g>"#,
+                frame.x,
+                frame.width,
+                frame.depth,
+                node.total_count,
+                percentage,
+                FRAME_HEIGHT - 1.0,
+                color(kind, &node.frame.name),
+                x + 3.0,
+                y + FRAME_HEIGHT - 4.5,
+                escape(&fit(&label, width)),
+            )?;
+        }
+        writeln!(out, "</g>")?;
+        writeln!(out, "</svg>")?;
+        Ok(())
+    }
+}
+
+/// Truncates a name to what fits in `width` pixels, the same way the script does after zooming.
+fn fit(name: &str, width: f64) -> String {
+    let chars = ((width - 6.0) / (FONT_SIZE * FONT_WIDTH)) as usize;
+    if chars < 3 {
+        return String::new();
+    }
+    if name.chars().count() <= chars {
+        return name.to_string();
+    }
+    let mut fitted: String = name.chars().take(chars - 2).collect();
+    fitted.push_str("..");
+    fitted
+}
+
+/// Colour of a frame: warm hues for native code, with kernel, JIT, inlined and
+/// Python frames in their own hues. The name picks the shade, so a function
+/// keeps its colour from one graph to the next.
+fn color(kind: Option<FrameKind>, name: &str) -> String {
+    let hash = name.bytes().fold(0xcbf29ce484222325u64, |hash, b| (hash ^ b as u64).wrapping_mul(0x100000001b3));
+    let v = |shift: u32| ((hash >> shift) & 0xff) as f64 / 255.0;
+    let (r, g, b) = match kind {
+        None => (200.0, 200.0, 200.0),
+        Some(FrameKind::User) => (205.0 + 50.0 * v(0), 230.0 * v(8), 55.0 * v(16)),
+        Some(FrameKind::Kernel) => (190.0 + 65.0 * v(0), 90.0 + 65.0 * v(0), 0.0),
+        Some(FrameKind::Jit) => (50.0 + 60.0 * v(0), 200.0 + 55.0 * v(0), 50.0 + 60.0 * v(0)),
+        Some(FrameKind::Inlined) => (50.0 + 60.0 * v(0), 165.0 + 55.0 * v(0), 165.0 + 55.0 * v(0)),
+        Some(FrameKind::Python) => (175.0 + 55.0 * v(0), 175.0 + 55.0 * v(0), 50.0 + 20.0 * v(8)),
+    };
+    format!("rgb({},{},{})", r as u8, g as u8, b as u8)
+}
+
+/// Escapes text for XML and HTML, attributes included.
+pub(crate) fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+const STYLE: &str = "
+text { font-family: Verdana, sans-serif; font-size: 12px; fill: #000; }
+#title { font-size: 17px; }
+.button { cursor: pointer; fill: #446; }
+.button:hover { text-decoration: underline; }
+.frame { cursor: pointer; }
+.frame text { pointer-events: none; }
+.frame:hover rect { stroke: #000; stroke-width: 0.5; }
+.frame.parent rect { opacity: 0.5; }
+.frame.hidden { display: none; }
+";
+
+const SCRIPT: &str = r#"
+const WIDTH = 1200, PAD = 10, CHAR = 12 * 0.59;
+let frames, details, matched, searchButton, resetButton, searching = null;
+window.addEventListener("load", () => {
+    frames = Array.from(document.querySelectorAll(".frame"));
+    details = document.getElementById("details");
+    matched = document.getElementById("matched");
+    searchButton = document.getElementById("search");
+    resetButton = document.getElementById("reset");
+    resetButton.style.opacity = 0.1;
+    for (const frame of frames) {
+        frame.addEventListener("click", () => zoom(frame));
+        frame.addEventListener("mouseover", () => details.textContent = frame.querySelector("title").textContent);
+        frame.addEventListener("mouseout", () => details.textContent = " ");
+    }
+    resetButton.addEventListener("click", () => zoom(frames[0]));
+    searchButton.addEventListener("click", search);
+});
+function fit(name, width) {
+    const chars = Math.floor((width - 6) / CHAR);
+    if (chars < 3) return "";
+    return name.length <= chars ? name : name.slice(0, chars - 2) + "..";
+}
+function place(frame, x, w) {
+    const rect = frame.querySelector("rect"), text = frame.querySelector("text");
+    const width = w * (WIDTH - 2 * PAD);
+    rect.setAttribute("x", PAD + x * (WIDTH - 2 * PAD));
+    rect.setAttribute("width", width);
+    text.setAttribute("x", PAD + x * (WIDTH - 2 * PAD) + 3);
+    text.textContent = fit(frame.dataset.name, width);
+}
+function zoom(target) {
+    const x0 = +target.dataset.x, w0 = +target.dataset.w, d0 = +target.dataset.d, eps = 1e-9;
+    for (const frame of frames) {
+        const x = +frame.dataset.x, w = +frame.dataset.w, d = +frame.dataset.d;
+        if (d < d0 && x <= x0 + eps && x + w >= x0 + w0 - eps) {
+            frame.classList.remove("hidden");
+            frame.classList.add("parent");
+            place(frame, 0, 1);
+        } else if (d >= d0 && x >= x0 - eps && x + w <= x0 + w0 + eps) {
+            frame.classList.remove("hidden", "parent");
+            place(frame, (x - x0) / w0, w / w0);
+        } else {
+            frame.classList.add("hidden");
+        }
+    }
+    resetButton.style.opacity = d0 == 0 ? 0.1 : 1;
+}
+function search() {
+    if (searching !== null) {
+        for (const frame of frames) frame.querySelector("rect").style.fill = "";
+        searching = null;
+        searchButton.textContent = "Search";
+        matched.textContent = " ";
+        return;
+    }
+    const pattern = prompt("Search for (regular expression):", "");
+    if (!pattern) return;
+    let regex;
+    try { regex = new RegExp(pattern); } catch (e) { alert(e); return; }
+    searching = pattern;
+    // Nested matches cover the same samples; only count the outermost ones
+    const spans = [];
+    for (const frame of frames.slice(1)) {
+        if (!regex.test(frame.dataset.name)) continue;
+        frame.querySelector("rect").style.fill = "rgb(230,0,230)";
+        spans.push([+frame.dataset.x, +frame.dataset.x + +frame.dataset.w]);
+    }
+    spans.sort((a, b) => a[0] - b[0]);
+    let covered = 0, end = 0;
+    for (const [start, stop] of spans) {
+        if (stop > end) { covered += stop - Math.max(start, end); end = stop; }
+    }
+    searchButton.textContent = "Reset Search";
+    matched.textContent = "Matched: " + (covered * 100).toFixed(1) + "%";
+}
+"#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aggregator::FrameKey;
+
+    fn render(tree: &CallTree, icicle: bool) -> String {
+        let mut svg = Vec::new();
+        FlameGraph::new(tree).with_icicle(icicle).write(&mut svg).unwrap();
+        String::from_utf8(svg).unwrap()
+    }
+
+    #[test]
+    fn test_flamegraph_svg() {
+        let mut tree = CallTree::new();
+        let main = FrameKey::function("main");
+        let parse = FrameKey::function("parse<std::string>");
+        let helper = FrameKey { kind: FrameKind::Inlined, ..FrameKey::function("helper") };
+        tree.add(&[main.clone(), parse.clone()], 3);
+        tree.add(&[main.clone(), parse, helper], 1);
+
+        let svg = render(&tree, false);
+        assert!(svg.starts_with("<?xml"));
+        assert!(svg.trim_end().ends_with("</svg>"));
+        assert_eq!(svg.matches(r#"<g class="frame""#).count(), 4);
+        assert!(svg.contains("<title>main (4 events, 100.00%)</title>"));
+        assert!(svg.contains("<title>parse&lt;std::string&gt; (4 events, 100.00%)</title>"));
+        assert!(svg.contains("<title>helper_[i] (1 events, 25.00%)</title>"));
+        assert!(!svg.contains("<std::string>"));
+
+        // The root sits at the bottom of a flame graph and at the top of an icicle graph
+        let root_y = |svg: &str| {
+            let root = &svg[svg.find(r#"data-name="all""#).unwrap()..];
+            let y = &root[root.find(" y=\"").unwrap() + 4..];
+            y[..y.find('"').unwrap()].parse::<f64>().unwrap()
+        };
+        assert!(root_y(&svg) > root_y(&render(&tree, true)));
+    }
+
+    #[test]
+    fn test_fit() {
+        assert_eq!(fit("main", 100.0), "main");
+        assert_eq!(fit("a_rather_long_function_name", 60.0), "a_rat..");
+        assert_eq!(fit("main", 10.0), "");
+    }
+}
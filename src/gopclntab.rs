@@ -0,0 +1,422 @@
+use anyhow::{bail, Context, Result};
+use object::{Object, ObjectSection, ObjectSymbol};
+
+use crate::symcache::InlineFrame;
+
+/// `_func.pcdata` index of the inline tree index table
+const PCDATA_INL_TREE_INDEX: usize = 2;
+/// `_func.funcdata` index of the inline tree
+const FUNCDATA_INL_TREE: usize = 3;
+
+/// Layout differences between the pclntab formats we understand
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Version {
+    /// Go 1.18 and 1.19
+    Go118,
+    /// Go 1.20 and later, which added `_func.startLine` and shrank inlined calls
+    Go120,
+}
+
+/// Function, file and line tables the Go runtime uses for its own tracebacks.
+///
+/// Every Go binary carries them in `.gopclntab`, even when built with `-ldflags=-s -w`,
+/// and unlike DWARF they describe inlining the way the Go toolchain means it.
+/// Formats from Go 1.18 on are supported.
+pub struct GoFunctionTable {
+    data: Vec<u8>,
+    version: Version,
+    quantum: u64,
+    text_start: u64,
+    nfunc: usize,
+    funcname: usize,
+    cutab: usize,
+    filetab: usize,
+    pctab: usize,
+    functab: usize,
+    /// Bytes from the `go:func.*` symbol on, which inline trees are relative to
+    gofunc: Option<Vec<u8>>,
+}
+
+/// The `_func` fields we use
+struct Func {
+    entry: u64,
+    name_off: u32,
+    pcfile: u32,
+    pcln: u32,
+    cu_offset: u32,
+    inl_tree_index: u32,
+    inl_tree: Option<u32>,
+}
+
+impl GoFunctionTable {
+    /// Returns `None` for binaries that were not built by Go.
+    pub fn from_object(object: &object::File) -> Result<Option<Self>> {
+        let Some(section) = object.sections().find(|s| s.name().is_ok_and(|n| n.ends_with("gopclntab"))) else {
+            return Ok(None);
+        };
+        let data = section.data()?.to_vec();
+        let text = object.section_by_name(".text").map(|s| s.address());
+        let mut table = Self::parse(data, text, None)?;
+
+        // Inline trees live next to other read-only function metadata, at go:func.*
+        let gofunc = object
+            .symbols()
+            .find(|s| matches!(s.name(), Ok("go:func.*") | Ok("go.func.*")))
+            .map(|symbol| symbol.address())
+            .or_else(|| table.find_gofunc_in_moduledata(object, section.address()));
+        table.gofunc = gofunc.and_then(|address| {
+            let section = object.sections().find(|s| address >= s.address() && address < s.address() + s.size())?;
+            Some(section.data().ok()?.get((address - section.address()) as usize..)?.to_vec())
+        });
+        Ok(Some(table))
+    }
+
+    /// Stripped binaries have no symbol for go:func.*, but the runtime's `moduledata`
+    /// (in `.noptrdata`) points to it. It is recognized by its first field, which
+    /// points to the pclntab, and its `minpc` being the start of the text.
+    fn find_gofunc_in_moduledata(&self, object: &object::File, pclntab: u64) -> Option<u64> {
+        let data = object.section_by_name(".noptrdata")?.data().ok()?;
+        let word = |at: usize| Some(u64::from_le_bytes(data.get(at..at + 8)?.try_into().ok()?));
+        // Field offsets of minpc and gofunc; 1.20 added covctrs before gofunc
+        let (minpc, gofunc) = match self.version {
+            Version::Go118 => (160, 304),
+            Version::Go120 => (160, 320),
+        };
+        (0..data.len())
+            .step_by(8)
+            .find(|&at| word(at) == Some(pclntab) && word(at + minpc) == Some(self.text_start))
+            .and_then(|at| word(at + gofunc))
+    }
+
+    fn parse(data: Vec<u8>, text: Option<u64>, gofunc: Option<Vec<u8>>) -> Result<Self> {
+        let magic = u32::from_le_bytes(data.get(0..4).context("Truncated pclntab")?.try_into()?);
+        let version = match magic {
+            0xfffffff0 => Version::Go118,
+            0xfffffff1 => Version::Go120,
+            _ => bail!("Unsupported pclntab magic 0x{:x}", magic),
+        };
+        if data.get(7) != Some(&8) {
+            bail!("Only 64-bit pclntabs are supported");
+        }
+        let word = |i: usize| -> Result<u64> {
+            let bytes = data.get(8 + i * 8..16 + i * 8).context("Truncated pclntab header")?;
+            Ok(u64::from_le_bytes(bytes.try_into()?))
+        };
+        // PIE binaries only get textStart by relocation, so the file may hold zero
+        let text_start = match (word(2)?, text) {
+            (0, Some(text)) => text,
+            (start, _) => start,
+        };
+        Ok(Self {
+            version,
+            quantum: data[6] as u64,
+            text_start,
+            nfunc: word(0)? as usize,
+            funcname: word(3)? as usize,
+            cutab: word(4)? as usize,
+            filetab: word(5)? as usize,
+            pctab: word(6)? as usize,
+            functab: word(7)? as usize,
+            gofunc,
+            data,
+        })
+    }
+
+    fn u32(&self, at: usize) -> Option<u32> {
+        Some(u32::from_le_bytes(self.data.get(at..at + 4)?.try_into().ok()?))
+    }
+
+    fn string(&self, at: usize) -> Option<String> {
+        let bytes = self.data.get(at..)?;
+        let end = bytes.iter().position(|&b| b == 0)?;
+        Some(String::from_utf8_lossy(&bytes[..end]).into_owned())
+    }
+
+    /// Finds the function containing `address` and the end of its code.
+    fn find_func(&self, address: u64) -> Option<(Func, u64)> {
+        let pc = address.checked_sub(self.text_start)?;
+        // functab holds nfunc (entryoff, funcoff) pairs plus an end marker
+        let entry_off = |i: usize| self.u32(self.functab + i * 8).map(|e| e as u64);
+        let (mut lo, mut hi) = (0, self.nfunc);
+        if pc >= entry_off(self.nfunc)? {
+            return None;
+        }
+        while lo + 1 < hi {
+            let mid = (lo + hi) / 2;
+            if entry_off(mid)? <= pc {
+                lo = mid;
+            } else {
+                hi = mid;
+            }
+        }
+        if pc < entry_off(lo)? {
+            return None;
+        }
+        let end = self.text_start + entry_off(lo + 1)?;
+        let func_off = self.functab + self.u32(self.functab + lo * 8 + 4)? as usize;
+
+        let (npcdata_at, pcdata_at) = match self.version {
+            Version::Go118 => (func_off + 28, func_off + 40),
+            Version::Go120 => (func_off + 28, func_off + 44),
+        };
+        let npcdata = self.u32(npcdata_at)? as usize;
+        let nfuncdata = *self.data.get(pcdata_at - 1)? as usize;
+        let inl_tree_index =
+            if npcdata > PCDATA_INL_TREE_INDEX { self.u32(pcdata_at + PCDATA_INL_TREE_INDEX * 4)? } else { 0 };
+        let inl_tree = if nfuncdata > FUNCDATA_INL_TREE {
+            Some(self.u32(pcdata_at + npcdata * 4 + FUNCDATA_INL_TREE * 4)?).filter(|&off| off != u32::MAX)
+        } else {
+            None
+        };
+
+        let func = Func {
+            entry: self.text_start + self.u32(func_off)? as u64,
+            name_off: self.u32(func_off + 4)?,
+            pcfile: self.u32(func_off + 20)?,
+            pcln: self.u32(func_off + 24)?,
+            cu_offset: self.u32(func_off + 32)?,
+            inl_tree_index,
+            inl_tree,
+        };
+        Some((func, end))
+    }
+
+    /// Looks `pc` up in a pc-value table, returning the value and the pc range it holds for.
+    ///
+    /// Tables are sequences of (value delta, pc delta) varint pairs starting at value -1.
+    fn pcvalue(&self, func: &Func, table_off: u32, pc: u64) -> Option<(i32, u64, u64)> {
+        if table_off == 0 {
+            return None;
+        }
+        let mut pos = self.pctab + table_off as usize;
+        let mut value: i32 = -1;
+        let mut start = func.entry;
+        let mut first = true;
+        loop {
+            let udelta = read_uvarint(&self.data, &mut pos)? as u32;
+            if udelta == 0 && !first {
+                return None;
+            }
+            first = false;
+            let delta = if udelta & 1 != 0 { !(udelta >> 1) as i32 } else { (udelta >> 1) as i32 };
+            let end = start + read_uvarint(&self.data, &mut pos)? * self.quantum;
+            value = value.wrapping_add(delta);
+            if pc < end {
+                return Some((value, start, end));
+            }
+            start = end;
+        }
+    }
+
+    fn file_name(&self, func: &Func, file_index: i32) -> Option<String> {
+        let at = self.cutab + (func.cu_offset as usize + usize::try_from(file_index).ok()?) * 4;
+        let offset = self.u32(at)?;
+        if offset == u32::MAX {
+            return None;
+        }
+        self.string(self.filetab + offset as usize)
+    }
+
+    /// File and line at `pc`, with the pc range both stay the same for.
+    fn location(&self, func: &Func, pc: u64) -> (Option<String>, Option<u32>, u64, u64) {
+        let mut range = (0, u64::MAX);
+        let file = self.pcvalue(func, func.pcfile, pc).and_then(|(index, start, end)| {
+            range = (start, end);
+            self.file_name(func, index)
+        });
+        let line = self.pcvalue(func, func.pcln, pc).map(|(line, start, end)| {
+            range = (range.0.max(start), range.1.min(end));
+            line as u32
+        });
+        (file, line, range.0, range.1)
+    }
+
+    /// Address range of the function containing `address`.
+    pub fn function_bounds(&self, address: u64) -> Option<(u64, u64)> {
+        let (func, end) = self.find_func(address)?;
+        Some((func.entry, end))
+    }
+
+    /// Resolves an address to its inline chain (innermost first) and the address
+    /// range sharing that chain.
+    pub fn find_frames(&self, address: u64) -> Option<(Vec<InlineFrame>, u64, u64)> {
+        let (func, func_end) = self.find_func(address)?;
+        let (file, line, mut start, mut end) = self.location(&func, address);
+        start = start.max(func.entry);
+        end = end.min(func_end);
+        let mut frames = Vec::new();
+        let mut innermost = InlineFrame { function: None, file, line };
+
+        // Walk out of inlined calls: each one is named by the tree and located at its call site
+        let mut index = match (func.inl_tree, self.pcvalue(&func, func.inl_tree_index, address)) {
+            (Some(_), Some((index, index_start, index_end))) => {
+                start = start.max(index_start);
+                end = end.min(index_end);
+                index
+            }
+            _ => -1,
+        };
+        while index >= 0 {
+            let Some((name_off, parent_pc)) = self.inlined_call(func.inl_tree?, index as usize) else { break };
+            innermost.function = self.string(self.funcname + name_off as usize);
+            frames.push(innermost);
+
+            let pc = func.entry + parent_pc as u64;
+            let (file, line, _, _) = self.location(&func, pc);
+            innermost = InlineFrame { function: None, file, line };
+            index = self.pcvalue(&func, func.inl_tree_index, pc).map_or(-1, |(index, _, _)| index);
+        }
+        innermost.function = self.string(self.funcname + func.name_off as usize);
+        frames.push(innermost);
+        Some((frames, start, end))
+    }
+
+    /// Returns the name offset and parent pc of an entry of a function's inline tree.
+    fn inlined_call(&self, tree: u32, index: usize) -> Option<(u32, u32)> {
+        let gofunc = self.gofunc.as_ref()?;
+        let field = |at: usize| Some(u32::from_le_bytes(gofunc.get(at..at + 4)?.try_into().ok()?));
+        match self.version {
+            // parent int16, funcID, _, file int32, line int32, func_ int32, parentPc int32
+            Version::Go118 => {
+                let at = tree as usize + index * 20;
+                Some((field(at + 12)?, field(at + 16)?))
+            }
+            // funcID, _ [3], nameOff int32, parentPc int32, startLine int32
+            Version::Go120 => {
+                let at = tree as usize + index * 16;
+                Some((field(at + 4)?, field(at + 8)?))
+            }
+        }
+    }
+}
+
+fn read_uvarint(data: &[u8], pos: &mut usize) -> Option<u64> {
+    let mut value = 0u64;
+    let mut shift = 0;
+    loop {
+        let byte = *data.get(*pos)?;
+        *pos += 1;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+        shift += 7;
+        if shift > 63 {
+            return None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn uvarint(out: &mut Vec<u8>, mut value: u64) {
+        while value >= 0x80 {
+            out.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        out.push(value as u8);
+    }
+
+    /// Encodes (value, end pc) runs the way the Go linker does.
+    fn pcvalue_table(out: &mut Vec<u8>, runs: &[(i32, u64)]) -> u32 {
+        let offset = out.len() as u32;
+        let (mut value, mut pc) = (-1i32, 0u64);
+        for &(next, end) in runs {
+            let delta = next - value;
+            uvarint(out, if delta < 0 { ((!delta as u64) << 1) | 1 } else { (delta as u64) << 1 });
+            uvarint(out, end - pc);
+            (value, pc) = (next, end);
+        }
+        out.push(0);
+        offset
+    }
+
+    /// A Go 1.20 table with `main.main` at text+0 (inlining `main.helper` in
+    /// 0x40..0x80) and `main.other` at text+0x100.
+    fn sample_table() -> GoFunctionTable {
+        let funcnames = b"main.main\0main.helper\0main.other\0";
+        let filetab = b"main.go\0";
+        let cutab = 0u32.to_le_bytes();
+
+        let mut pctab = vec![0];
+        let main_file = pcvalue_table(&mut pctab, &[(0, 0x100)]);
+        let main_line = pcvalue_table(&mut pctab, &[(10, 0x40), (12, 0x80), (11, 0x100)]);
+        let main_inline = pcvalue_table(&mut pctab, &[(-1, 0x40), (0, 0x80), (-1, 0x100)]);
+        let other_file = pcvalue_table(&mut pctab, &[(0, 0x80)]);
+        let other_line = pcvalue_table(&mut pctab, &[(30, 0x80)]);
+
+        let func = |entry: u32, name: u32, pcfile: u32, pcln: u32, inline: Option<u32>| {
+            let mut f = Vec::new();
+            for v in [entry, name, 0, 0, 0, pcfile, pcln, if inline.is_some() { 3 } else { 0 }, 0, 1] {
+                f.extend_from_slice(&v.to_le_bytes());
+            }
+            f.extend_from_slice(&[0, 0, 0, if inline.is_some() { 4 } else { 0 }]);
+            if let Some(inline) = inline {
+                for v in [0, 0, inline, u32::MAX, u32::MAX, u32::MAX, 0] {
+                    f.extend_from_slice(&v.to_le_bytes());
+                }
+            }
+            f
+        };
+        let main = func(0, 0, main_file, main_line, Some(main_inline));
+        let other = func(0x100, 22, other_file, other_line, None);
+        let mut functab = Vec::new();
+        for v in [0u32, 24, 0x100, 24 + main.len() as u32, 0x180, 0] {
+            functab.extend_from_slice(&v.to_le_bytes());
+        }
+        functab.extend(main);
+        functab.extend(other);
+
+        let mut data = Vec::new();
+        data.extend_from_slice(&0xfffffff1u32.to_le_bytes());
+        data.extend_from_slice(&[0, 0, 1, 8]);
+        let funcname_at = 72;
+        let cutab_at = funcname_at + funcnames.len();
+        let filetab_at = cutab_at + cutab.len();
+        let pctab_at = filetab_at + filetab.len();
+        let functab_at = pctab_at + pctab.len();
+        for v in [2, 1, 0x401000, funcname_at, cutab_at, filetab_at, pctab_at, functab_at] {
+            data.extend_from_slice(&(v as u64).to_le_bytes());
+        }
+        for part in [&funcnames[..], &cutab, filetab, &pctab, &functab] {
+            data.extend_from_slice(part);
+        }
+
+        // One inlined call of main.helper from main.main+0x3c
+        let mut gofunc = Vec::new();
+        for v in [0u32, 10, 0x3c, 20] {
+            gofunc.extend_from_slice(&v.to_le_bytes());
+        }
+        GoFunctionTable::parse(data, None, Some(gofunc)).unwrap()
+    }
+
+    fn names(frames: &[InlineFrame]) -> Vec<(String, u32)> {
+        frames.iter().map(|f| (f.function.clone().unwrap(), f.line.unwrap())).collect()
+    }
+
+    #[test]
+    fn test_function_lines() {
+        let table = sample_table();
+        let (frames, start, end) = table.find_frames(0x401010).unwrap();
+        assert_eq!(names(&frames), vec![("main.main".to_string(), 10)]);
+        assert_eq!(frames[0].file.as_deref(), Some("main.go"));
+        assert_eq!((start, end), (0x401000, 0x401040));
+
+        let (frames, start, end) = table.find_frames(0x401120).unwrap();
+        assert_eq!(names(&frames), vec![("main.other".to_string(), 30)]);
+        assert_eq!((start, end), (0x401100, 0x401180));
+
+        assert!(table.find_frames(0x401180).is_none());
+        assert!(table.find_frames(0x400fff).is_none());
+    }
+
+    #[test]
+    fn test_inline_tree() {
+        let table = sample_table();
+        let (frames, start, end) = table.find_frames(0x401050).unwrap();
+        assert_eq!(names(&frames), vec![("main.helper".to_string(), 12), ("main.main".to_string(), 10)]);
+        assert_eq!((start, end), (0x401040, 0x401080));
+    }
+}
@@ -0,0 +1,193 @@
+use anyhow::{bail, Result};
+use std::io::Write;
+
+use crate::flamegraph::escape;
+use crate::types::Report;
+
+/// Milliseconds covered by each row of a column. A thread is sampled about
+/// every 100 ms, so a single column only fills a few of its rows; since the
+/// collector's waits are jittered, bands show up across columns instead.
+const ROW_MS: u64 = 20;
+const ROWS: usize = (1000 / ROW_MS) as usize;
+const CELL_HEIGHT: f64 = 8.0;
+/// Columns get narrower as the run gets longer, down to this
+const MIN_CELL_WIDTH: f64 = 4.0;
+const MAX_CELL_WIDTH: f64 = 24.0;
+const PLOT_WIDTH: f64 = 1000.0;
+const LEFT_PAD: f64 = 70.0;
+const RIGHT_PAD: f64 = 20.0;
+/// Room for the title and subtitle
+const TOP_PAD: f64 = 50.0;
+/// Room for the time axis
+const BOTTOM_PAD: f64 = 40.0;
+const FONT_SIZE: f64 = 12.0;
+
+/// Events and samples of each cell, by second and then by offset within the second
+fn cells(report: &Report) -> Vec<[(usize, usize); ROWS]> {
+    let Some(first) = report.timeline.iter().map(|s| s.timestamp).min() else {
+        return Vec::new();
+    };
+    // Whole seconds since the first sample, so offsets line up across columns
+    let start = first / 1000 * 1000;
+    let mut columns: Vec<[(usize, usize); ROWS]> = Vec::new();
+    for sample in &report.timeline {
+        let elapsed = sample.timestamp - start;
+        let column = (elapsed / 1000) as usize;
+        if columns.len() <= column {
+            columns.resize(column + 1, [(0, 0); ROWS]);
+        }
+        let cell = &mut columns[column][(elapsed % 1000 / ROW_MS) as usize];
+        cell.0 += sample.weight;
+        cell.1 += 1;
+    }
+    columns
+}
+
+/// Fails unless the report has the sample times a heatmap is drawn from.
+pub fn check_heatmap(report: &Report) -> Result<()> {
+    if report.timeline.is_empty() {
+        bail!("A heatmap needs sample times, which reports read from folded stacks don't have");
+    }
+    Ok(())
+}
+
+/// White for no events to dark red for the busiest cell
+fn color(events: usize, max: usize) -> String {
+    let heat = events as f64 / max.max(1) as f64;
+    let channel = |from: f64, to: f64| (from + (to - from) * heat).round() as u8;
+    format!("rgb({},{},{})", channel(255.0, 180.0), channel(245.0, 20.0), channel(220.0, 0.0))
+}
+
+/// Writes a subsecond-offset heatmap of the samples as SVG: a column per
+/// second of the run, and in it the events in each slice of that second, from
+/// its start at the top to its end at the bottom. Work that happens at the same
+/// point of every second shows as a horizontal band, and changes of phase as a
+/// change between columns.
+pub fn write_heatmap(report: &Report, title: &str, out: &mut impl Write) -> Result<()> {
+    check_heatmap(report)?;
+    let columns = cells(report);
+    let max = columns.iter().flatten().map(|cell| cell.0).max().unwrap_or(0);
+    let cell_width = (PLOT_WIDTH / columns.len() as f64).clamp(MIN_CELL_WIDTH, MAX_CELL_WIDTH);
+    let plot_height = ROWS as f64 * CELL_HEIGHT;
+    let width = LEFT_PAD + columns.len() as f64 * cell_width + RIGHT_PAD;
+    let height = TOP_PAD + plot_height + BOTTOM_PAD;
+
+    writeln!(out, r#"<?xml version="1.0" standalone="no"?>"#)?;
+    writeln!(
+        out,
+        r#"<svg version="1.1" width="{w}" height="{h}" viewBox="0 0 {w} {h}" xmlns="http://www.w3.org/2000/svg">"#,
+        w = width,
+        h = height
+    )?;
+    writeln!(out, r#"<rect x="0" y="0" width="{}" height="{}" fill="rgb(255,255,255)"/>"#, width, height)?;
+    writeln!(
+        out,
+        r#"<text x="{}" y="20" text-anchor="middle" font-family="Verdana" font-size="17">{}</text>"#,
+        width / 2.0,
+        escape(title)
+    )?;
+    writeln!(
+        out,
+        r#"<text x="{}" y="38" text-anchor="middle" font-family="Verdana" font-size="{}">{} samples, {} events; busiest cell {} events</text>"#,
+        width / 2.0,
+        FONT_SIZE,
+        report.timeline.len(),
+        report.timeline.iter().map(|s| s.weight).sum::<usize>(),
+        max
+    )?;
+
+    // Offset axis, every 100 ms
+    for ms in (0..=1000).step_by(100) {
+        let y = TOP_PAD + ms as f64 / ROW_MS as f64 * CELL_HEIGHT;
+        writeln!(
+            out,
+            r#"<text x="{}" y="{}" text-anchor="end" font-family="Verdana" font-size="{}">{} ms</text>"#,
+            LEFT_PAD - 5.0,
+            y + FONT_SIZE / 3.0,
+            FONT_SIZE,
+            ms
+        )?;
+    }
+    // Time axis, labelled often enough to stay readable
+    let every = (40.0 / cell_width).ceil() as usize;
+    for second in (0..columns.len()).step_by(every) {
+        writeln!(
+            out,
+            r#"<text x="{}" y="{}" text-anchor="middle" font-family="Verdana" font-size="{}">{}s</text>"#,
+            LEFT_PAD + (second as f64 + 0.5) * cell_width,
+            TOP_PAD + plot_height + 16.0,
+            FONT_SIZE,
+            second
+        )?;
+    }
+    writeln!(
+        out,
+        r#"<text x="{}" y="{}" text-anchor="middle" font-family="Verdana" font-size="{}">seconds since the first sample</text>"#,
+        LEFT_PAD + columns.len() as f64 * cell_width / 2.0,
+        TOP_PAD + plot_height + 32.0,
+        FONT_SIZE
+    )?;
+
+    for (second, column) in columns.iter().enumerate() {
+        for (row, &(events, samples)) in column.iter().enumerate() {
+            if samples == 0 {
+                continue;
+            }
+            let offset = row as u64 * ROW_MS;
+            writeln!(
+                out,
+                r#"<rect x="{:.1}" y="{:.1}" width="{:.1}" height="{:.1}" fill="{}"><title>{}s +{}-{} ms: {} samples, {} events</title></rect>"#,
+                LEFT_PAD + second as f64 * cell_width,
+                TOP_PAD + row as f64 * CELL_HEIGHT,
+                cell_width,
+                CELL_HEIGHT,
+                color(events, max),
+                second,
+                offset,
+                offset + ROW_MS,
+                samples,
+                events
+            )?;
+        }
+    }
+    writeln!(out, "</svg>")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aggregator::{Aggregator, FrameKey};
+    use crate::types::TimedSample;
+
+    #[test]
+    fn test_heatmap() {
+        let mut aggregator = Aggregator::new();
+        aggregator.read_folded("main;eval 5\n".as_bytes()).unwrap();
+        let mut report = aggregator.generate_report();
+        assert!(write_heatmap(&report, "test", &mut Vec::new()).is_err());
+
+        let sample = |timestamp, weight| TimedSample {
+            timestamp,
+            pid: 1,
+            tid: 1,
+            thread_name: None,
+            weight,
+            stack: vec![FrameKey::function("main")],
+        };
+        report.timeline = vec![sample(1250, 2), sample(1255, 1), sample(2250, 1), sample(3999, 4)];
+        let columns = cells(&report);
+        assert_eq!(columns.len(), 3);
+        assert_eq!(columns[0][12], (3, 2));
+        assert_eq!(columns[1][12], (1, 1));
+        assert_eq!(columns[2][49], (4, 1));
+
+        let mut out = Vec::new();
+        write_heatmap(&report, "stalls <test>", &mut out).unwrap();
+        let svg = String::from_utf8(out).unwrap();
+        assert!(svg.contains("stalls &lt;test&gt;"));
+        assert_eq!(svg.matches("<title>").count(), 3);
+        assert!(svg.contains("<title>0s +240-260 ms: 2 samples, 3 events</title>"));
+        assert!(svg.contains(r#"fill="rgb(180,20,0)"><title>2s"#));
+    }
+}
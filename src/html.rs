@@ -0,0 +1,233 @@
+use anyhow::Result;
+use std::io::Write;
+
+use crate::calltree::CallTree;
+use crate::flamegraph::{escape, FlameGraph};
+use crate::types::{Report, ThreadTimeline};
+
+/// A flame graph in an iframe of its own, so the script and ids of several
+/// graphs on one page don't get in each other's way.
+fn flame_graph_frame(tree: &CallTree, title: &str) -> Result<String> {
+    let mut svg = Vec::new();
+    FlameGraph::new(tree).with_title(title).write(&mut svg)?;
+    Ok(format!(r#"<iframe class="flamegraph" srcdoc="{}"></iframe>"#, escape(&String::from_utf8(svg)?)))
+}
+
+fn thread_tree(thread: &ThreadTimeline) -> CallTree {
+    let mut tree = CallTree::new();
+    for sample in &thread.samples {
+        tree.add(&sample.stack, sample.weight);
+    }
+    tree
+}
+
+/// A table cell that sorts by `value` rather than by its text
+fn number_cell(value: impl std::fmt::Display, text: String) -> String {
+    format!(r#"<td class="num" data-value="{}">{}</td>"#, value, text)
+}
+
+fn summary_table(report: &Report) -> String {
+    let show_source = report.stats.iter().any(|s| s.file.is_some());
+    let show_address = report.stats.iter().any(|s| s.address.is_some());
+    let mut html = String::from("<table class=\"sortable\"><thead><tr><th>Function</th>");
+    if show_source {
+        html.push_str("<th>Source</th>");
+    }
+    if show_address {
+        html.push_str("<th>Address</th>");
+    }
+    html.push_str("<th>Samples</th><th>Events</th><th>%</th><th>Total %</th></tr></thead><tbody>\n");
+    for stat in &report.stats {
+        let total_percentage = stat.total_count as f64 / report.total_events.max(1) as f64 * 100.0;
+        html.push_str(&format!("<tr><td>{}</td>", escape(&stat.name)));
+        if show_source {
+            html.push_str(&format!("<td>{}</td>", escape(&stat.source().unwrap_or_default())));
+        }
+        if show_address {
+            html.push_str(&format!("<td>{}</td>", stat.address.map(|a| format!("0x{:x}", a)).unwrap_or_default()));
+        }
+        html.push_str(&number_cell(stat.samples, stat.samples.to_string()));
+        html.push_str(&number_cell(stat.count, stat.count.to_string()));
+        html.push_str(&number_cell(stat.percentage, format!("{:.2}%", stat.percentage)));
+        html.push_str(&number_cell(total_percentage, format!("{:.2}%", total_percentage)));
+        html.push_str("</tr>\n");
+    }
+    html.push_str("</tbody></table>\n");
+    html
+}
+
+fn metadata_table(report: &Report) -> String {
+    let mut rows = Vec::new();
+    if let Some(metadata) = &report.metadata {
+        rows.push(("Command", metadata.command.clone().unwrap_or_default()));
+        rows.push(("PID", metadata.pid.to_string()));
+        rows.push(("Event", metadata.event.clone()));
+        rows.push(("Duration", format!("{} ms", metadata.duration_ms)));
+        rows.push(("Sample rate", format!("{:.1} samples/s", metadata.sample_rate)));
+        rows.push(("Kernel", metadata.kernel.clone().unwrap_or_default()));
+        rows.push(("Host", metadata.host.clone().unwrap_or_default()));
+    }
+    rows.push(("Samples", report.sample_count.to_string()));
+    rows.push(("Events", report.total_events.to_string()));
+    let mut html = String::from("<table class=\"metadata\">\n");
+    for (key, value) in rows {
+        html.push_str(&format!("<tr><th>{}</th><td>{}</td></tr>\n", key, escape(&value)));
+    }
+    html.push_str("</table>\n");
+    html
+}
+
+fn memory_table(report: &Report) -> String {
+    let Some(memory) = &report.memory else {
+        return String::new();
+    };
+    let mut html = format!(
+        "<h2>Memory</h2><p>Total RSS: {} kB, PSS: {} kB</p>\n<table class=\"sortable\"><thead><tr><th>Mapping</th><th>RSS kB</th><th>PSS kB</th></tr></thead><tbody>\n",
+        memory.total_rss_kb, memory.total_pss_kb
+    );
+    for usage in memory.usage.iter().filter(|u| u.rss_kb > 0) {
+        html.push_str(&format!(
+            "<tr><td>{}</td>{}{}</tr>\n",
+            escape(&usage.name),
+            number_cell(usage.rss_kb, usage.rss_kb.to_string()),
+            number_cell(usage.pss_kb, usage.pss_kb.to_string())
+        ));
+    }
+    html.push_str("</tbody></table>\n");
+    html
+}
+
+/// Writes the report as a single HTML file that needs nothing else to be
+/// viewed: run metadata, the summary table with sortable columns, and a flame
+/// graph for all threads and for each thread in tabs.
+pub fn write_html(report: &Report, title: &str, out: &mut impl Write) -> Result<()> {
+    let threads = report.threads();
+    let thread_stats = report.thread_stats();
+
+    let mut tabs = String::from(r#"<button class="tab active" data-tab="tab-all">All threads</button>"#);
+    let mut panels = format!(
+        "<section class=\"panel active\" id=\"tab-all\">\n{}{}</section>\n",
+        flame_graph_frame(&report.call_tree, "All threads")?,
+        summary_table(report)
+    );
+    for (i, (thread, stats)) in threads.iter().zip(&thread_stats).enumerate() {
+        let name = match &stats.name {
+            Some(name) => format!("{} ({})", name, stats.tid),
+            None => stats.tid.to_string(),
+        };
+        tabs.push_str(&format!(r#"<button class="tab" data-tab="tab-{}">{}</button>"#, i, escape(&name)));
+        let mut table = String::from(
+            "<table class=\"sortable\"><thead><tr><th>Function</th><th>Events</th><th>% of thread</th></tr></thead><tbody>\n",
+        );
+        for function in &stats.functions {
+            let share = function.events as f64 / stats.events.max(1) as f64 * 100.0;
+            table.push_str(&format!(
+                "<tr><td>{}</td>{}{}</tr>\n",
+                escape(&function.name),
+                number_cell(function.events, function.events.to_string()),
+                number_cell(share, format!("{:.2}%", share))
+            ));
+        }
+        table.push_str("</tbody></table>\n");
+        panels.push_str(&format!(
+            "<section class=\"panel\" id=\"tab-{}\">\n<p>{} samples, {} events ({:.2}% of all)</p>\n{}{}</section>\n",
+            i,
+            stats.samples,
+            stats.events,
+            stats.percentage,
+            flame_graph_frame(&thread_tree(thread), &name)?,
+            table
+        ));
+    }
+
+    writeln!(out, "<!DOCTYPE html>")?;
+    writeln!(out, "<html lang=\"en\"><head><meta charset=\"utf-8\"><title>{}</title>", escape(title))?;
+    writeln!(out, "<style>{}</style></head><body>", STYLE)?;
+    writeln!(out, "<h1>{}</h1>", escape(title))?;
+    out.write_all(metadata_table(report).as_bytes())?;
+    writeln!(out, "<nav>{}</nav>", tabs)?;
+    out.write_all(panels.as_bytes())?;
+    out.write_all(memory_table(report).as_bytes())?;
+    writeln!(out, "<script>{}</script></body></html>", SCRIPT)?;
+    Ok(())
+}
+
+const STYLE: &str = "
+body { font-family: Verdana, sans-serif; font-size: 13px; margin: 20px; color: #222; }
+table { border-collapse: collapse; margin: 10px 0; }
+th, td { padding: 3px 10px; border-bottom: 1px solid #ddd; text-align: left; }
+td.num { text-align: right; font-variant-numeric: tabular-nums; }
+table.sortable th { cursor: pointer; background: #eee; user-select: none; }
+table.sortable th.asc::after { content: ' \\25B2'; }
+table.sortable th.desc::after { content: ' \\25BC'; }
+table.metadata th { background: none; }
+nav { margin-top: 20px; border-bottom: 1px solid #aaa; }
+.tab { border: 1px solid #aaa; border-bottom: none; background: #eee; padding: 5px 12px; cursor: pointer; }
+.tab.active { background: #fff; font-weight: bold; }
+.panel { display: none; }
+.panel.active { display: block; }
+iframe.flamegraph { width: 1220px; max-width: 100%; height: 500px; border: none; }
+";
+
+const SCRIPT: &str = r#"
+for (const tab of document.querySelectorAll(".tab")) {
+    tab.addEventListener("click", () => {
+        for (const other of document.querySelectorAll(".tab, .panel")) other.classList.remove("active");
+        tab.classList.add("active");
+        document.getElementById(tab.dataset.tab).classList.add("active");
+    });
+}
+for (const table of document.querySelectorAll("table.sortable")) {
+    const headers = Array.from(table.querySelectorAll("th"));
+    headers.forEach((header, column) => header.addEventListener("click", () => {
+        const descending = !header.classList.contains("desc");
+        for (const other of headers) other.classList.remove("asc", "desc");
+        header.classList.add(descending ? "desc" : "asc");
+        const key = row => {
+            const cell = row.children[column];
+            return cell.dataset.value !== undefined ? parseFloat(cell.dataset.value) : cell.textContent;
+        };
+        const body = table.tBodies[0];
+        const rows = Array.from(body.rows);
+        rows.sort((a, b) => {
+            const [x, y] = [key(a), key(b)];
+            const order = typeof x === "number" ? x - y : x.localeCompare(y);
+            return descending ? -order : order;
+        });
+        for (const row of rows) body.appendChild(row);
+    }));
+}
+// Size each flame graph's frame to the graph
+for (const frame of document.querySelectorAll("iframe.flamegraph")) {
+    frame.addEventListener("load", () => {
+        const svg = frame.contentDocument && frame.contentDocument.querySelector("svg");
+        if (svg) frame.style.height = (svg.getAttribute("height") | 0) + 10 + "px";
+    });
+}
+"#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aggregator::Aggregator;
+
+    #[test]
+    fn test_html_report() {
+        let mut aggregator = Aggregator::new();
+        aggregator.read_folded("main;eval 5\nmain;parse<int> 3\n".as_bytes()).unwrap();
+        let mut out = Vec::new();
+        write_html(&aggregator.generate_report(), "rrstat <test>", &mut out).unwrap();
+        let html = String::from_utf8(out).unwrap();
+
+        assert!(html.starts_with("<!DOCTYPE html>"));
+        assert!(html.contains("<title>rrstat &lt;test&gt;</title>"));
+        assert!(html.contains("<td>parse&lt;int&gt;</td>"));
+        assert!(html.contains(r#"<td class="num" data-value="62.5">62.50%</td>"#));
+        // The flame graph is embedded, escaped once more for the attribute
+        assert!(html.contains("srcdoc=\"&lt;?xml"));
+        assert!(html.contains("parse&amp;lt;int&amp;gt;"));
+        // Nothing is loaded from elsewhere
+        assert!(!html.contains("src=\"http"));
+        assert!(!html.contains("<link"));
+    }
+}
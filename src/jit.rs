@@ -0,0 +1,425 @@
+use anyhow::{bail, Context, Result};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::maps;
+
+const JITDUMP_MAGIC: u32 = 0x4A69_5444;
+const JITDUMP_HEADER_SIZE: usize = 40;
+const JIT_CODE_LOAD: u32 = 0;
+const JIT_CODE_MOVE: u32 = 1;
+const JIT_CODE_DEBUG_INFO: u32 = 2;
+/// Lookups that miss re-read the symbol files at most this often
+const REFRESH_INTERVAL: Duration = Duration::from_millis(100);
+/// Debug info waiting for its code load; older entries are for code that never got loaded
+const MAX_PENDING_LINES: usize = 64;
+
+/// One source line inside a JIT-compiled function
+#[derive(Debug, Clone)]
+pub struct JitLine {
+    pub address: u64,
+    pub file: String,
+    pub line: u32,
+}
+
+/// A JIT-compiled function announced by the runtime
+#[derive(Debug, Clone)]
+pub struct JitSymbol {
+    pub start: u64,
+    pub size: u64,
+    pub name: String,
+    /// Line table sorted by address, only available from jitdump
+    pub lines: Vec<JitLine>,
+}
+
+impl JitSymbol {
+    /// Source location of `address`, taken from the closest preceding line entry.
+    pub fn location(&self, address: u64) -> Option<&JitLine> {
+        let idx = self.lines.partition_point(|l| l.address <= address);
+        self.lines.get(idx.checked_sub(1)?)
+    }
+}
+
+/// Tracks how far a symbol source has been read so it can be refreshed cheaply
+#[derive(Debug)]
+struct Source {
+    path: PathBuf,
+    consumed: usize,
+}
+
+impl Source {
+    /// Reads everything after the already consumed part of the file.
+    fn read_new(&self) -> Result<Vec<u8>> {
+        let mut file = File::open(&self.path)
+            .with_context(|| format!("Failed to open {:?}", self.path))?;
+        file.seek(SeekFrom::Start(self.consumed as u64))?;
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+        Ok(data)
+    }
+}
+
+/// JIT symbols of one process, read from `/tmp/perf-<pid>.map` and `jit-<pid>.dump`.
+///
+/// Both files are append-only while the runtime is alive, so a refresh only
+/// parses what was written since the previous one. Symbols are kept
+/// non-overlapping: code announced later replaces whatever was at its address.
+#[derive(Debug)]
+pub struct JitSymbols {
+    pid: u32,
+    symbols: BTreeMap<u64, JitSymbol>,
+    perf_map: Option<Source>,
+    jitdump: Option<Source>,
+    /// Debug info records come before the code load they describe
+    pending_lines: Vec<(u64, Vec<JitLine>)>,
+    last_refresh: Option<Instant>,
+}
+
+impl JitSymbols {
+    pub fn new(pid: u32) -> Self {
+        Self {
+            pid,
+            symbols: BTreeMap::new(),
+            perf_map: None,
+            jitdump: None,
+            pending_lines: Vec::new(),
+            last_refresh: None,
+        }
+    }
+
+    /// Refreshes unless the previous refresh was less than [`REFRESH_INTERVAL`]
+    /// ago, so addresses no JIT symbol covers don't hit the filesystem every time.
+    pub fn refresh_if_stale(&mut self) -> Result<()> {
+        if self.last_refresh.is_some_and(|last| last.elapsed() < REFRESH_INTERVAL) {
+            return Ok(());
+        }
+        self.refresh()
+    }
+
+    /// Picks up any symbol files that appeared and reads newly appended entries.
+    pub fn refresh(&mut self) -> Result<()> {
+        self.last_refresh = Some(Instant::now());
+        if self.perf_map.is_none() {
+            let path = PathBuf::from(format!("/tmp/perf-{}.map", self.pid));
+            if path.exists() {
+                self.perf_map = Some(Source { path, consumed: 0 });
+            }
+        }
+        if self.jitdump.is_none() {
+            self.jitdump = find_jitdump(self.pid).map(|path| Source { path, consumed: 0 });
+        }
+
+        if let Some(source) = self.perf_map.as_mut() {
+            let data = source.read_new()?;
+            let mut symbols = Vec::new();
+            source.consumed += parse_perf_map(&data, &mut symbols);
+            for symbol in symbols {
+                insert_symbol(&mut self.symbols, symbol);
+            }
+        }
+        if let Some(source) = self.jitdump.as_mut() {
+            let data = source.read_new()?;
+            if !data.is_empty() {
+                let mut parser = JitdumpParser {
+                    symbols: &mut self.symbols,
+                    pending_lines: &mut self.pending_lines,
+                };
+                source.consumed += parser.parse(&data, source.consumed == 0)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.symbols.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    /// Finds the JIT function containing `address`.
+    pub fn lookup(&self, address: u64) -> Option<&JitSymbol> {
+        let (_, symbol) = self.symbols.range(..=address).next_back()?;
+        (address < symbol.start + symbol.size).then_some(symbol)
+    }
+}
+
+/// Inserts `symbol`, evicting older symbols whose code it overwrites.
+fn insert_symbol(symbols: &mut BTreeMap<u64, JitSymbol>, symbol: JitSymbol) {
+    let end = symbol.start + symbol.size.max(1);
+    if let Some((&start, prev)) = symbols.range(..symbol.start).next_back() {
+        if prev.start + prev.size > symbol.start {
+            symbols.remove(&start);
+        }
+    }
+    let overwritten: Vec<u64> = symbols.range(symbol.start..end).map(|(&start, _)| start).collect();
+    for start in overwritten {
+        symbols.remove(&start);
+    }
+    symbols.insert(symbol.start, symbol);
+}
+
+/// The jitdump file is mmapped by the runtime (that is how perf finds it), so look
+/// for it in the process mappings before falling back to the conventional locations.
+fn find_jitdump(pid: u32) -> Option<PathBuf> {
+    let file_name = format!("jit-{}.dump", pid);
+    if let Ok(mappings) = maps::read_mappings(pid) {
+        if let Some(mapping) = mappings.iter().find(|m| m.pathname.ends_with(&file_name)) {
+            let path = PathBuf::from(format!("/proc/{}/root{}", pid, mapping.pathname));
+            if path.exists() {
+                return Some(path);
+            }
+            return Some(PathBuf::from(&mapping.pathname));
+        }
+    }
+    let cwd = Path::new("/proc").join(pid.to_string()).join("cwd").join(&file_name);
+    let tmp = Path::new("/tmp").join(&file_name);
+    [cwd, tmp].into_iter().find(|p| p.exists())
+}
+
+/// Parses complete `START SIZE name` lines and returns the number of bytes consumed.
+fn parse_perf_map(data: &[u8], symbols: &mut Vec<JitSymbol>) -> usize {
+    let complete = match data.iter().rposition(|&b| b == b'\n') {
+        Some(pos) => pos + 1,
+        None => return 0,
+    };
+    for line in String::from_utf8_lossy(&data[..complete]).lines() {
+        let mut parts = line.trim().splitn(3, char::is_whitespace);
+        let (start, size, name) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(start), Some(size), Some(name)) => (start, size, name),
+            _ => continue,
+        };
+        let parse_hex = |s: &str| u64::from_str_radix(s.trim_start_matches("0x"), 16).ok();
+        if let (Some(start), Some(size)) = (parse_hex(start), parse_hex(size)) {
+            symbols.push(JitSymbol { start, size, name: name.trim().to_string(), lines: Vec::new() });
+        }
+    }
+    complete
+}
+
+struct JitdumpParser<'a> {
+    symbols: &'a mut BTreeMap<u64, JitSymbol>,
+    pending_lines: &'a mut Vec<(u64, Vec<JitLine>)>,
+}
+
+impl JitdumpParser<'_> {
+    /// Parses complete records and returns the number of bytes consumed.
+    ///
+    /// `at_start` is set when `data` begins with the file header.
+    fn parse(&mut self, data: &[u8], at_start: bool) -> Result<usize> {
+        let mut offset = 0;
+        if at_start {
+            if data.len() < JITDUMP_HEADER_SIZE {
+                return Ok(0);
+            }
+            if read_u32(data, 0) != Some(JITDUMP_MAGIC) {
+                bail!("Not a jitdump file (or written with a foreign byte order)");
+            }
+            offset = read_u32(data, 8).unwrap_or(JITDUMP_HEADER_SIZE as u32) as usize;
+        }
+
+        while let (Some(id), Some(size)) = (read_u32(data, offset), read_u32(data, offset + 4)) {
+            let size = size as usize;
+            if size < 16 || offset + size > data.len() {
+                break;
+            }
+            let body = &data[offset + 16..offset + size];
+            match id {
+                JIT_CODE_LOAD => self.code_load(body),
+                JIT_CODE_MOVE => self.code_move(body),
+                JIT_CODE_DEBUG_INFO => self.debug_info(body),
+                _ => {}
+            }
+            offset += size;
+        }
+        Ok(offset)
+    }
+
+    fn code_load(&mut self, body: &[u8]) {
+        // pid, tid, vma, code_addr, code_size, code_index, name
+        let (Some(start), Some(size)) = (read_u64(body, 16), read_u64(body, 24)) else {
+            return;
+        };
+        let name = read_cstr(body, 40).map(|(s, _)| s).unwrap_or_default();
+        let lines = match self.pending_lines.iter().position(|(addr, _)| *addr == start) {
+            Some(idx) => self.pending_lines.remove(idx).1,
+            None => Vec::new(),
+        };
+        insert_symbol(self.symbols, JitSymbol { start, size, name, lines });
+    }
+
+    fn code_move(&mut self, body: &[u8]) {
+        // pid, tid, vma, old_code_addr, new_code_addr, code_size, code_index
+        let (Some(old), Some(new)) = (read_u64(body, 16), read_u64(body, 24)) else {
+            return;
+        };
+        if let Some(mut moved) = self.symbols.remove(&old) {
+            moved.start = new;
+            // Lines outside the old code come from stale or malformed records
+            moved.lines.retain(|line| line.address >= old && line.address - old < moved.size);
+            for line in &mut moved.lines {
+                line.address = line.address - old + new;
+            }
+            insert_symbol(self.symbols, moved);
+        }
+    }
+
+    fn debug_info(&mut self, body: &[u8]) {
+        let (Some(code_addr), Some(count)) = (read_u64(body, 0), read_u64(body, 8)) else {
+            return;
+        };
+        let mut lines: Vec<JitLine> = Vec::new();
+        let mut pos = 16;
+        for _ in 0..count {
+            let (Some(address), Some(line)) = (read_u64(body, pos), read_u32(body, pos + 8)) else {
+                break;
+            };
+            // Entry is code_addr, line, discriminator, then the file name
+            let repeats_file = body.get(pos + 16) == Some(&0xff);
+            let Some((file, next)) = read_cstr(body, pos + 16) else {
+                break;
+            };
+            // A name of "\xff" repeats the previous entry's file
+            let file = match (repeats_file, lines.last()) {
+                (true, Some(prev)) => prev.file.clone(),
+                _ => file,
+            };
+            lines.push(JitLine { address, file, line });
+            pos = next;
+        }
+        lines.sort_by_key(|l| l.address);
+        if self.pending_lines.len() >= MAX_PENDING_LINES {
+            self.pending_lines.remove(0);
+        }
+        self.pending_lines.push((code_addr, lines));
+    }
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset..offset + 4)?;
+    Some(u32::from_ne_bytes(bytes.try_into().ok()?))
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Option<u64> {
+    let bytes = data.get(offset..offset + 8)?;
+    Some(u64::from_ne_bytes(bytes.try_into().ok()?))
+}
+
+/// Reads a NUL-terminated string, returning it with the offset just past the NUL.
+fn read_cstr(data: &[u8], offset: usize) -> Option<(String, usize)> {
+    let rest = data.get(offset..)?;
+    let len = rest.iter().position(|&b| b == 0)?;
+    Some((String::from_utf8_lossy(&rest[..len]).into_owned(), offset + len + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(id: u32, body: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(&id.to_ne_bytes());
+        out.extend_from_slice(&(16 + body.len() as u32).to_ne_bytes());
+        out.extend_from_slice(&0u64.to_ne_bytes());
+        out.extend_from_slice(body);
+        out
+    }
+
+    #[test]
+    fn test_parse_perf_map() {
+        let mut symbols = Vec::new();
+        let data = b"7f0000001000 40 LazyCompile:~add /app/index.js:3\n7f0000002000 0x10 stub\n7f00";
+        let consumed = parse_perf_map(data, &mut symbols);
+        assert_eq!(consumed, data.len() - 4);
+        assert_eq!(symbols.len(), 2);
+        assert_eq!(symbols[0].name, "LazyCompile:~add /app/index.js:3");
+        assert_eq!(symbols[0].size, 0x40);
+        assert_eq!(symbols[1].start, 0x7f0000002000);
+    }
+
+    #[test]
+    fn test_parse_jitdump_with_line_info() {
+        let mut data = Vec::new();
+        for v in [JITDUMP_MAGIC, 1, JITDUMP_HEADER_SIZE as u32, 62, 0, 42] {
+            data.extend_from_slice(&v.to_ne_bytes());
+        }
+        data.extend_from_slice(&[0u8; 16]);
+
+        let mut debug = Vec::new();
+        debug.extend_from_slice(&0x5000u64.to_ne_bytes());
+        debug.extend_from_slice(&2u64.to_ne_bytes());
+        for (addr, line, file) in [(0x5000u64, 10u32, &b"Main.java\0"[..]), (0x5010, 12, b"\xff\0")] {
+            debug.extend_from_slice(&addr.to_ne_bytes());
+            debug.extend_from_slice(&line.to_ne_bytes());
+            debug.extend_from_slice(&0u32.to_ne_bytes());
+            debug.extend_from_slice(file);
+        }
+        data.extend(record(JIT_CODE_DEBUG_INFO, &debug));
+
+        let mut load = Vec::new();
+        load.extend_from_slice(&[0u8; 8]);
+        for v in [0x5000u64, 0x5000, 0x20, 1] {
+            load.extend_from_slice(&v.to_ne_bytes());
+        }
+        load.extend_from_slice(b"Main.run\0");
+        load.extend_from_slice(&[0x90; 0x20]);
+        data.extend(record(JIT_CODE_LOAD, &load));
+
+        let mut jit = JitSymbols::new(42);
+        let mut parser = JitdumpParser { symbols: &mut jit.symbols, pending_lines: &mut jit.pending_lines };
+        assert_eq!(parser.parse(&data, true).unwrap(), data.len());
+
+        let symbol = jit.lookup(0x5014).unwrap();
+        assert_eq!(symbol.name, "Main.run");
+        let line = symbol.location(0x5014).unwrap();
+        assert_eq!((line.file.as_str(), line.line), ("Main.java", 12));
+        assert!(jit.lookup(0x5020).is_none());
+    }
+
+    #[test]
+    fn test_unloaded_debug_info_is_dropped() {
+        let mut jit = JitSymbols::new(42);
+        let mut parser = JitdumpParser { symbols: &mut jit.symbols, pending_lines: &mut jit.pending_lines };
+        for code_addr in 0..MAX_PENDING_LINES as u64 + 6 {
+            let mut debug = code_addr.to_ne_bytes().to_vec();
+            debug.extend_from_slice(&0u64.to_ne_bytes());
+            parser.debug_info(&debug);
+        }
+        assert_eq!(jit.pending_lines.len(), MAX_PENDING_LINES);
+        assert_eq!(jit.pending_lines[0].0, 6);
+    }
+
+    #[test]
+    fn test_code_move_drops_stray_lines() {
+        let mut jit = JitSymbols::new(42);
+        let line = |address, line| JitLine { address, file: "Main.java".to_string(), line };
+        let lines = vec![line(0x0f00, 1), line(0x1000, 2), line(0x1010, 3), line(0x1100, 4)];
+        insert_symbol(&mut jit.symbols, JitSymbol { start: 0x1000, size: 0x100, name: "run".to_string(), lines });
+        let mut parser = JitdumpParser { symbols: &mut jit.symbols, pending_lines: &mut jit.pending_lines };
+        let mut body = vec![0u8; 16];
+        for v in [0x1000u64, 0x8000, 0x100, 1] {
+            body.extend_from_slice(&v.to_ne_bytes());
+        }
+        parser.code_move(&body);
+
+        let moved = jit.lookup(0x8010).unwrap();
+        let lines: Vec<(u64, u32)> = moved.lines.iter().map(|l| (l.address, l.line)).collect();
+        assert_eq!(lines, [(0x8000, 2), (0x8010, 3)]);
+    }
+
+    #[test]
+    fn test_newer_code_replaces_overlapping_symbols() {
+        let mut symbols = BTreeMap::new();
+        let symbol = |start, size, name: &str| JitSymbol { start, size, name: name.to_string(), lines: Vec::new() };
+        insert_symbol(&mut symbols, symbol(0x1000, 0x100, "old"));
+        insert_symbol(&mut symbols, symbol(0x2000, 0x10, "other"));
+        insert_symbol(&mut symbols, symbol(0x1080, 0x20, "new"));
+        assert_eq!(symbols.len(), 2);
+        assert_eq!(symbols[&0x1080].name, "new");
+        assert_eq!(symbols[&0x2000].name, "other");
+    }
+}
@@ -4,6 +4,27 @@ pub mod types;
 pub mod ringbuffer;
 pub mod collector;
 pub mod symbols;
+pub mod demangle;
+pub mod symcache;
+pub mod elf;
+pub mod gopclntab;
+pub mod jit;
 pub mod maps;
+pub mod mmap_events;
+pub mod modules;
+pub mod memory;
+pub mod unwind;
+pub mod python;
 pub mod aggregator;
+pub mod calltree;
+pub mod annotate;
 pub mod report;
+pub mod flamegraph;
+pub mod pprof;
+pub mod speedscope;
+pub mod firefox;
+pub mod callgrind;
+pub mod html;
+pub mod heatmap;
+pub mod top;
+pub mod output;
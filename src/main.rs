@@ -1,8 +1,19 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 use clap::Parser;
 use std::{thread, time::Duration};
 use rrstat::profiler::PerfCounter;
+use rrstat::annotate::Annotator;
 use rrstat::cli;
+use rrstat::collector::POLL_INTERVAL;
+use rrstat::maps::MemorySummary;
+use rrstat::output::{write_output, OutputOptions};
+use rrstat::symbols::SymbolResolver;
+use rrstat::symcache::SymbolCache;
+use rrstat::report::ReportFormat;
+use rrstat::ringbuffer::RingBuffer;
+use rrstat::types::{Report, RunMetadata, Sample};
+use std::fs::File;
+use std::io::BufReader;
 use std::sync::{Arc, atomic::{AtomicBool, Ordering}};
 use signal_hook::consts::signal::SIGINT;
 use signal_hook::flag;
@@ -15,36 +26,149 @@ fn setup_ctrl_c() -> Result<Arc<AtomicBool>, anyhow::Error> {
 
 fn main() -> Result<()> {
     let args = cli::Args::parse();
+    match args.command {
+        Some(cli::Command::Annotate { function, disassemble, profile }) => {
+            let run = profile_target(&profile)?;
+            let mut annotator = Annotator::new(run.resolver, function).with_name_style(profile.demangle);
+            annotator.process_samples(run.samples);
+            rrstat::report::print_annotation(&annotator.annotate()?, disassemble);
+        }
+        None => {
+            let profile = args.profile.context("--pid is required")?;
+            let run = profile_target(&profile)?;
+            let mut aggregator = rrstat::aggregator::Aggregator::with_resolver(run.resolver)
+                .with_name_style(profile.demangle)
+                .with_granularity(profile.granularity);
+            aggregator.process_samples(run.samples);
+            let mut report = aggregator.generate_report();
+            report.memory = run.memory;
+            report.metadata = Some(run.metadata);
+            let options = OutputOptions { icicle: args.report.icicle, event: profile.event.clone(), name_style: profile.demangle };
+            show_report(&report, &args.report, &options, aggregator.resolver_mut())?;
+        }
+        Some(cli::Command::Top { profile, refresh }) => {
+            let (resolver, sampler) = start_sampling(&profile, Duration::from_millis(refresh))?;
+            let aggregator = rrstat::aggregator::Aggregator::with_resolver(resolver)
+                .with_name_style(profile.demangle)
+                .with_granularity(profile.granularity);
+            let result = rrstat::top::run(aggregator, &sampler.buffer, Duration::from_millis(refresh));
+            sampler.stop();
+            result?;
+        }
+        Some(cli::Command::Report { input, report: report_args }) => {
+            let file = File::open(&input).with_context(|| format!("Failed to open {}", input.display()))?;
+            let mut aggregator = rrstat::aggregator::Aggregator::new();
+            aggregator.read_folded(BufReader::new(file))?;
+            let options = OutputOptions { icicle: report_args.icicle, event: "events".to_string(), ..Default::default() };
+            show_report(&aggregator.generate_report(), &report_args, &options, aggregator.resolver_mut())?;
+        }
+    }
+    Ok(())
+}
+
+/// Prints the report in the requested format, then writes the requested output files.
+fn show_report(report: &Report, args: &cli::ReportArgs, options: &OutputOptions, resolver: &mut SymbolResolver) -> Result<()> {
+    match args.format {
+        ReportFormat::Text => {
+            rrstat::report::print_summary(report);
+            if let Some(function) = &args.focus {
+                rrstat::report::print_focus(report, function);
+            }
+        }
+        ReportFormat::Json => rrstat::report::write_json(report, &mut std::io::stdout().lock())?,
+        ReportFormat::Csv => rrstat::report::write_csv(report, &mut std::io::stdout().lock())?,
+    }
+    for path in &args.output {
+        write_output(report, path, options, resolver)?;
+        // Keep stdout parseable for --format json/csv
+        eprintln!("Wrote {}", path.display());
+    }
+    Ok(())
+}
+
+/// What profiling a target produced
+struct ProfiledRun {
+    /// Resolver tracking the target, usable after it exits
+    resolver: SymbolResolver,
+    samples: Vec<Sample>,
+    /// The target's memory usage at the end of the run, when requested
+    memory: Option<MemorySummary>,
+    metadata: RunMetadata,
+}
+
+/// A collector sampling the target in the background
+struct Sampler {
+    buffer: Arc<RingBuffer>,
+    running: Arc<AtomicBool>,
+    collector: thread::JoinHandle<()>,
+}
+
+impl Sampler {
+    /// Stops sampling (which leaves the target detached) and returns the samples not drained yet.
+    fn stop(self) -> Vec<Sample> {
+        self.running.store(false, Ordering::Relaxed);
+        self.collector.join().unwrap();
+        self.buffer.drain()
+    }
+}
+
+/// Starts sampling every thread of the target; returns the resolver tracking it and the sampler.
+///
+/// The buffer holds what every thread (and as many again started later) is
+/// sampled in `drain_every`, so nothing is dropped between two drains.
+fn start_sampling(args: &cli::ProfileArgs, drain_every: Duration) -> Result<(SymbolResolver, Sampler)> {
     let event = cli::parse_event(&args.event)?;
-    
-    let mut perf_counter = PerfCounter::new(args.pid, event)?;
+
+    let mut resolver = SymbolResolver::new();
+    if !args.no_symbol_cache {
+        if let Some(dir) = args.symbol_cache_dir.clone().or_else(SymbolCache::default_dir) {
+            resolver = resolver.with_disk_cache(SymbolCache::new(dir, args.symbol_cache_size * 1024 * 1024));
+        }
+    }
+    // Keep the mappings around in case the target exits before we symbolize
+    let _ = resolver.track(args.pid as u32);
+
+    let mut perf_counter = PerfCounter::new(args.pid, event.clone())?;
     perf_counter.enable()?;
-    
-    let term = setup_ctrl_c()?;
+
     let running = Arc::new(AtomicBool::new(true));
-    let buffer = Arc::new(rrstat::ringbuffer::RingBuffer::new(1024));
-    
+    let threads = rrstat::collector::list_threads(args.pid).map_or(1, |tids| tids.len());
+    // Polls wait at least half the interval
+    let polls = (drain_every.as_millis() / (POLL_INTERVAL / 2).as_millis()) as usize + 1;
+    let buffer = Arc::new(RingBuffer::new((2 * threads * polls).max(1024)));
+
     let collector = rrstat::collector::Collector::new(
         perf_counter.counter,
         Arc::clone(&buffer),
         Arc::clone(&running),
         args.pid,
-    );
-    let collector_handle = collector.spawn();
-    
+    )
+    .with_threads(event);
+    Ok((resolver, Sampler { buffer, running, collector: collector.spawn() }))
+}
+
+/// Samples the target until the duration is over or Ctrl-C is pressed.
+fn profile_target(args: &cli::ProfileArgs) -> Result<ProfiledRun> {
+    let mut metadata = RunMetadata::read(args.pid, &args.event);
+    let (resolver, sampler) = start_sampling(args, POLL_INTERVAL)?;
+    let term = setup_ctrl_c()?;
+
     let start = std::time::Instant::now();
+    let mut samples = Vec::new();
     while !term.load(Ordering::Relaxed) && start.elapsed() < Duration::from_millis(args.duration) {
-        thread::sleep(Duration::from_millis(100));
+        thread::sleep(POLL_INTERVAL);
+        samples.extend(sampler.buffer.drain());
     }
+    metadata.duration_ms = start.elapsed().as_millis() as u64;
     
-    running.store(false, Ordering::Relaxed);
-    collector_handle.join().unwrap();
-    
-    let samples = buffer.drain();
-    let mut aggregator = rrstat::aggregator::Aggregator::new();
-    aggregator.process_samples(samples);
-    let report = aggregator.generate_report();
-    rrstat::report::print_summary(&report);
-    
-    Ok(())
-}
\ No newline at end of file
+    // Read while the target is (hopefully) still alive
+    let memory = if args.memory {
+        rrstat::maps::read_smaps(args.pid as u32).ok().map(|entries| MemorySummary::from_smaps(&entries))
+    } else {
+        None
+    };
+
+    samples.extend(sampler.stop());
+    metadata.sample_rate = samples.len() as f64 * 1000.0 / metadata.duration_ms.max(1) as f64;
+    Ok(ProfiledRun { resolver, samples, memory, metadata })
+}
@@ -1,6 +1,38 @@
 use anyhow::{Context, Result};
+use serde::Serialize;
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::{BufRead, BufReader};
+use std::time::Instant;
+
+/// What backs a mapping, derived from its pathname column
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MappingKind {
+    /// A regular file, including deleted files and memfds
+    File,
+    /// No backing file: `malloc` arenas, JIT code, `[anon:<name>]` regions
+    Anonymous,
+    Heap,
+    /// The main thread's stack, or `[stack:<tid>]` on older kernels
+    Stack,
+    Vdso,
+    /// Other kernel-provided regions such as `[vvar]` or `[vsyscall]`
+    Special,
+}
+
+impl MappingKind {
+    pub fn classify(pathname: &str) -> Self {
+        match pathname {
+            "" | "//anon" => Self::Anonymous,
+            "[heap]" => Self::Heap,
+            "[vdso]" => Self::Vdso,
+            p if p.starts_with("[anon") => Self::Anonymous,
+            p if p.starts_with("[stack") => Self::Stack,
+            p if p.starts_with('[') => Self::Special,
+            _ => Self::File,
+        }
+    }
+}
 
 /// memory mapping from /proc/[pid]/maps
 #[derive(Debug, Clone)]
@@ -9,7 +41,101 @@ pub struct Mapping {
     pub end: u64,
     pub perms: String,
     pub offset: u64,
+    pub dev_major: u32,
+    pub dev_minor: u32,
+    /// Zero for anything that is not a file
+    pub inode: u64,
+    pub kind: MappingKind,
+    /// Full path, spaces included; empty for anonymous memory
     pub pathname: String,
+    /// The file was deleted or replaced after being mapped (` (deleted)` suffix)
+    pub deleted: bool,
+}
+
+impl Mapping {
+    /// True for memory not backed by a file, e.g. code emitted by a JIT
+    pub fn is_anonymous(&self) -> bool {
+        self.kind == MappingKind::Anonymous
+    }
+
+    pub fn size(&self) -> u64 {
+        self.end - self.start
+    }
+}
+
+/// The mapping table of one process, sorted by address.
+///
+/// Taken once when profiling starts and then kept up to date with mmap events
+/// or by re-reading `/proc/[pid]/maps`. If the process exits, the last known
+/// table stays usable, so samples can still be symbolized afterwards.
+#[derive(Debug, Clone)]
+pub struct MemoryMap {
+    pid: u32,
+    mappings: Vec<Mapping>,
+    refreshed_at: Instant,
+}
+
+impl MemoryMap {
+    pub fn snapshot(pid: u32) -> Result<Self> {
+        Ok(Self::from_mappings(pid, read_mappings(pid)?))
+    }
+
+    pub fn from_mappings(pid: u32, mut mappings: Vec<Mapping>) -> Self {
+        mappings.sort_by_key(|m| m.start);
+        Self { pid, mappings, refreshed_at: Instant::now() }
+    }
+
+    pub fn pid(&self) -> u32 {
+        self.pid
+    }
+
+    pub fn mappings(&self) -> &[Mapping] {
+        &self.mappings
+    }
+
+    /// When the table was last read from `/proc`
+    pub fn refreshed_at(&self) -> Instant {
+        self.refreshed_at
+    }
+
+    /// Finds the mapping containing `address`.
+    pub fn find(&self, address: u64) -> Option<&Mapping> {
+        let idx = self.mappings.partition_point(|m| m.start <= address);
+        let mapping = self.mappings.get(idx.checked_sub(1)?)?;
+        (address < mapping.end).then_some(mapping)
+    }
+
+    /// Re-reads `/proc/[pid]/maps`, e.g. after a `dlopen`. Keeps the old table
+    /// if the process is gone.
+    pub fn refresh(&mut self) -> Result<()> {
+        let mappings = read_mappings(self.pid)?;
+        *self = Self::from_mappings(self.pid, mappings);
+        Ok(())
+    }
+
+    /// Applies a new mapping reported by the kernel (`PERF_RECORD_MMAP2`).
+    ///
+    /// Like `mmap(MAP_FIXED)`, it replaces whatever was mapped in its range;
+    /// partially covered mappings are trimmed.
+    pub fn apply(&mut self, mapping: Mapping) {
+        let mut updated = Vec::with_capacity(self.mappings.len() + 2);
+        for old in self.mappings.drain(..) {
+            if old.end <= mapping.start || old.start >= mapping.end {
+                updated.push(old);
+                continue;
+            }
+            if old.start < mapping.start {
+                updated.push(Mapping { end: mapping.start, ..old.clone() });
+            }
+            if old.end > mapping.end {
+                let skipped = mapping.end - old.start;
+                updated.push(Mapping { start: mapping.end, offset: old.offset + skipped, ..old });
+            }
+        }
+        updated.push(mapping);
+        updated.sort_by_key(|m| m.start);
+        self.mappings = updated;
+    }
 }
 
 /// Finds the mapping for a specific binary or library containing the given address.
@@ -17,51 +143,258 @@ pub struct Mapping {
 /// This is used to compute the "relative address" (offset) needed by addr2line.
 /// Real addresses in a running process are randomized (ASLR).
 ///
-/// Returns the mapping closest to the address that matches the given object name hint,
-/// or simply the first code segment if no hint is relevant.
+/// Takes a [`MemoryMap`] snapshot of the process and returns the executable
+/// mapping containing the address, or `None` when it is outside any code.
 pub fn find_mapping_for_address(pid: u32, address: u64) -> Result<Option<Mapping>> {
+    let map = MemoryMap::snapshot(pid)?;
+    Ok(map.find(address).filter(|m| m.perms.contains('x')).cloned())
+}
+
+/// Reads every mapping of the process, including anonymous ones.
+pub fn read_mappings(pid: u32) -> Result<Vec<Mapping>> {
     let maps_path = format!("/proc/{}/maps", pid);
     let file = File::open(&maps_path)
         .with_context(|| format!("Failed to open maps file: {}", maps_path))?;
     let reader = BufReader::new(file);
 
+    let mut mappings = Vec::new();
     for line in reader.lines() {
-        let line = line?;
-        if let Some(mapping) = parse_map_line(&line) {
-            if address >= mapping.start && address < mapping.end {
-                if mapping.perms.contains('x') {
-                     return Ok(Some(mapping));
-                }
-            }
+        if let Some(mapping) = parse_map_line(&line?) {
+            mappings.push(mapping);
         }
     }
+    Ok(mappings)
+}
 
-    Ok(None)
+/// Splits the next whitespace-separated column off `rest`.
+fn next_field<'a>(rest: &mut &'a str) -> Option<&'a str> {
+    let trimmed = rest.trim_start();
+    let end = trimmed.find(char::is_whitespace).unwrap_or(trimmed.len());
+    let (field, remainder) = trimmed.split_at(end);
+    *rest = remainder;
+    (!field.is_empty()).then_some(field)
 }
 
 fn parse_map_line(line: &str) -> Option<Mapping> {
     // Format: 7f45c000-7f45e000 r-xp 00000000 08:01 123456 /path/to/file
-    let parts: Vec<&str> = line.split_whitespace().collect();
-    if parts.len() < 6 {
-        return None;
-    }
-
-    let range_parts: Vec<&str> = parts[0].split('-').collect();
-    if range_parts.len() != 2 {
-        return None;
-    }
+    // Anonymous mappings have no pathname column; pathnames may contain spaces.
+    let mut rest = line;
+    let (start, end) = next_field(&mut rest)?.split_once('-')?;
+    let start = u64::from_str_radix(start, 16).ok()?;
+    let end = u64::from_str_radix(end, 16).ok()?;
+    let perms = next_field(&mut rest)?.to_string();
+    let offset = u64::from_str_radix(next_field(&mut rest)?, 16).ok()?;
+    let (dev_major, dev_minor) = next_field(&mut rest)?.split_once(':')?;
+    let dev_major = u32::from_str_radix(dev_major, 16).ok()?;
+    let dev_minor = u32::from_str_radix(dev_minor, 16).ok()?;
+    let inode = next_field(&mut rest)?.parse().ok()?;
 
-    let start = u64::from_str_radix(range_parts[0], 16).ok()?;
-    let end = u64::from_str_radix(range_parts[1], 16).ok()?;
-    let perms = parts[1].to_string();
-    let offset = u64::from_str_radix(parts[2], 16).ok()?;
-    let pathname = parts[5].to_string();
+    let pathname = rest.trim_start();
+    let (pathname, deleted) = match pathname.strip_suffix(" (deleted)") {
+        Some(pathname) => (pathname, true),
+        None => (pathname, false),
+    };
 
     Some(Mapping {
         start,
         end,
         perms,
         offset,
-        pathname,
+        dev_major,
+        dev_minor,
+        inode,
+        kind: MappingKind::classify(pathname),
+        pathname: pathname.to_string(),
+        deleted,
     })
-}
\ No newline at end of file
+}
+
+/// A mapping with its memory usage from `/proc/[pid]/smaps`, in KiB
+#[derive(Debug, Clone)]
+pub struct SmapsEntry {
+    pub mapping: Mapping,
+    pub rss_kb: u64,
+    /// Proportional set size: shared pages are divided among the processes mapping them
+    pub pss_kb: u64,
+}
+
+/// Reads the per-mapping memory usage of a process.
+pub fn read_smaps(pid: u32) -> Result<Vec<SmapsEntry>> {
+    let smaps_path = format!("/proc/{}/smaps", pid);
+    let file = File::open(&smaps_path)
+        .with_context(|| format!("Failed to open smaps file: {}", smaps_path))?;
+    parse_smaps(BufReader::new(file))
+}
+
+fn parse_smaps(reader: impl BufRead) -> Result<Vec<SmapsEntry>> {
+    let mut entries: Vec<SmapsEntry> = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        // Each mapping line is followed by "Key:   value kB" lines
+        let mut rest = line.as_str();
+        let Some(key) = next_field(&mut rest) else { continue };
+        if !key.ends_with(':') {
+            if let Some(mapping) = parse_map_line(&line) {
+                entries.push(SmapsEntry { mapping, rss_kb: 0, pss_kb: 0 });
+            }
+            continue;
+        }
+        let (Some(entry), Some(value)) = (entries.last_mut(), next_field(&mut rest)) else { continue };
+        match key {
+            "Rss:" => entry.rss_kb = value.parse().unwrap_or(0),
+            "Pss:" => entry.pss_kb = value.parse().unwrap_or(0),
+            _ => {}
+        }
+    }
+    Ok(entries)
+}
+
+/// Memory usage of one group of mappings in the report
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct MemoryUsage {
+    /// File path, or the kind of memory (`[anon]`, `[heap]`, `[stack]`, ...)
+    pub name: String,
+    pub mappings: usize,
+    pub rss_kb: u64,
+    pub pss_kb: u64,
+}
+
+/// Memory of the profiled process grouped by file and by kind of memory
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct MemorySummary {
+    pub total_rss_kb: u64,
+    pub total_pss_kb: u64,
+    /// Largest RSS first
+    pub usage: Vec<MemoryUsage>,
+}
+
+impl MemorySummary {
+    pub fn from_smaps(entries: &[SmapsEntry]) -> Self {
+        let mut groups: HashMap<String, MemoryUsage> = HashMap::new();
+        for entry in entries {
+            let name = match entry.mapping.kind {
+                MappingKind::Anonymous => "[anon]",
+                MappingKind::Stack => "[stack]",
+                _ => entry.mapping.pathname.as_str(),
+            };
+            let group = groups.entry(name.to_string()).or_insert_with(|| MemoryUsage {
+                name: name.to_string(),
+                mappings: 0,
+                rss_kb: 0,
+                pss_kb: 0,
+            });
+            group.mappings += 1;
+            group.rss_kb += entry.rss_kb;
+            group.pss_kb += entry.pss_kb;
+        }
+
+        let mut usage: Vec<MemoryUsage> = groups.into_values().collect();
+        usage.sort_by(|a, b| b.rss_kb.cmp(&a.rss_kb).then_with(|| a.name.cmp(&b.name)));
+        Self {
+            total_rss_kb: usage.iter().map(|u| u.rss_kb).sum(),
+            total_pss_kb: usage.iter().map(|u| u.pss_kb).sum(),
+            usage,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mapping(start: u64, end: u64, offset: u64, pathname: &str) -> Mapping {
+        Mapping {
+            start,
+            end,
+            perms: "r-xp".to_string(),
+            offset,
+            dev_major: 0,
+            dev_minor: 0,
+            inode: 0,
+            kind: MappingKind::classify(pathname),
+            pathname: pathname.to_string(),
+            deleted: false,
+        }
+    }
+
+    #[test]
+    fn test_find_uses_sorted_table() {
+        let map = MemoryMap::from_mappings(1, vec![
+            mapping(0x3000, 0x4000, 0, "/lib/b.so"),
+            mapping(0x1000, 0x2000, 0, "/lib/a.so"),
+        ]);
+        assert_eq!(map.find(0x1fff).unwrap().pathname, "/lib/a.so");
+        assert!(map.find(0x2000).is_none());
+        assert_eq!(map.find(0x3000).unwrap().pathname, "/lib/b.so");
+    }
+
+    #[test]
+    fn test_apply_replaces_overlapped_range() {
+        let mut map = MemoryMap::from_mappings(1, vec![mapping(0x1000, 0x5000, 0x100, "")]);
+        map.apply(mapping(0x2000, 0x3000, 0, "/lib/plugin.so"));
+        let ranges: Vec<_> = map.mappings().iter().map(|m| (m.start, m.end, m.offset)).collect();
+        assert_eq!(ranges, vec![(0x1000, 0x2000, 0x100), (0x2000, 0x3000, 0), (0x3000, 0x5000, 0x2100)]);
+        assert_eq!(map.find(0x2800).unwrap().pathname, "/lib/plugin.so");
+    }
+
+    #[test]
+    fn test_parse_map_line_columns() {
+        let m = parse_map_line("55d0c6a00000-55d0c6a21000 r-xp 00002000 fd:01 1319884                    /usr/bin/my app").unwrap();
+        assert_eq!((m.start, m.end, m.offset), (0x55d0c6a00000, 0x55d0c6a21000, 0x2000));
+        assert_eq!((m.dev_major, m.dev_minor, m.inode), (0xfd, 1, 1319884));
+        assert_eq!(m.pathname, "/usr/bin/my app");
+        assert_eq!(m.kind, MappingKind::File);
+        assert!(!m.deleted);
+
+        let m = parse_map_line("7f0000000000-7f0000001000 r-xp 00000000 08:01 42 /tmp/old lib.so (deleted)").unwrap();
+        assert_eq!(m.pathname, "/tmp/old lib.so");
+        assert!(m.deleted);
+    }
+
+    #[test]
+    fn test_parse_special_mappings() {
+        let kind = |line: &str| parse_map_line(line).unwrap().kind;
+        assert_eq!(kind("7f0000000000-7f0000021000 rw-p 00000000 00:00 0 "), MappingKind::Anonymous);
+        assert_eq!(kind("7f0000000000-7f0000021000 rw-p 00000000 00:00 0"), MappingKind::Anonymous);
+        assert_eq!(kind("7f0000000000-7f0000021000 rw-p 00000000 00:00 0    [anon:jemalloc]"), MappingKind::Anonymous);
+        assert_eq!(kind("55d0c7000000-55d0c7021000 rw-p 00000000 00:00 0    [heap]"), MappingKind::Heap);
+        assert_eq!(kind("7ffd00000000-7ffd00021000 rw-p 00000000 00:00 0    [stack]"), MappingKind::Stack);
+        assert_eq!(kind("7ffd00100000-7ffd00102000 r-xp 00000000 00:00 0    [vdso]"), MappingKind::Vdso);
+        assert_eq!(kind("ffffffffff600000-ffffffffff601000 --xp 00000000 00:00 0 [vsyscall]"), MappingKind::Special);
+        assert!(parse_map_line("7f0000000000-7f0000021000 rw-p 00000000").is_none());
+    }
+
+    #[test]
+    fn test_memory_summary_from_smaps() {
+        let smaps = "\
+55d0c6a00000-55d0c6a21000 r-xp 00002000 fd:01 1319884 /usr/bin/app
+Size:                132 kB
+Rss:                 100 kB
+Pss:                  60 kB
+VmFlags: rd ex mr mw me dw
+55d0c7000000-55d0c7021000 rw-p 00000000 00:00 0 [heap]
+Rss:                 300 kB
+Pss:                 300 kB
+7f0000000000-7f0000021000 rw-p 00000000 00:00 0
+Rss:                  20 kB
+Pss:                  20 kB
+7f0000100000-7f0000121000 rw-p 00000000 00:00 0 [anon:arena]
+Rss:                  30 kB
+Pss:                  25 kB
+";
+        let entries = parse_smaps(smaps.as_bytes()).unwrap();
+        assert_eq!(entries.len(), 4);
+        assert_eq!((entries[0].rss_kb, entries[0].pss_kb), (100, 60));
+
+        let summary = MemorySummary::from_smaps(&entries);
+        assert_eq!((summary.total_rss_kb, summary.total_pss_kb), (450, 405));
+        let rows: Vec<_> = summary.usage.iter().map(|u| (u.name.as_str(), u.mappings, u.rss_kb)).collect();
+        assert_eq!(rows, vec![("[heap]", 1, 300), ("/usr/bin/app", 1, 100), ("[anon]", 2, 50)]);
+    }
+
+    #[test]
+    fn test_read_own_smaps() {
+        let entries = read_smaps(std::process::id()).unwrap();
+        assert!(entries.iter().any(|e| e.mapping.kind == MappingKind::Stack && e.rss_kb > 0));
+    }
+}
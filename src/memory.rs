@@ -0,0 +1,37 @@
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+
+/// Reads `len` bytes at `address` from the memory of another process.
+///
+/// Tries `process_vm_readv` first (one syscall, no file descriptor) and falls
+/// back to `/proc/<pid>/mem`, which also works for regions like the vDSO that
+/// some kernels refuse to copy with `process_vm_readv`. Both need ptrace access.
+pub fn read_process_memory(pid: u32, address: u64, len: usize) -> Result<Vec<u8>> {
+    let mut buf = vec![0u8; len];
+    let local = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+        iov_len: len,
+    };
+    let remote = libc::iovec {
+        iov_base: address as *mut libc::c_void,
+        iov_len: len,
+    };
+    let read = unsafe { libc::process_vm_readv(pid as libc::pid_t, &local, 1, &remote, 1, 0) };
+    if read == len as isize {
+        return Ok(buf);
+    }
+
+    let mem_path = format!("/proc/{}/mem", pid);
+    let file = File::open(&mem_path).with_context(|| format!("Failed to open {}", mem_path))?;
+    file.read_exact_at(&mut buf, address)
+        .with_context(|| format!("Failed to read {} bytes at 0x{:x} from {}", len, address, mem_path))?;
+    Ok(buf)
+}
+
+
+/// Reads a native-endian 64-bit word, e.g. a pointer or a saved register.
+pub fn read_u64(pid: u32, address: u64) -> Result<u64> {
+    let bytes = read_process_memory(pid, address, 8)?;
+    Ok(u64::from_ne_bytes(bytes.try_into().unwrap()))
+}
@@ -0,0 +1,186 @@
+use anyhow::{bail, Result};
+use perf_event_open_sys::bindings;
+use std::sync::atomic::{fence, Ordering};
+
+use crate::maps::{Mapping, MappingKind};
+
+/// Data pages of the ring buffer; mmap records are small and rare, and there
+/// is one buffer per thread
+const DATA_PAGES: usize = 2;
+
+/// What a watcher received since it was last polled
+#[derive(Debug, Default)]
+pub struct MmapRecords {
+    pub mappings: Vec<Mapping>,
+    /// The buffer was full and the kernel dropped records, so mappings may be
+    /// missing: `/proc/<pid>/maps` has to be read again
+    pub lost: bool,
+}
+
+/// Receives `PERF_RECORD_MMAP2` events for one thread, so new executable
+/// mappings (`dlopen`, JIT code files) are known without re-reading `/proc`.
+///
+/// The kernel reports an mmap to the events of the thread that made it, and
+/// won't let inherited per-thread events be mapped, so every thread of the
+/// target needs a watcher of its own.
+///
+/// Uses a dummy software event that counts nothing and only produces side-band records.
+pub struct MmapWatcher {
+    fd: libc::c_int,
+    base: *mut libc::c_void,
+    len: usize,
+}
+
+// The ring buffer is only touched through `&mut self`
+unsafe impl Send for MmapWatcher {}
+
+impl MmapWatcher {
+    pub fn new(tid: i32) -> Result<Self> {
+        let mut attr: bindings::perf_event_attr = unsafe { std::mem::zeroed() };
+        attr.type_ = bindings::PERF_TYPE_SOFTWARE;
+        attr.size = std::mem::size_of::<bindings::perf_event_attr>() as u32;
+        attr.config = bindings::PERF_COUNT_SW_DUMMY as u64;
+        attr.set_mmap(1);
+        attr.set_mmap2(1);
+        attr.set_exclude_kernel(1);
+        attr.set_exclude_hv(1);
+
+        let fd = unsafe {
+            perf_event_open_sys::perf_event_open(&mut attr, tid, -1, -1, bindings::PERF_FLAG_FD_CLOEXEC as libc::c_ulong)
+        };
+        if fd < 0 {
+            bail!("perf_event_open for mmap events failed: {}", std::io::Error::last_os_error());
+        }
+
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let len = page_size * (1 + DATA_PAGES);
+        let base = unsafe {
+            libc::mmap(std::ptr::null_mut(), len, libc::PROT_READ | libc::PROT_WRITE, libc::MAP_SHARED, fd, 0)
+        };
+        if base == libc::MAP_FAILED {
+            let err = std::io::Error::last_os_error();
+            unsafe { libc::close(fd) };
+            bail!("Failed to map the perf ring buffer: {}", err);
+        }
+        Ok(Self { fd, base, len })
+    }
+
+    /// Returns the mappings created since the previous call.
+    pub fn poll(&mut self) -> MmapRecords {
+        let page = self.base as *mut bindings::perf_event_mmap_page;
+        let (data, data_size, head, tail) = unsafe {
+            let offset = (*page).data_offset as usize;
+            let size = (*page).data_size as usize;
+            let head = std::ptr::read_volatile(&(*page).data_head);
+            fence(Ordering::Acquire);
+            ((self.base as *const u8).add(offset), size, head, (*page).data_tail)
+        };
+
+        let mut records = MmapRecords::default();
+        let mut pos = tail;
+        while pos < head {
+            let read = |at: u64, len: usize| -> Vec<u8> {
+                (0..len as u64)
+                    .map(|i| unsafe { *data.add(((at + i) % data_size as u64) as usize) })
+                    .collect()
+            };
+            let header = read(pos, 8);
+            let kind = u32::from_ne_bytes(header[0..4].try_into().unwrap());
+            let size = u16::from_ne_bytes(header[6..8].try_into().unwrap()) as usize;
+            if size < 8 {
+                break;
+            }
+            match kind {
+                bindings::PERF_RECORD_MMAP2 => records.mappings.extend(parse_mmap2(&read(pos, size))),
+                bindings::PERF_RECORD_LOST => records.lost = true,
+                _ => {}
+            }
+            pos += size as u64;
+        }
+
+        fence(Ordering::Release);
+        unsafe { std::ptr::write_volatile(&mut (*page).data_tail, head) };
+        records
+    }
+}
+
+impl Drop for MmapWatcher {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.base, self.len);
+            libc::close(self.fd);
+        }
+    }
+}
+
+/// Layout after the 8-byte header: pid, tid (u32); addr, len, pgoff (u64);
+/// maj, min (u32); ino, ino_generation (u64); prot, flags (u32); filename.
+fn parse_mmap2(record: &[u8]) -> Option<Mapping> {
+    let u32_at = |at: usize| Some(u32::from_ne_bytes(record.get(at..at + 4)?.try_into().ok()?));
+    let u64_at = |at: usize| Some(u64::from_ne_bytes(record.get(at..at + 8)?.try_into().ok()?));
+
+    let start = u64_at(16)?;
+    let len = u64_at(24)?;
+    let offset = u64_at(32)?;
+    let dev_major = u32_at(40)?;
+    let dev_minor = u32_at(44)?;
+    let inode = u64_at(48)?;
+    let prot = u32_at(64)? as i32;
+    let flags = u32_at(68)? as i32;
+    let name = record.get(72..)?;
+    let name = &name[..name.iter().position(|&b| b == 0).unwrap_or(name.len())];
+
+    let perm = |bit: i32, c: char| if prot & bit != 0 { c } else { '-' };
+    let shared = if flags & libc::MAP_SHARED != 0 { 's' } else { 'p' };
+    // Anonymous memory is reported as "//anon", which `Mapping::is_anonymous` understands
+    let pathname = String::from_utf8_lossy(name).into_owned();
+    let (pathname, deleted) = match pathname.strip_suffix(" (deleted)") {
+        Some(pathname) => (pathname.to_string(), true),
+        None => (pathname, false),
+    };
+    Some(Mapping {
+        start,
+        end: start + len,
+        perms: [perm(libc::PROT_READ, 'r'), perm(libc::PROT_WRITE, 'w'), perm(libc::PROT_EXEC, 'x'), shared]
+            .iter()
+            .collect(),
+        offset,
+        dev_major,
+        dev_minor,
+        inode,
+        kind: MappingKind::classify(&pathname),
+        pathname,
+        deleted,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_mmap2_record() {
+        let mut record = vec![0u8; 8];
+        for v in [42u32, 42] {
+            record.extend_from_slice(&v.to_ne_bytes());
+        }
+        for v in [0x7f00_0000_1000u64, 0x2000, 0x1000] {
+            record.extend_from_slice(&v.to_ne_bytes());
+        }
+        for v in [8u32, 1] {
+            record.extend_from_slice(&v.to_ne_bytes());
+        }
+        record.extend_from_slice(&1234u64.to_ne_bytes());
+        record.extend_from_slice(&[0u8; 8]);
+        record.extend_from_slice(&((libc::PROT_READ | libc::PROT_EXEC) as u32).to_ne_bytes());
+        record.extend_from_slice(&(libc::MAP_PRIVATE as u32).to_ne_bytes());
+        record.extend_from_slice(b"/usr/lib/libplugin.so\0\0\0");
+
+        let mapping = parse_mmap2(&record).unwrap();
+        assert_eq!((mapping.start, mapping.end, mapping.offset), (0x7f00_0000_1000, 0x7f00_0000_3000, 0x1000));
+        assert_eq!(mapping.perms, "r-xp");
+        assert_eq!(mapping.pathname, "/usr/lib/libplugin.so");
+        assert_eq!((mapping.dev_major, mapping.dev_minor, mapping.inode), (8, 1, 1234));
+        assert_eq!(mapping.kind, MappingKind::File);
+    }
+}
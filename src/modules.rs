@@ -0,0 +1,284 @@
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+
+use crate::elf;
+use crate::maps::{Mapping, MappingKind, MemoryMap};
+use crate::memory;
+
+/// Index of a binary in a [`ModuleTable`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ModuleId(pub u32);
+
+/// An address normalized to the binary it belongs to.
+///
+/// Unlike `(pid, address)` this is the same for every process mapping the binary,
+/// whatever its ASLR layout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ModuleAddress {
+    pub module: ModuleId,
+    /// Offset into the file
+    pub offset: u64,
+}
+
+/// A binary mapped by one or more profiled processes
+#[derive(Debug)]
+pub struct Module {
+    /// Pathname as shown in `/proc/<pid>/maps`
+    pub name: String,
+    /// Where the file can be opened from our mount namespace, if it can be found at all
+    pub path: Option<PathBuf>,
+    pub build_id: Option<Vec<u8>>,
+    /// In-memory ELF image for modules without a file, i.e. the vDSO
+    pub image: Option<Arc<[u8]>>,
+}
+
+/// Binaries seen so far, deduplicated by build-id (or path when there is none)
+#[derive(Debug, Default)]
+pub struct ModuleTable {
+    modules: Vec<Arc<Module>>,
+    by_key: HashMap<Vec<u8>, ModuleId>,
+    /// The vDSO, once its image was read from some process
+    vdso: Option<ModuleId>,
+}
+
+pub type SharedModules = Arc<Mutex<ModuleTable>>;
+
+impl ModuleTable {
+    /// The table shared by every collector and resolver of this process, so a
+    /// [`ModuleAddress`] means the same thing wherever it ends up.
+    pub fn global() -> SharedModules {
+        static GLOBAL: OnceLock<SharedModules> = OnceLock::new();
+        Arc::clone(GLOBAL.get_or_init(SharedModules::default))
+    }
+
+    pub fn get(&self, id: ModuleId) -> Option<Arc<Module>> {
+        self.modules.get(id.0 as usize).cloned()
+    }
+
+    pub fn len(&self) -> usize {
+        self.modules.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.modules.is_empty()
+    }
+
+    pub fn insert(&mut self, module: Module) -> ModuleId {
+        let key = match (&module.build_id, &module.path) {
+            (Some(build_id), _) => build_id.clone(),
+            (None, Some(path)) => path.to_string_lossy().as_bytes().to_vec(),
+            (None, None) => module.name.as_bytes().to_vec(),
+        };
+        if let Some(&id) = self.by_key.get(&key) {
+            return id;
+        }
+        let id = ModuleId(self.modules.len() as u32);
+        self.modules.push(Arc::new(module));
+        self.by_key.insert(key, id);
+        id
+    }
+
+    /// Adds the vDSO read from a process, which then stands for the vDSO of
+    /// every later process: it is the same image until the next boot.
+    pub fn insert_vdso(&mut self, module: Module) -> ModuleId {
+        let loaded = module.image.is_some();
+        let id = self.insert(module);
+        if loaded {
+            self.vdso = Some(id);
+        }
+        id
+    }
+}
+
+/// Re-reading `/proc/<pid>/maps` on unknown addresses happens at most this often
+const REFRESH_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Turns `(pid, address)` into [`ModuleAddress`]es using each process's [`MemoryMap`].
+pub struct AddressNormalizer {
+    modules: SharedModules,
+    maps: HashMap<u32, MemoryMap>,
+    /// Module of each file mapping, registered on first use
+    module_ids: HashMap<(u32, u64, String), ModuleId>,
+}
+
+impl AddressNormalizer {
+    pub fn new(modules: SharedModules) -> Self {
+        Self { modules, maps: HashMap::new(), module_ids: HashMap::new() }
+    }
+
+    /// Snapshots the mappings of a process now, so its addresses can still be
+    /// normalized after it exits.
+    pub fn track(&mut self, pid: u32) -> Result<()> {
+        self.maps.insert(pid, MemoryMap::snapshot(pid)?);
+        Ok(())
+    }
+
+    /// Mappings of a tracked process, as last seen.
+    pub fn mappings(&self, pid: u32) -> Option<&[Mapping]> {
+        self.maps.get(&pid).map(|map| map.mappings())
+    }
+
+    /// Records a mapping reported by a `PERF_RECORD_MMAP2` event.
+    pub fn apply_mmap(&mut self, pid: u32, mapping: Mapping) {
+        if let Some(map) = self.maps.get_mut(&pid) {
+            map.apply(mapping);
+        }
+    }
+
+    /// Returns `None` for addresses outside file-backed code, e.g. JIT code.
+    pub fn normalize(&mut self, pid: u32, address: u64) -> Option<ModuleAddress> {
+        if !self.maps.contains_key(&pid) {
+            self.track(pid).ok()?;
+        }
+        let map = self.maps.get_mut(&pid)?;
+        if map.find(address).is_none() && map.refreshed_at().elapsed() >= REFRESH_INTERVAL {
+            // Unknown address: the process may have called dlopen since we looked.
+            // If it exited, the last snapshot is all there is.
+            let _ = map.refresh();
+        }
+
+        let mapping = map.find(address).filter(|m| m.perms.contains('x'))?;
+        if mapping.is_anonymous() {
+            return None;
+        }
+        let offset = address - mapping.start + mapping.offset;
+        let key = (pid, mapping.start, mapping.pathname.clone());
+        let module = match self.module_ids.get(&key) {
+            Some(&module) => module,
+            None => {
+                let vdso = mapping.kind == MappingKind::Vdso;
+                let cached = self.modules.lock().unwrap().vdso.filter(|_| vdso);
+                let id = match cached {
+                    Some(id) => id,
+                    None => {
+                        let module = load_module(pid, mapping);
+                        let mut modules = self.modules.lock().unwrap();
+                        if vdso { modules.insert_vdso(module) } else { modules.insert(module) }
+                    }
+                };
+                self.module_ids.insert(key, id);
+                id
+            }
+        };
+        Some(ModuleAddress { module, offset })
+    }
+}
+
+/// Collects what is needed to symbolize the binary later, even after the process exited.
+fn load_module(pid: u32, mapping: &Mapping) -> Module {
+    if mapping.kind == MappingKind::Vdso {
+        let image = memory::read_process_memory(pid, mapping.start, (mapping.end - mapping.start) as usize)
+            .ok()
+            .map(Arc::<[u8]>::from);
+        let build_id = image.as_ref().and_then(|data| {
+            use object::Object;
+            object::File::parse(&**data).ok()?.build_id().ok()?.map(|id| id.to_vec())
+        });
+        return Module { name: mapping.pathname.clone(), path: None, build_id, image };
+    }
+
+    let path = locate_binary(pid, mapping).ok();
+    let build_id = path.as_ref().and_then(|p| elf::read_build_id(p).ok().flatten());
+    Module { name: mapping.pathname.clone(), path, build_id, image: None }
+}
+
+/// Finds a path to the file that is actually mapped, as seen from our mount namespace.
+///
+/// Containerized targets see their own root, so the mapped path is opened through
+/// `/proc/<pid>/root`. Files that were deleted or replaced since being mapped (package
+/// upgrades) are only reachable through `/proc/<pid>/map_files`. When that entry can be
+/// read and has a build-id, the build-id is the reference a candidate path has to match.
+pub fn locate_binary(pid: u32, mapping: &Mapping) -> Result<PathBuf> {
+    let map_file = PathBuf::from(format!("/proc/{}/map_files/{:x}-{:x}", pid, mapping.start, mapping.end));
+    let map_file_build_id = elf::read_build_id(&map_file);
+    let map_file_readable = map_file_build_id.is_ok();
+    let expected = map_file_build_id.ok().flatten();
+
+    let mut candidates = Vec::new();
+    if !shares_root(pid) {
+        candidates.push(PathBuf::from(format!("/proc/{}/root{}", pid, mapping.pathname)));
+    }
+    candidates.push(PathBuf::from(&mapping.pathname));
+
+    for candidate in candidates {
+        let usable = match &expected {
+            Some(build_id) => elf::read_build_id(&candidate).ok().flatten().as_ref() == Some(build_id),
+            // Without a reference, a path that now holds a different file is worse than nothing
+            None => !mapping.deleted && candidate.exists(),
+        };
+        if usable {
+            return Ok(candidate);
+        }
+    }
+
+    if map_file_readable {
+        return Ok(map_file);
+    }
+    Err(anyhow!("Cannot find the binary mapped as {:?} in process {}", mapping.pathname, pid))
+}
+
+/// True when the process sees the same filesystem root as we do, so its paths can be
+/// opened directly and binaries shared with other processes.
+fn shares_root(pid: u32) -> bool {
+    match (fs::metadata(format!("/proc/{}/root/", pid)), fs::metadata("/")) {
+        (Ok(theirs), Ok(ours)) => {
+            use std::os::unix::fs::MetadataExt;
+            theirs.dev() == ours.dev() && theirs.ino() == ours.ino()
+        }
+        // Not allowed to look at the root: assume no container rather than failing
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_modules_deduplicated_by_build_id() {
+        let mut table = ModuleTable::default();
+        let module = |name: &str, build_id: Option<u8>| Module {
+            name: name.to_string(),
+            path: Some(PathBuf::from(name)),
+            build_id: build_id.map(|b| vec![b; 20]),
+            image: None,
+        };
+        let libc = table.insert(module("/usr/lib/libc.so.6", Some(1)));
+        let container_libc = table.insert(module("/proc/42/root/usr/lib/libc.so.6", Some(1)));
+        let other = table.insert(module("/usr/lib/libc.so.6", Some(2)));
+        let no_id = table.insert(module("/opt/app", None));
+        assert_eq!(libc, container_libc);
+        assert_ne!(libc, other);
+        assert_eq!(table.insert(module("/opt/app", None)), no_id);
+        assert_eq!(table.len(), 3);
+    }
+
+    #[test]
+    fn test_normalize_own_code() {
+        let mut normalizer = AddressNormalizer::new(SharedModules::default());
+        let address = test_normalize_own_code as fn() as usize as u64;
+        let first = normalizer.normalize(std::process::id(), address).unwrap();
+        let again = normalizer.normalize(std::process::id(), address).unwrap();
+        assert_eq!(first, again);
+        let module = normalizer.modules.lock().unwrap().get(first.module).unwrap();
+        assert!(module.path.is_some());
+    }
+
+    #[test]
+    fn test_vdso_read_once() {
+        let modules = SharedModules::default();
+        // Stands in for a vDSO read from an earlier process
+        let earlier = Module { name: "[vdso]".to_string(), path: None, build_id: None, image: Some(Arc::from(&b"ELF"[..])) };
+        let vdso = modules.lock().unwrap().insert_vdso(earlier);
+
+        let mut normalizer = AddressNormalizer::new(Arc::clone(&modules));
+        let address = unsafe { libc::getauxval(libc::AT_SYSINFO_EHDR) };
+        let normalized = normalizer.normalize(std::process::id(), address).unwrap();
+        assert_eq!(normalized.module, vdso);
+        assert_eq!(modules.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,159 @@
+use anyhow::{bail, Context, Result};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use crate::callgrind::write_callgrind;
+use crate::demangle::NameStyle;
+use crate::firefox::write_firefox;
+use crate::flamegraph::FlameGraph;
+use crate::heatmap::{check_heatmap, write_heatmap};
+use crate::html::write_html;
+use crate::pprof::PprofBuilder;
+use crate::speedscope::write_speedscope;
+use crate::symbols::SymbolResolver;
+use crate::types::Report;
+
+/// File formats a report can be written in, picked from the file name
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Interactive SVG flame graph (`.svg`)
+    FlameGraph,
+    /// One `frame;frame;frame count` line per stack, as read by inferno,
+    /// flamegraph.pl and speedscope (`.folded`)
+    Folded,
+    /// Gzipped `profile.proto`, as read by `go tool pprof` (`.pb.gz`)
+    Pprof,
+    /// speedscope JSON, one evented profile per thread (`.speedscope.json`)
+    Speedscope,
+    /// Firefox Profiler processed profile, one track per thread (`.firefox.json`)
+    Firefox,
+    /// callgrind text format, for KCachegrind (`callgrind.out*`)
+    Callgrind,
+    /// Self-contained HTML report (`.html`)
+    Html,
+    /// SVG heatmap of the samples by second and offset within the second (`.heatmap.svg`)
+    Heatmap,
+}
+
+impl OutputFormat {
+    pub fn from_path(path: &Path) -> Result<Self> {
+        // valgrind names these callgrind.out.<pid>
+        if path.file_name().is_some_and(|name| name.to_string_lossy().starts_with("callgrind.out")) {
+            return Ok(OutputFormat::Callgrind);
+        }
+        let name = path.to_string_lossy();
+        for (suffix, format) in [
+            (".pb.gz", OutputFormat::Pprof),
+            (".heatmap.svg", OutputFormat::Heatmap),
+            (".speedscope.json", OutputFormat::Speedscope),
+            (".firefox.json", OutputFormat::Firefox),
+        ] {
+            if name.ends_with(suffix) {
+                return Ok(format);
+            }
+        }
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("svg") => Ok(OutputFormat::FlameGraph),
+            Some("folded") => Ok(OutputFormat::Folded),
+            Some("html") => Ok(OutputFormat::Html),
+            _ => bail!("Unknown output format for {} (expected .svg, .folded, .pb.gz, .speedscope.json, .firefox.json, callgrind.out, .html or .heatmap.svg)", path.display()),
+        }
+    }
+}
+
+/// How output files are drawn
+#[derive(Debug, Clone, Default)]
+pub struct OutputOptions {
+    /// Draw flame graphs top-down, with the root at the top
+    pub icicle: bool,
+    /// Name of the sampled event
+    pub event: String,
+    pub name_style: NameStyle,
+}
+
+/// Writes the report to `path`, in the format its extension names.
+///
+/// `resolver` is the one the report's samples were symbolized with; pprof
+/// profiles take addresses, mappings and inline frames from it.
+pub fn write_output(report: &Report, path: &Path, options: &OutputOptions, resolver: &mut SymbolResolver) -> Result<()> {
+    let format = OutputFormat::from_path(path)?;
+    // Don't leave an empty file behind for output that can't be written
+    if format == OutputFormat::Heatmap {
+        check_heatmap(report)?;
+    }
+    let file = File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+    let mut out = BufWriter::new(file);
+    match format {
+        OutputFormat::FlameGraph => {
+            let title = if options.icicle { "Icicle Graph" } else { "Flame Graph" };
+            FlameGraph::new(&report.call_tree).with_title(title).with_icicle(options.icicle).write(&mut out)?;
+        }
+        OutputFormat::Folded => write_folded(report, &mut out)?,
+        OutputFormat::Pprof => {
+            let mut builder = PprofBuilder::new(resolver, &options.event).with_name_style(options.name_style);
+            builder.add_report(report);
+            builder.write(&mut out)?;
+        }
+        OutputFormat::Speedscope => write_speedscope(report, &profile_name(options), &mut out)?,
+        OutputFormat::Firefox => write_firefox(report, &profile_name(options), &mut out)?,
+        OutputFormat::Callgrind => write_callgrind(report, &options.event, &mut out)?,
+        OutputFormat::Html => write_html(report, &profile_name(options), &mut out)?,
+        OutputFormat::Heatmap => write_heatmap(report, &profile_name(options), &mut out)?,
+    }
+    out.flush()?;
+    Ok(())
+}
+
+fn profile_name(options: &OutputOptions) -> String {
+    format!("rrstat ({})", options.event)
+}
+
+/// Writes the folded stacks of the report, one `frame;frame;frame count` line each.
+pub fn write_folded(report: &Report, out: &mut impl Write) -> Result<()> {
+    for (stack, count) in &report.folded_stacks {
+        writeln!(out, "{} {}", stack, count)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aggregator::Aggregator;
+
+    #[test]
+    fn test_format_from_path() {
+        assert_eq!(OutputFormat::from_path(Path::new("out/flamegraph.svg")).unwrap(), OutputFormat::FlameGraph);
+        assert_eq!(OutputFormat::from_path(Path::new("stacks.folded")).unwrap(), OutputFormat::Folded);
+        assert_eq!(OutputFormat::from_path(Path::new("profile.pb.gz")).unwrap(), OutputFormat::Pprof);
+        assert_eq!(OutputFormat::from_path(Path::new("run.speedscope.json")).unwrap(), OutputFormat::Speedscope);
+        assert_eq!(OutputFormat::from_path(Path::new("run.firefox.json")).unwrap(), OutputFormat::Firefox);
+        assert_eq!(OutputFormat::from_path(Path::new("callgrind.out")).unwrap(), OutputFormat::Callgrind);
+        assert_eq!(OutputFormat::from_path(Path::new("/tmp/callgrind.out.1234")).unwrap(), OutputFormat::Callgrind);
+        assert_eq!(OutputFormat::from_path(Path::new("report.html")).unwrap(), OutputFormat::Html);
+        assert_eq!(OutputFormat::from_path(Path::new("run.heatmap.svg")).unwrap(), OutputFormat::Heatmap);
+        assert!(OutputFormat::from_path(Path::new("report")).is_err());
+        assert!(OutputFormat::from_path(Path::new("stacks.gz")).is_err());
+    }
+
+    #[test]
+    fn test_folded_round_trip() {
+        let folded = "main;eval 5\nmain;parse (parser.c:42) 2\nmain;parse (parser.c:42);lex_[i] 3\n";
+        let mut aggregator = Aggregator::new();
+        aggregator.read_folded(folded.as_bytes()).unwrap();
+        let mut written = Vec::new();
+        write_folded(&aggregator.generate_report(), &mut written).unwrap();
+        assert_eq!(String::from_utf8(written).unwrap(), folded);
+    }
+
+    #[test]
+    fn test_heatmap_checked_before_writing() {
+        let mut aggregator = Aggregator::new();
+        aggregator.read_folded("main;eval 5\n".as_bytes()).unwrap();
+        let path = std::env::temp_dir().join(format!("rrstat-{}.heatmap.svg", std::process::id()));
+        let result = write_output(&aggregator.generate_report(), &path, &OutputOptions::default(), aggregator.resolver_mut());
+        assert!(result.is_err());
+        assert!(!path.exists());
+    }
+}
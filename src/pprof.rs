@@ -0,0 +1,422 @@
+use anyhow::Result;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::collections::HashMap;
+use std::io::Write;
+
+use crate::aggregator::FrameKey;
+use crate::demangle::{format_name, NameStyle};
+use crate::maps::Mapping;
+use crate::modules::ModuleAddress;
+use crate::symbols::SymbolResolver;
+use crate::types::{Report, Sample, StackFrame};
+
+/// Appends protobuf fields to a message, in the wire format `profile.proto` is read in.
+#[derive(Default)]
+struct Message {
+    buf: Vec<u8>,
+}
+
+impl Message {
+    fn varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.buf.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        self.buf.push(value as u8);
+    }
+
+    /// A varint field; zero is the default and left out
+    fn uint(&mut self, field: u32, value: u64) {
+        if value != 0 {
+            self.varint((field as u64) << 3);
+            self.varint(value);
+        }
+    }
+
+    fn bool(&mut self, field: u32, value: bool) {
+        self.uint(field, value as u64);
+    }
+
+    fn bytes(&mut self, field: u32, bytes: &[u8]) {
+        self.varint((field as u64) << 3 | 2);
+        self.varint(bytes.len() as u64);
+        self.buf.extend_from_slice(bytes);
+    }
+
+    fn message(&mut self, field: u32, message: Message) {
+        self.bytes(field, &message.buf);
+    }
+
+    /// A packed repeated varint field
+    fn packed(&mut self, field: u32, values: impl IntoIterator<Item = u64>) {
+        let mut packed = Message::default();
+        for value in values {
+            packed.varint(value);
+        }
+        self.bytes(field, &packed.buf);
+    }
+}
+
+/// One `Line` of a location: a function and the line executing in it
+struct Line {
+    function: u64,
+    line: u32,
+}
+
+/// A `profile.proto` profile, built up from samples and encoded by hand.
+///
+/// Locations are instructions of the target (or Python functions), with the
+/// functions inlined at them; mappings come from the target's memory maps.
+pub struct PprofBuilder<'a> {
+    resolver: &'a mut SymbolResolver,
+    event: String,
+    name_style: NameStyle,
+    strings: Vec<String>,
+    string_ids: HashMap<String, u64>,
+    /// Encoded `Mapping` messages and the id of each (pid, start of mapping)
+    mappings: Vec<Message>,
+    mapping_ids: HashMap<(i32, u64), u64>,
+    locations: Vec<Message>,
+    native_locations: HashMap<(i32, u64), u64>,
+    named_locations: HashMap<FrameKey, u64>,
+    functions: Vec<Message>,
+    function_ids: HashMap<(String, Option<String>), u64>,
+    samples: Vec<Message>,
+    duration_ms: u64,
+}
+
+impl<'a> PprofBuilder<'a> {
+    /// `event` names the counted event, the profile's second sample type.
+    pub fn new(resolver: &'a mut SymbolResolver, event: impl Into<String>) -> Self {
+        Self {
+            resolver,
+            event: event.into(),
+            name_style: NameStyle::default(),
+            // String 0 is always the empty string
+            strings: vec![String::new()],
+            string_ids: HashMap::from([(String::new(), 0)]),
+            mappings: Vec::new(),
+            mapping_ids: HashMap::new(),
+            locations: Vec::new(),
+            native_locations: HashMap::new(),
+            named_locations: HashMap::new(),
+            functions: Vec::new(),
+            function_ids: HashMap::new(),
+            samples: Vec::new(),
+            duration_ms: 0,
+        }
+    }
+
+    /// Sets how function names are shown; the mangled name is always kept as the system name.
+    pub fn with_name_style(mut self, style: NameStyle) -> Self {
+        self.name_style = style;
+        self
+    }
+
+    fn string(&mut self, text: &str) -> u64 {
+        if let Some(&id) = self.string_ids.get(text) {
+            return id;
+        }
+        let id = self.strings.len() as u64;
+        self.strings.push(text.to_string());
+        self.string_ids.insert(text.to_string(), id);
+        id
+    }
+
+    /// Adds the samples of the report, or its folded stacks when it was read back from them.
+    pub fn add_report(&mut self, report: &Report) {
+        if report.samples.is_empty() {
+            for (stack, count) in &report.folded_stacks {
+                let frames: Vec<FrameKey> = stack.split(';').map(FrameKey::parse).collect();
+                let locations: Vec<u64> = frames.iter().rev().map(|frame| self.named_location(frame)).collect();
+                self.push_sample(locations, *count as u64, *count as u64, Vec::new());
+            }
+        }
+        for sample in &report.samples {
+            self.add(sample);
+        }
+    }
+
+    pub fn add(&mut self, sample: &Sample) {
+        let pid = sample.pid;
+        let locations = if sample.stack.is_empty() {
+            vec![self.native_location(pid, sample.instruction_pointer, sample.location)]
+        } else {
+            sample
+                .stack
+                .iter()
+                .map(|frame| match frame {
+                    StackFrame::Native { address, location } => self.native_location(pid, *address, *location),
+                    StackFrame::Python { function, file, line } => self.named_location(&FrameKey {
+                        name: function.clone(),
+                        file: Some(file.clone()),
+                        line: *line,
+                        ..Default::default()
+                    }),
+                })
+                .collect()
+        };
+
+        let mut labels = Vec::new();
+        for (key, value) in [("pid", sample.pid), ("tid", sample.tid)] {
+            let mut label = Message::default();
+            label.uint(1, self.string(key));
+            label.uint(3, value as u64);
+            labels.push(label);
+        }
+        if let Some(name) = &sample.thread_name {
+            let mut label = Message::default();
+            label.uint(1, self.string("thread_name"));
+            label.uint(2, self.string(name));
+            labels.push(label);
+        }
+        self.duration_ms = self.duration_ms.max(sample.timestamp);
+        self.push_sample(locations, 1, sample.period, labels);
+    }
+
+    /// `locations` are innermost first.
+    fn push_sample(&mut self, locations: Vec<u64>, samples: u64, events: u64, labels: Vec<Message>) {
+        let mut message = Message::default();
+        message.packed(1, locations);
+        message.packed(2, [samples, events]);
+        for label in labels {
+            message.message(3, label);
+        }
+        self.samples.push(message);
+    }
+
+    fn function(&mut self, raw_name: &str, file: Option<&str>) -> u64 {
+        let key = (raw_name.to_string(), file.map(str::to_string));
+        if let Some(&id) = self.function_ids.get(&key) {
+            return id;
+        }
+        let id = self.functions.len() as u64 + 1;
+        let mut message = Message::default();
+        message.uint(1, id);
+        message.uint(2, self.string(&format_name(raw_name, self.name_style)));
+        message.uint(3, self.string(raw_name));
+        message.uint(4, file.map_or(0, |file| self.string(file)));
+        self.functions.push(message);
+        self.function_ids.insert(key, id);
+        id
+    }
+
+    /// Location of an instruction of the target, with the functions inlined there.
+    ///
+    /// Return addresses are symbolized through `location`, which the unwinder
+    /// points at the call; `address` is what the profile records.
+    fn native_location(&mut self, pid: i32, address: u64, location: Option<ModuleAddress>) -> u64 {
+        if let Some(&id) = self.native_locations.get(&(pid, address)) {
+            return id;
+        }
+        let id = self.locations.len() as u64 + 1;
+        let mapping = self.mapping(pid, address);
+        let mut lines = Vec::new();
+        let info = match location {
+            Some(location) => self.resolver.resolve_location(location),
+            None => self.resolver.resolve(pid as u32, address),
+        };
+        if let Ok(info) = info {
+            let innermost = (info.function, info.file, info.line);
+            let outer = info.inlined_into.into_iter().map(|frame| (frame.function, frame.file, frame.line));
+            // Innermost first; the last line is the function the others were inlined into
+            for (function, file, line) in std::iter::once(innermost).chain(outer) {
+                if let Some(function) = function {
+                    let function = self.function(&function, file.as_deref());
+                    lines.push(Line { function, line: line.unwrap_or(0) });
+                }
+            }
+        }
+
+        let mut message = Message::default();
+        message.uint(1, id);
+        message.uint(2, mapping);
+        message.uint(3, address);
+        for line in lines {
+            let mut encoded = Message::default();
+            encoded.uint(1, line.function);
+            encoded.uint(2, line.line as u64);
+            message.message(4, encoded);
+        }
+        self.locations.push(message);
+        self.native_locations.insert((pid, address), id);
+        id
+    }
+
+    /// Location of a frame known only by name, e.g. a Python function.
+    fn named_location(&mut self, frame: &FrameKey) -> u64 {
+        if let Some(&id) = self.named_locations.get(frame) {
+            return id;
+        }
+        let id = self.locations.len() as u64 + 1;
+        let function = self.function(&frame.name, frame.file.as_deref());
+        let mut line = Message::default();
+        line.uint(1, function);
+        line.uint(2, frame.line.unwrap_or(0) as u64);
+        let mut message = Message::default();
+        message.uint(1, id);
+        message.uint(3, frame.address.unwrap_or(0));
+        message.message(4, line);
+        self.locations.push(message);
+        self.named_locations.insert(frame.clone(), id);
+        id
+    }
+
+    /// Id of the mapping holding `address` in the process, or 0 when there is none.
+    fn mapping(&mut self, pid: i32, address: u64) -> u64 {
+        let Some(mapping) = self
+            .resolver
+            .mappings(pid as u32)
+            .and_then(|mappings| mappings.iter().find(|m| m.start <= address && address < m.end))
+            .cloned()
+        else {
+            return 0;
+        };
+        if let Some(&id) = self.mapping_ids.get(&(pid, mapping.start)) {
+            return id;
+        }
+        let id = self.mappings.len() as u64 + 1;
+        let message = self.encode_mapping(id, pid, &mapping);
+        self.mappings.push(message);
+        self.mapping_ids.insert((pid, mapping.start), id);
+        id
+    }
+
+    fn encode_mapping(&mut self, id: u64, pid: i32, mapping: &Mapping) -> Message {
+        let build_id = self
+            .resolver
+            .locate(pid as u32, mapping.start)
+            .and_then(|location| self.resolver.module(location.module))
+            .and_then(|module| module.build_id.clone())
+            .map(|id| id.iter().map(|b| format!("{:02x}", b)).collect::<String>());
+        let mut message = Message::default();
+        message.uint(1, id);
+        message.uint(2, mapping.start);
+        message.uint(3, mapping.end);
+        message.uint(4, mapping.offset);
+        message.uint(5, self.string(&mapping.pathname));
+        message.uint(6, build_id.map_or(0, |id| self.string(&id)));
+        // Names, files, lines and inline frames are all filled in here, so pprof
+        // doesn't try to symbolize again
+        for field in 7..=10 {
+            message.bool(field, true);
+        }
+        message
+    }
+
+    /// Encodes the `Profile` message.
+    pub fn encode(mut self) -> Vec<u8> {
+        let mut profile = Message::default();
+        for (kind, unit) in [("samples", "count"), (self.event.clone().as_str(), "events")] {
+            let mut value_type = Message::default();
+            value_type.uint(1, self.string(kind));
+            value_type.uint(2, self.string(unit));
+            profile.message(1, value_type);
+        }
+        let mut period_type = Message::default();
+        period_type.uint(1, self.string(&self.event.clone()));
+        period_type.uint(2, self.string("events"));
+        let default_type = self.string(&self.event.clone());
+
+        for sample in std::mem::take(&mut self.samples) {
+            profile.message(2, sample);
+        }
+        for mapping in std::mem::take(&mut self.mappings) {
+            profile.message(3, mapping);
+        }
+        for location in std::mem::take(&mut self.locations) {
+            profile.message(4, location);
+        }
+        for function in std::mem::take(&mut self.functions) {
+            profile.message(5, function);
+        }
+        for string in &self.strings {
+            profile.bytes(6, string.as_bytes());
+        }
+        profile.uint(10, self.duration_ms * 1_000_000);
+        profile.message(11, period_type);
+        profile.uint(14, default_type);
+        profile.buf
+    }
+
+    /// Writes the profile gzipped, as `go tool pprof` reads it.
+    pub fn write(self, out: &mut impl Write) -> Result<()> {
+        let mut encoder = GzEncoder::new(out, Compression::default());
+        encoder.write_all(&self.encode())?;
+        encoder.finish()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Splits a message into (field, value) pairs; length-delimited values are their bytes
+    fn fields(mut buf: &[u8]) -> Vec<(u64, Result<u64, Vec<u8>>)> {
+        fn varint(buf: &mut &[u8]) -> u64 {
+            let mut value = 0;
+            for shift in (0..).step_by(7) {
+                let byte = buf[0];
+                *buf = &buf[1..];
+                value |= ((byte & 0x7f) as u64) << shift;
+                if byte < 0x80 {
+                    break;
+                }
+            }
+            value
+        }
+        let mut fields = Vec::new();
+        while !buf.is_empty() {
+            let key = varint(&mut buf);
+            if key & 7 == 2 {
+                let len = varint(&mut buf) as usize;
+                fields.push((key >> 3, Err(buf[..len].to_vec())));
+                buf = &buf[len..];
+            } else {
+                fields.push((key >> 3, Ok(varint(&mut buf))));
+            }
+        }
+        fields
+    }
+
+    #[test]
+    fn test_encode_profile() {
+        let mut resolver = SymbolResolver::new();
+        let mut builder = PprofBuilder::new(&mut resolver, "cpu-clock");
+        let python = |function: &str, line| StackFrame::Python { function: function.into(), file: "app.py".into(), line };
+        let sample = Sample {
+            pid: 42,
+            tid: 43,
+            thread_name: Some("worker".into()),
+            period: 300,
+            timestamp: 1500,
+            stack: vec![python("inner", Some(3)), python("outer", Some(7))],
+            ..Default::default()
+        };
+        builder.add(&sample);
+        builder.add(&sample);
+        let profile = fields(&builder.encode());
+
+        let strings: Vec<String> = profile
+            .iter()
+            .filter(|(field, _)| *field == 6)
+            .map(|(_, value)| String::from_utf8(value.clone().unwrap_err()).unwrap())
+            .collect();
+        assert_eq!(strings[0], "");
+        for expected in ["samples", "count", "cpu-clock", "events", "inner", "outer", "app.py", "pid", "tid", "worker"] {
+            assert!(strings.iter().any(|s| s == expected), "missing string {:?}", expected);
+        }
+        let count = |wanted| profile.iter().filter(|(field, _)| *field == wanted).count();
+        assert_eq!((count(1), count(2), count(4), count(5)), (2, 2, 2, 2));
+        assert!(profile.contains(&(10, Ok(1_500_000_000))));
+
+        let sample = profile.iter().find(|(field, _)| *field == 2).unwrap().1.clone().unwrap_err();
+        let sample = fields(&sample);
+        // Two locations, leaf first, then the values: one sample of 300 events
+        assert_eq!(sample[0], (1, Err(vec![1, 2])));
+        assert_eq!(sample[1], (2, Err(vec![1, 0xac, 0x02])));
+        assert_eq!(sample.iter().filter(|(field, _)| *field == 3).count(), 3);
+    }
+}
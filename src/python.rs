@@ -0,0 +1,428 @@
+use anyhow::{anyhow, bail, Context, Result};
+use object::{Object, ObjectSymbol};
+use std::fs;
+use std::ops::Range;
+use std::path::Path;
+
+use crate::elf::{LoadSegments, SymbolTable};
+use crate::maps::{self, Mapping, MappingKind};
+use crate::memory;
+use crate::modules;
+use crate::types::StackFrame;
+
+/// Python frames deeper than this are cut
+const MAX_FRAMES: usize = 1024;
+/// Threads of one interpreter looked at before giving up on finding the sampled one
+const MAX_THREADS: usize = 4096;
+/// Longest string read for a function or file name
+const MAX_STRING: usize = 4096;
+
+/// Byte offsets into the interpreter's internal structures, which change with
+/// every minor version. Taken from the CPython headers built with `Py_BUILD_CORE`.
+#[derive(Debug)]
+struct Offsets {
+    interpreters_head: u64,
+    threads_head: u64,
+    tstate_next: u64,
+    tstate_native_thread_id: u64,
+    tstate_cframe: u64,
+    cframe_current_frame: u64,
+    frame_code: u64,
+    frame_previous: u64,
+    frame_prev_instr: u64,
+    frame_owner: u64,
+    /// 3.11 marks the first frame of each `_PyEval_EvalFrameDefault` call
+    frame_is_entry: Option<u64>,
+    /// 3.12 inserts a shim frame with this owner instead
+    owned_by_cstack: Option<u8>,
+    code_filename: u64,
+    code_qualname: u64,
+    code_firstlineno: u64,
+    code_linetable: u64,
+    code_adaptive: u64,
+    ascii_size: u64,
+    compact_size: u64,
+}
+
+const PY311: Offsets = Offsets {
+    interpreters_head: 40,
+    threads_head: 16,
+    tstate_next: 8,
+    tstate_native_thread_id: 160,
+    tstate_cframe: 56,
+    cframe_current_frame: 8,
+    frame_code: 32,
+    frame_previous: 48,
+    frame_prev_instr: 56,
+    frame_owner: 69,
+    frame_is_entry: Some(68),
+    owned_by_cstack: None,
+    code_filename: 112,
+    code_qualname: 128,
+    code_firstlineno: 72,
+    code_linetable: 136,
+    code_adaptive: 184,
+    ascii_size: 48,
+    compact_size: 72,
+};
+
+const PY312: Offsets = Offsets {
+    interpreters_head: 40,
+    threads_head: 72,
+    tstate_next: 8,
+    tstate_native_thread_id: 144,
+    tstate_cframe: 56,
+    cframe_current_frame: 0,
+    frame_code: 0,
+    frame_previous: 8,
+    frame_prev_instr: 56,
+    frame_owner: 70,
+    frame_is_entry: None,
+    owned_by_cstack: Some(3),
+    code_filename: 112,
+    code_qualname: 128,
+    code_firstlineno: 68,
+    code_linetable: 136,
+    code_adaptive: 192,
+    ascii_size: 40,
+    compact_size: 56,
+};
+
+/// Common to every version: `PyVarObject.ob_size` and `PyBytesObject.ob_sval`
+const BYTES_SIZE: u64 = 16;
+const BYTES_DATA: u64 = 32;
+/// `PyASCIIObject.length` and `PyASCIIObject.state`
+const UNICODE_LENGTH: u64 = 16;
+const UNICODE_STATE: u64 = 32;
+
+/// Reads Python stacks out of a running CPython 3.11 or 3.12 interpreter.
+///
+/// The interpreter state is found through the `_PyRuntime` symbol of the
+/// executable or `libpython`, then every thread's frame chain is read with
+/// `process_vm_readv`. Nothing is injected into the target.
+pub struct PythonUnwinder {
+    pid: u32,
+    version: (u8, u8),
+    offsets: &'static Offsets,
+    runtime: u64,
+    /// Runtime addresses of `_PyEval_EvalFrameDefault`, the native frame each
+    /// run of Python frames executes in
+    eval_frame: Range<u64>,
+}
+
+impl PythonUnwinder {
+    /// Returns `None` if the process is not running a supported CPython.
+    pub fn attach(pid: u32) -> Result<Option<Self>> {
+        let mappings = maps::read_mappings(pid)?;
+        let mut seen = Vec::new();
+        for mapping in &mappings {
+            let name = Path::new(&mapping.pathname).file_name().and_then(|n| n.to_str()).unwrap_or("");
+            if mapping.kind != MappingKind::File
+                || !(name.starts_with("python") || name.starts_with("libpython"))
+                || seen.contains(&mapping.pathname)
+            {
+                continue;
+            }
+            seen.push(mapping.pathname.clone());
+            let Ok(path) = modules::locate_binary(pid, mapping) else { continue };
+            if let Some(unwinder) = Self::from_binary(pid, &path, &mappings, &mapping.pathname)? {
+                return Ok(Some(unwinder));
+            }
+        }
+        Ok(None)
+    }
+
+    fn from_binary(pid: u32, path: &Path, mappings: &[Mapping], pathname: &str) -> Result<Option<Self>> {
+        let file = fs::File::open(path).with_context(|| format!("Failed to open binary {:?}", path))?;
+        let data = unsafe { memmap2::Mmap::map(&file)? };
+        let object = object::File::parse(&*data)?;
+        let symbol = |name: &str| {
+            object
+                .symbols()
+                .chain(object.dynamic_symbols())
+                .find(|s| s.name() == Ok(name) && s.address() != 0)
+                .map(|s| s.address())
+        };
+        let (Some(runtime), Some(version)) = (symbol("_PyRuntime"), symbol("Py_Version")) else {
+            return Ok(None);
+        };
+
+        let segments = LoadSegments::from_object(&object);
+        let to_runtime = |vaddr: u64| -> Result<u64> {
+            let offset = segments.vaddr_to_offset(vaddr).ok_or_else(|| anyhow!("0x{:x} is not in a segment", vaddr))?;
+            mappings
+                .iter()
+                .find(|m| m.pathname == pathname && offset >= m.offset && offset < m.offset + m.size())
+                .map(|m| m.start + offset - m.offset)
+                .ok_or_else(|| anyhow!("Offset 0x{:x} of {} is not mapped", offset, pathname))
+        };
+
+        // Py_Version holds PY_VERSION_HEX, e.g. 0x030b07f0 for 3.11.7
+        let hex = memory::read_u64(pid, to_runtime(version)?)?;
+        let version = ((hex >> 24) as u8, (hex >> 16) as u8);
+        let offsets = match version {
+            (3, 11) => &PY311,
+            (3, 12) => &PY312,
+            (major, minor) => bail!("Python {}.{} is not supported", major, minor),
+        };
+
+        let symbols = SymbolTable::from_object(&object);
+        let eval = symbols
+            .iter()
+            .find(|s| s.name == "_PyEval_EvalFrameDefault")
+            .context("_PyEval_EvalFrameDefault not found")?;
+        let eval_start = to_runtime(eval.address)?;
+
+        Ok(Some(Self {
+            pid,
+            version,
+            offsets,
+            runtime: to_runtime(runtime)?,
+            eval_frame: eval_start..eval_start + eval.size.max(1),
+        }))
+    }
+
+    pub fn version(&self) -> (u8, u8) {
+        self.version
+    }
+
+    /// Reads the Python stack of thread `tid`, split into the runs of frames executed
+    /// by each `_PyEval_EvalFrameDefault` call, innermost first.
+    ///
+    /// Empty if the thread is not running Python code.
+    pub fn stack(&self, tid: u32) -> Result<Vec<Vec<StackFrame>>> {
+        let o = self.offsets;
+        let interp = self.read_u64(self.runtime + o.interpreters_head)?;
+        let mut tstate = self.read_u64(interp + o.threads_head)?;
+        let mut remaining = MAX_THREADS;
+        while tstate != 0 && self.read_u64(tstate + o.tstate_native_thread_id)? != tid as u64 {
+            tstate = self.read_u64(tstate + o.tstate_next)?;
+            remaining -= 1;
+            if remaining == 0 {
+                bail!("Too many Python threads");
+            }
+        }
+        if tstate == 0 {
+            return Ok(Vec::new());
+        }
+
+        let cframe = self.read_u64(tstate + o.tstate_cframe)?;
+        let mut frame = self.read_u64(cframe + o.cframe_current_frame)?;
+        let mut segments = vec![Vec::new()];
+        let mut count = 0;
+        while frame != 0 && count < MAX_FRAMES {
+            count += 1;
+            let header = memory::read_process_memory(self.pid, frame, (o.frame_owner + 1) as usize)?;
+            let word = |at: u64| u64::from_ne_bytes(header[at as usize..at as usize + 8].try_into().unwrap());
+            let previous = word(o.frame_previous);
+
+            if o.owned_by_cstack == Some(header[o.frame_owner as usize]) {
+                segments.push(Vec::new());
+                frame = previous;
+                continue;
+            }
+            let segment = segments.last_mut().unwrap();
+            segment.push(self.read_frame(word(o.frame_code), word(o.frame_prev_instr))?);
+            if o.frame_is_entry.is_some_and(|at| header[at as usize] != 0) {
+                segments.push(Vec::new());
+            }
+            frame = previous;
+        }
+        segments.retain(|s| !s.is_empty());
+        Ok(segments)
+    }
+
+    fn read_frame(&self, code: u64, prev_instr: u64) -> Result<StackFrame> {
+        let o = self.offsets;
+        let function = self.read_string(self.read_u64(code + o.code_qualname)?)?;
+        let file = self.read_string(self.read_u64(code + o.code_filename)?)?;
+        let firstlineno = memory::read_process_memory(self.pid, code + o.code_firstlineno, 4)?;
+        let firstlineno = i32::from_ne_bytes(firstlineno.try_into().unwrap());
+        let linetable = self.read_bytes(self.read_u64(code + o.code_linetable)?)?;
+
+        // prev_instr points at the instruction being executed, in 2-byte code units
+        let index = prev_instr.saturating_sub(code + o.code_adaptive) / 2;
+        let line = line_for_instruction(&linetable, firstlineno, index);
+        Ok(StackFrame::Python { function, file, line })
+    }
+
+    /// Replaces each `_PyEval_EvalFrameDefault` frame of a native stack with the
+    /// Python frames it was running.
+    pub fn merge(&self, native: Vec<StackFrame>, python: Vec<Vec<StackFrame>>) -> Vec<StackFrame> {
+        let mut segments = python.into_iter();
+        let mut merged = Vec::with_capacity(native.len());
+        for (i, frame) in native.into_iter().enumerate() {
+            if let StackFrame::Native { address, .. } = frame {
+                let lookup = if i == 0 { address } else { address - 1 };
+                if self.eval_frame.contains(&lookup) {
+                    if let Some(segment) = segments.next() {
+                        merged.extend(segment);
+                        continue;
+                    }
+                }
+            }
+            merged.push(frame);
+        }
+        // Native unwinding stopped early; the remaining Python callers still go at the bottom
+        merged.extend(segments.flatten());
+        merged
+    }
+
+    fn read_u64(&self, address: u64) -> Result<u64> {
+        if address == 0 {
+            bail!("Null pointer in the interpreter state");
+        }
+        memory::read_u64(self.pid, address)
+    }
+
+    fn read_bytes(&self, address: u64) -> Result<Vec<u8>> {
+        let len = self.read_u64(address + BYTES_SIZE)? as usize;
+        if len > 1 << 20 {
+            bail!("Implausible bytes length {}", len);
+        }
+        memory::read_process_memory(self.pid, address + BYTES_DATA, len)
+    }
+
+    /// Reads a `str`, which is stored as Latin-1, UCS-2 or UCS-4 depending on its contents.
+    fn read_string(&self, address: u64) -> Result<String> {
+        let len = (self.read_u64(address + UNICODE_LENGTH)? as usize).min(MAX_STRING);
+        let state = memory::read_process_memory(self.pid, address + UNICODE_STATE, 4)?;
+        let state = u32::from_ne_bytes(state.try_into().unwrap());
+        let kind = ((state >> 2) & 7) as usize;
+        let (compact, ascii) = (state & (1 << 5) != 0, state & (1 << 6) != 0);
+        let data = match (compact, ascii) {
+            (true, true) => address + self.offsets.ascii_size,
+            (true, false) => address + self.offsets.compact_size,
+            _ => bail!("Legacy string objects are not supported"),
+        };
+        let bytes = memory::read_process_memory(self.pid, data, len * kind.max(1))?;
+        Ok(match kind {
+            2 => char::decode_utf16(bytes.chunks_exact(2).map(|c| u16::from_ne_bytes([c[0], c[1]])))
+                .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+                .collect(),
+            4 => bytes
+                .chunks_exact(4)
+                .map(|c| char::from_u32(u32::from_ne_bytes(c.try_into().unwrap())).unwrap_or(char::REPLACEMENT_CHARACTER))
+                .collect(),
+            _ => bytes.iter().map(|&b| b as char).collect(),
+        })
+    }
+}
+
+/// Finds the source line of a code unit in a 3.11+ location table (`co_linetable`).
+///
+/// Each entry starts with a byte that has the high bit set and holds the entry's
+/// form and length in code units; the line delta follows as a varint for the long
+/// forms. See `Objects/locations.md` in CPython.
+fn line_for_instruction(table: &[u8], firstlineno: i32, index: u64) -> Option<u32> {
+    let mut line = firstlineno as i64;
+    let mut address = 0u64;
+    let mut pos = 0;
+    while pos < table.len() {
+        let header = table[pos];
+        let code = (header >> 3) & 15;
+        let length = (header & 7) as u64 + 1;
+        pos += 1;
+
+        let delta = match code {
+            // No location
+            15 => 0,
+            // Long form and form without columns
+            13 | 14 => read_signed_varint(table, &mut pos)?,
+            // One-line forms
+            10..=12 => code as i64 - 10,
+            // Short forms: same line
+            _ => 0,
+        };
+        line += delta;
+        if index < address + length {
+            return (code != 15).then_some(line as u32);
+        }
+        address += length;
+        // Skip the column data up to the next entry
+        while pos < table.len() && table[pos] & 0x80 == 0 {
+            pos += 1;
+        }
+    }
+    None
+}
+
+fn read_varint(table: &[u8], pos: &mut usize) -> Option<u64> {
+    let mut value = 0u64;
+    let mut shift = 0;
+    loop {
+        let byte = *table.get(*pos)?;
+        *pos += 1;
+        value |= ((byte & 63) as u64) << shift;
+        if byte & 64 == 0 {
+            return Some(value);
+        }
+        shift += 6;
+    }
+}
+
+fn read_signed_varint(table: &[u8], pos: &mut usize) -> Option<i64> {
+    let value = read_varint(table, pos)?;
+    Some(if value & 1 != 0 { -((value >> 1) as i64) } else { (value >> 1) as i64 })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_line_table() {
+        // co_linetable of this function compiled by CPython 3.12:
+        //   1 def f(x):
+        //   2     y = x + 1
+        //   3     if y > 2:
+        //   4         return y * 2
+        //   5
+        //   6     return [i for i in range(y)]
+        let table = [
+            128, 0, 216, 8, 9, 136, 65, 137, 5, 128, 65, 216, 7, 8, 136, 49, 130, 117, 216, 15, 16, 144, 49, 137, 117,
+            136, 12, 228, 23, 28, 152, 81, 147, 120, 214, 11, 32, 144, 33, 138, 65, 210, 11, 32, 208, 4, 32, 249, 210,
+            11, 32,
+        ];
+        let lines: Vec<_> = [0, 1, 6, 11, 16, 40, 41, 42, 43, 45]
+            .iter()
+            .map(|&i| line_for_instruction(&table, 1, i))
+            .collect();
+        assert_eq!(
+            lines,
+            vec![Some(1), Some(2), Some(3), Some(4), Some(6), Some(6), None, None, Some(6), Some(6)]
+        );
+        assert_eq!(line_for_instruction(&table, 1, 46), None);
+    }
+
+    #[test]
+    fn test_merge_replaces_eval_frames() {
+        let unwinder = PythonUnwinder {
+            pid: 0,
+            version: (3, 12),
+            offsets: &PY312,
+            runtime: 0,
+            eval_frame: 0x5000..0x6000,
+        };
+        let native = |address| StackFrame::Native { address, location: None };
+        let python = |name: &str| StackFrame::Python { function: name.to_string(), file: "app.py".to_string(), line: Some(1) };
+
+        // nanosleep <- eval(sleepy <- handler) <- PyObject_Call <- eval(main) <- Py_RunMain
+        let merged = unwinder.merge(
+            vec![native(0x100), native(0x5010), native(0x7000), native(0x5200), native(0x9000)],
+            vec![vec![python("sleepy"), python("handler")], vec![python("main")], vec![python("lost")]],
+        );
+        assert_eq!(
+            merged,
+            vec![
+                native(0x100),
+                python("sleepy"),
+                python("handler"),
+                native(0x7000),
+                python("main"),
+                native(0x9000),
+                python("lost"),
+            ]
+        );
+    }
+}
@@ -1,25 +1,393 @@
-use crate::types::Report;
+use anyhow::Result;
+use clap::ValueEnum;
+use serde_json::json;
+use std::io::Write;
+
+use crate::annotate::Annotation;
+use crate::calltree::{CallTree, NodeId};
+use crate::maps::MemorySummary;
+use crate::types::{Report, RunMetadata, TimeSeries};
+
+/// How the report is printed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum ReportFormat {
+    /// Tables for reading in a terminal
+    #[default]
+    Text,
+    /// One document with run metadata, all statistics, threads and folded stacks
+    Json,
+    /// One row per report entry
+    Csv,
+}
+
+/// Writes the report as JSON: run metadata, every entry of the summary, the
+/// per-thread breakdown and the folded stacks.
+pub fn write_json(report: &Report, out: &mut impl Write) -> Result<()> {
+    let folded_stacks: Vec<_> =
+        report.folded_stacks.iter().map(|(stack, count)| json!({ "stack": stack, "count": count })).collect();
+    let document = json!({
+        "metadata": report.metadata,
+        "samples": report.sample_count,
+        "events": report.total_events,
+        "functions": report.stats,
+        "threads": report.thread_stats(),
+        "time_series": report.time_series(TIME_BUCKETS, TIME_SERIES_FUNCTIONS),
+        "folded_stacks": folded_stacks,
+        "memory": report.memory,
+    });
+    serde_json::to_writer_pretty(&mut *out, &document)?;
+    writeln!(out)?;
+    Ok(())
+}
+
+/// Writes the entries of the summary as CSV, with a header row.
+pub fn write_csv(report: &Report, out: &mut impl Write) -> Result<()> {
+    writeln!(out, "function,file,line,address,samples,events,percentage,total_events,total_percentage")?;
+    for stat in &report.stats {
+        let total_percentage = stat.total_count as f64 / report.total_events.max(1) as f64 * 100.0;
+        writeln!(
+            out,
+            "{},{},{},{},{},{},{:.4},{},{:.4}",
+            csv_field(&stat.name),
+            csv_field(stat.file.as_deref().unwrap_or_default()),
+            stat.line.map(|line| line.to_string()).unwrap_or_default(),
+            stat.address.map(|address| format!("0x{:x}", address)).unwrap_or_default(),
+            stat.samples,
+            stat.count,
+            stat.percentage,
+            stat.total_count,
+            total_percentage
+        )?;
+    }
+    Ok(())
+}
+
+/// Quotes a field that holds a separator, quote or line break (C++ names often do).
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
 
 pub fn print_summary(report: &Report) {
-    println!("\n{:=^60}", " PROFILER SUMMARY ");
-    println!("Total Samples: {}", report.total_samples);
+    // Source and address columns only when aggregating by line or address
+    let show_source = report.stats.iter().any(|s| s.file.is_some());
+    let show_address = report.stats.iter().any(|s| s.address.is_some());
+    let width = 82 + if show_source { 33 } else { 0 } + if show_address { 15 } else { 0 };
+
+    println!("\n{:=^width$}", " PROFILER SUMMARY ");
+    if let Some(metadata) = &report.metadata {
+        print_metadata(metadata);
+    }
+    println!("Samples: {}, Events: {}", report.sample_count, report.total_events);
+    println!("{:-^width$}", "");
+
+    let mut header = format!("{:<40} | ", "Function / Context");
+    if show_source {
+        header.push_str(&format!("{:<30} | ", "Source"));
+    }
+    if show_address {
+        header.push_str(&format!("{:<12} | ", "Address"));
+    }
+    println!("{}{:>8} | {:>12} | {:>8} | {:>8}", header, "Samples", "Events", "%", "Total %");
+    println!("{:-^width$}", "");
+
+    for stat in &report.stats {
+        let mut row = format!("{:<40} | ", shorten_name(&stat.name, 38));
+        if show_source {
+            row.push_str(&format!("{:<30} | ", shorten_path(&stat.source().unwrap_or_default(), 30)));
+        }
+        if show_address {
+            row.push_str(&format!("{:<12} | ", stat.address.map(|a| format!("0x{:x}", a)).unwrap_or_default()));
+        }
+        let total_percentage = stat.total_count as f64 / report.total_events.max(1) as f64 * 100.0;
+        println!(
+            "{}{:>8} | {:>12} | {:>7.2}% | {:>7.2}%",
+            row, stat.samples, stat.count, stat.percentage, total_percentage
+        );
+    }
+    print_threads(report);
+    print_time_series(&report.time_series(TIME_BUCKETS, TIME_SERIES_FUNCTIONS));
+    if let Some(memory) = &report.memory {
+        print_memory(memory);
+    }
+    println!("{:=^width$}\n", "");
+}
+
+fn print_metadata(metadata: &RunMetadata) {
+    println!(
+        "Target: {} (pid {}), Event: {}, Duration: {} ms, Rate: {:.1} samples/s",
+        metadata.command.as_deref().unwrap_or("?"),
+        metadata.pid,
+        metadata.event,
+        metadata.duration_ms,
+        metadata.sample_rate
+    );
+}
+
+/// Events per thread, when more than one thread was sampled
+fn print_threads(report: &Report) {
+    let threads = report.thread_stats();
+    if threads.len() < 2 {
+        return;
+    }
+    println!("{:-^60}", " THREADS ");
+    println!("{:<30} | {:>8} | {:>12} | {:>8}", "Thread", "Samples", "Events", "%");
+    for thread in threads {
+        let name = format!("{} {}", thread.tid, thread.name.as_deref().unwrap_or(""));
+        println!("{:<30} | {:>8} | {:>12} | {:>7.2}%", name, thread.samples, thread.events, thread.percentage);
+    }
+}
+
+/// Slices of the run in the time series, i.e. the width of its sparklines
+const TIME_BUCKETS: usize = 40;
+/// Functions followed over the run
+const TIME_SERIES_FUNCTIONS: usize = 5;
+
+/// Draws each value as one of eight bar heights, scaled to the largest.
+pub fn sparkline(values: &[f64]) -> String {
+    const BARS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+    let max = values.iter().copied().fold(0.0, f64::max);
+    values
+        .iter()
+        .map(|&value| if max > 0.0 { BARS[((value / max * 7.0).round() as usize).min(7)] } else { BARS[0] })
+        .collect()
+}
+
+/// Events over the run and the share of the top functions, as sparklines
+fn print_time_series(series: &TimeSeries) {
+    if series.buckets.len() < 2 {
+        return;
+    }
+    println!("{:-^80}", format!(" TIMELINE ({} x {} ms) ", series.buckets.len(), series.bucket_ms));
+    println!("{:<28} | {:<40} | {:>7}", "Function", "Share over time", "Peak %");
+    let events: Vec<f64> = series.buckets.iter().map(|b| b.events as f64).collect();
+    println!("{:<28} | {:<40} |", "(events)", sparkline(&events));
+    for (i, function) in series.functions.iter().enumerate() {
+        let shares: Vec<f64> = series.buckets.iter().map(|b| b.shares[i]).collect();
+        let name = shorten_name(function, 28);
+        let peak = shares.iter().copied().fold(0.0, f64::max);
+        println!("{:<28} | {:<40} | {:>6.2}%", name, sparkline(&shares), peak);
+    }
+}
+
+fn print_memory(memory: &MemorySummary) {
+    println!("{:-^60}", " MEMORY ");
+    println!("Total RSS: {} kB, PSS: {} kB", memory.total_rss_kb, memory.total_pss_kb);
     println!("{:-^60}", "");
-    
-    println!("{:<40} | {:>8} | {:>8}", "Function / Context", "Samples", "%");
+
+    println!("{:<38} | {:>8} | {:>8}", "Mapping", "RSS kB", "PSS kB");
     println!("{:-^60}", "");
 
-    for stat in &report.stats {
-        let display_name = if stat.name.len() > 38 {
-            format!("{}..", &stat.name[..36])
+    for usage in memory.usage.iter().filter(|u| u.rss_kb > 0) {
+        println!("{:<38} | {:>8} | {:>8}", shorten_path(&usage.name, 38), usage.rss_kb, usage.pss_kb);
+    }
+}
+
+/// Prints the callers and callees of `function`, then everything it called
+/// (top-down) and the chains of calls leading to it (bottom-up).
+pub fn print_focus(report: &Report, function: &str) {
+    let view = report.callers_callees(function);
+    let share = |count: usize, of: usize| count as f64 / of.max(1) as f64 * 100.0;
+
+    println!("\n{:=^80}", format!(" {} ", function));
+    println!(
+        "Self: {} ({:.2}%), Total: {} ({:.2}%)",
+        view.self_count,
+        share(view.self_count, report.total_events),
+        view.total_count,
+        share(view.total_count, report.total_events)
+    );
+    println!("{:-^80}", " CALLERS ");
+    for (caller, count) in &view.callers {
+        println!("{:>8.2}%  {}", share(*count, view.total_count), caller.label());
+    }
+    println!("{:-^80}", " CALLEES ");
+    for (callee, count) in &view.callees {
+        println!("{:>8.2}%  {}", share(*count, view.total_count), callee.label());
+    }
+    println!("{:-^80}", " TOP-DOWN ");
+    print_tree(&report.top_down(function));
+    println!("{:-^80}", " BOTTOM-UP ");
+    print_tree(&report.bottom_up(function));
+    println!("{:=^80}\n", "");
+}
+
+/// Nodes below this share of the tree are left out
+const MIN_TREE_PERCENTAGE: f64 = 0.5;
+
+fn print_tree(tree: &CallTree) {
+    fn print_node(tree: &CallTree, id: NodeId, depth: usize, total: usize) {
+        let node = tree.node(id);
+        let percentage = node.total_count as f64 / total.max(1) as f64 * 100.0;
+        if percentage < MIN_TREE_PERCENTAGE {
+            return;
+        }
+        println!("{:>8.2}%  {:indent$}{}", percentage, "", node.frame.label(), indent = depth * 2);
+        for child in tree.children(id) {
+            print_node(tree, child, depth + 1, total);
+        }
+    }
+    let total = tree.node(CallTree::ROOT).total_count;
+    for child in tree.children(CallTree::ROOT) {
+        print_node(tree, child, 0, total);
+    }
+}
+
+/// Prints the source of an annotated function with the share of its samples on
+/// each line, followed by its disassembly when `disassemble` is set.
+pub fn print_annotation(annotation: &Annotation, disassemble: bool) {
+    let percentage = |count: usize| {
+        if count == 0 {
+            String::new()
         } else {
-            stat.name.clone()
+            format!("{:.2}%", count as f64 / annotation.count as f64 * 100.0)
+        }
+    };
+
+    println!("\n{:=^80}", format!(" {} ", annotation.function));
+    println!(
+        "Samples: {} of {} ({:.2}%)",
+        annotation.count,
+        annotation.total,
+        annotation.count as f64 / annotation.total.max(1) as f64 * 100.0
+    );
+    if let Some(file) = &annotation.file {
+        println!("Source: {}", file);
+    }
+    println!("{:-^80}", "");
+
+    if annotation.lines.is_empty() {
+        println!("No line information");
+    }
+    for line in &annotation.lines {
+        let text = line.text.as_deref().unwrap_or("<source not available>");
+        println!("{:>8} | {:>5} | {}", percentage(line.count), line.line, text);
+    }
+
+    if disassemble {
+        println!("{:-^80}", " DISASSEMBLY ");
+        for instruction in &annotation.instructions {
+            let line = instruction.line.map(|l| l.to_string()).unwrap_or_default();
+            println!(
+                "{:>8} | {:>12x} | {:>5} | {}",
+                percentage(instruction.count),
+                instruction.address,
+                line,
+                instruction.text
+            );
+        }
+    }
+    println!("{:=^80}\n", "");
+}
+
+/// Cuts a path to `width` characters, keeping the end where the file name is.
+/// Cuts the name to `width` characters, marking the cut with `..` at the end.
+fn shorten_name(name: &str, width: usize) -> String {
+    if name.chars().count() > width {
+        format!("{}..", name.chars().take(width - 2).collect::<String>())
+    } else {
+        name.to_string()
+    }
+}
+
+fn shorten_path(path: &str, width: usize) -> String {
+    match path.char_indices().rev().nth(width - 3) {
+        Some((idx, _)) if path.chars().count() > width => format!("..{}", &path[idx..]),
+        _ => path.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aggregator::{Aggregator, FrameKey};
+    use crate::types::TimedSample;
+
+    fn folded_report() -> Report {
+        let mut aggregator = Aggregator::new();
+        aggregator.read_folded("main;eval 5\nmain;parse<int, char> 3\n".as_bytes()).unwrap();
+        let mut report = aggregator.generate_report();
+        report.metadata = Some(RunMetadata::read(std::process::id() as i32, "cpu-clock"));
+        report
+    }
+
+    #[test]
+    fn test_json_report() {
+        let mut out = Vec::new();
+        write_json(&folded_report(), &mut out).unwrap();
+        let document: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(document["events"], 8);
+        assert_eq!(document["metadata"]["event"], "cpu-clock");
+        assert!(document["metadata"]["command"].as_str().is_some());
+        assert!(document["metadata"]["kernel"].as_str().is_some());
+        assert_eq!(document["functions"][0]["name"], "eval");
+        assert_eq!(document["functions"][0]["total_count"], 5);
+        assert_eq!(document["folded_stacks"][1], json!({ "stack": "main;parse<int, char>", "count": 3 }));
+        assert_eq!(document["threads"], json!([]));
+    }
+
+    #[test]
+    fn test_non_ascii_names_are_cut_by_chars() {
+        // Multi-byte characters straddle the cut, as in CPython qualnames
+        let name = format!("x{}", "Ñ".repeat(50));
+        assert_eq!(shorten_name(&name, 38), format!("x{}..", "Ñ".repeat(35)));
+        assert_eq!(shorten_name("main", 38), "main");
+
+        let mut aggregator = Aggregator::new();
+        aggregator.read_folded(format!("main;{} 3\n", name).as_bytes()).unwrap();
+        print_summary(&aggregator.generate_report());
+    }
+
+    #[test]
+    fn test_time_series() {
+        let mut report = folded_report();
+        let sample = |timestamp, names: &str, weight| TimedSample {
+            timestamp,
+            pid: 1,
+            tid: 1,
+            thread_name: None,
+            weight,
+            stack: names.split(';').map(FrameKey::function).collect(),
         };
-        
-        println!("{:<40} | {:>8} | {:>8.2}%", 
-            display_name, 
-            stat.count, 
-            stat.percentage
-        );
+        report.timeline = vec![
+            sample(1000, "main;parse", 3),
+            sample(1040, "main;eval", 1),
+            sample(1100, "main;eval", 2),
+            sample(1399, "main;parse;lex", 1),
+        ];
+        let series = report.time_series(4, 2);
+        assert_eq!(series.bucket_ms, 100);
+        assert_eq!(series.functions, ["eval", "parse"]);
+        let starts: Vec<u64> = series.buckets.iter().map(|b| b.start_ms).collect();
+        assert_eq!(starts, [1000, 1100, 1200, 1300]);
+        assert_eq!((series.buckets[0].samples, series.buckets[0].events), (2, 4));
+        assert_eq!(series.buckets[0].shares, [25.0, 75.0]);
+        assert_eq!(series.buckets[1].shares, [100.0, 0.0]);
+        assert_eq!(series.buckets[2].events, 0);
+        assert_eq!(series.buckets[3].shares, [0.0, 0.0]);
+
+        // Folded stacks have no times
+        assert!(folded_report().time_series(4, 2).buckets.is_empty());
+    }
+
+    #[test]
+    fn test_sparkline() {
+        assert_eq!(sparkline(&[0.0, 1.0, 2.0, 4.0]), "▁▃▅█");
+        assert_eq!(sparkline(&[0.0, 0.0]), "▁▁");
+    }
+
+    #[test]
+    fn test_csv_report() {
+        let mut out = Vec::new();
+        write_csv(&folded_report(), &mut out).unwrap();
+        let csv = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines[0], "function,file,line,address,samples,events,percentage,total_events,total_percentage");
+        assert_eq!(lines[1], "eval,,,,5,5,62.5000,5,62.5000");
+        assert_eq!(lines[2], "\"parse<int, char>\",,,,3,3,37.5000,3,37.5000");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
     }
-    println!("{:=^60}\n", "");
 }
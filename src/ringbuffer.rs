@@ -31,6 +31,6 @@ impl RingBuffer {
     
     pub fn drain(&self) -> Vec<Sample> {
         let mut data = self.data.lock().unwrap();
-        return data.drain(..).collect();
+        data.drain(..).collect()
     }
 }
@@ -0,0 +1,175 @@
+use anyhow::Result;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::Write;
+
+use crate::aggregator::FrameKey;
+use crate::types::{Report, ThreadTimeline};
+
+/// Frames shared by all profiles of a file, indexed once each
+#[derive(Default)]
+struct Frames {
+    frames: Vec<Value>,
+    ids: HashMap<FrameKey, usize>,
+}
+
+impl Frames {
+    fn id(&mut self, frame: &FrameKey) -> usize {
+        if let Some(&id) = self.ids.get(frame) {
+            return id;
+        }
+        let mut value = json!({ "name": frame.label() });
+        if let Some(file) = &frame.file {
+            value["file"] = json!(file);
+        }
+        if let Some(line) = frame.line {
+            value["line"] = json!(line);
+        }
+        self.frames.push(value);
+        self.ids.insert(frame.clone(), self.frames.len() - 1);
+        self.frames.len() - 1
+    }
+}
+
+/// Name of a thread's profile, e.g. `worker (tid 1235)`
+pub(crate) fn thread_title(thread: &ThreadTimeline) -> String {
+    match thread.name {
+        Some(name) => format!("{} (tid {})", name, thread.tid),
+        None => format!("tid {}", thread.tid),
+    }
+}
+
+/// An evented profile of one thread, in milliseconds: each sample's stack is
+/// open from the time it was taken until the thread's next sample.
+fn evented_profile(thread: &ThreadTimeline, frames: &mut Frames) -> Value {
+    let mut events = Vec::new();
+    let mut open: Vec<usize> = Vec::new();
+    let start = thread.samples.first().map_or(0, |s| s.timestamp);
+    let mut end = start;
+    for (i, sample) in thread.samples.iter().enumerate() {
+        let at = sample.timestamp;
+        let stack: Vec<usize> = sample.stack.iter().map(|frame| frames.id(frame)).collect();
+        let common = open.iter().zip(&stack).take_while(|(a, b)| a == b).count();
+        while open.len() > common {
+            events.push(json!({ "type": "C", "frame": open.pop().unwrap(), "at": at }));
+        }
+        for &frame in &stack[common..] {
+            events.push(json!({ "type": "O", "frame": frame, "at": at }));
+            open.push(frame);
+        }
+        // The last sample lasts as long as the one before it
+        end = match thread.samples.get(i + 1) {
+            Some(next) => next.timestamp,
+            None if i > 0 => at + (at - thread.samples[i - 1].timestamp).max(1),
+            None => at + 1,
+        };
+    }
+    while let Some(frame) = open.pop() {
+        events.push(json!({ "type": "C", "frame": frame, "at": end }));
+    }
+    json!({
+        "type": "evented",
+        "name": thread_title(thread),
+        "unit": "milliseconds",
+        "startValue": start,
+        "endValue": end,
+        "events": events,
+    })
+}
+
+/// A sampled profile weighted by events, for reports without a timeline
+fn sampled_profile(report: &Report, frames: &mut Frames) -> Value {
+    let mut samples = Vec::new();
+    let mut weights = Vec::new();
+    for (stack, count) in &report.folded_stacks {
+        samples.push(stack.split(';').map(|frame| frames.id(&FrameKey::parse(frame))).collect::<Vec<_>>());
+        weights.push(*count);
+    }
+    json!({
+        "type": "sampled",
+        "name": "all threads",
+        "unit": "none",
+        "startValue": 0,
+        "endValue": report.total_events,
+        "samples": samples,
+        "weights": weights,
+    })
+}
+
+/// Writes the report in speedscope's file format: one evented profile per
+/// thread, on the time axis of the run.
+pub fn write_speedscope(report: &Report, name: &str, out: &mut impl Write) -> Result<()> {
+    let mut frames = Frames::default();
+    let profiles: Vec<Value> = if report.timeline.is_empty() {
+        vec![sampled_profile(report, &mut frames)]
+    } else {
+        report.threads().iter().map(|thread| evented_profile(thread, &mut frames)).collect()
+    };
+    let file = json!({
+        "$schema": "https://www.speedscope.app/file-format-schema.json",
+        "shared": { "frames": frames.frames },
+        "profiles": profiles,
+        "name": name,
+        "activeProfileIndex": 0,
+        "exporter": concat!("rrstat ", env!("CARGO_PKG_VERSION")),
+    });
+    serde_json::to_writer(&mut *out, &file)?;
+    writeln!(out)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aggregator::Aggregator;
+    use crate::types::TimedSample;
+
+    #[test]
+    fn test_evented_profile() {
+        let stack = |names: &str| names.split(';').map(FrameKey::function).collect();
+        let sample = |timestamp, names| TimedSample {
+            timestamp,
+            pid: 1,
+            tid: 2,
+            thread_name: Some("worker".into()),
+            weight: 1,
+            stack: stack(names),
+        };
+        let samples = [sample(100, "main;parse"), sample(200, "main;parse;lex"), sample(300, "main;eval")];
+        let thread = ThreadTimeline { pid: 1, tid: 2, name: Some("worker"), samples: samples.iter().collect() };
+        let mut frames = Frames::default();
+        let profile = evented_profile(&thread, &mut frames);
+
+        assert_eq!(profile["name"], "worker (tid 2)");
+        assert_eq!((profile["startValue"].as_u64(), profile["endValue"].as_u64()), (Some(100), Some(400)));
+        let events: Vec<(String, &str, u64)> = profile["events"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|e| {
+                let name = frames.frames[e["frame"].as_u64().unwrap() as usize]["name"].as_str().unwrap().to_string();
+                (name, e["type"].as_str().unwrap(), e["at"].as_u64().unwrap())
+            })
+            .collect();
+        let expected = [
+            ("main", "O", 100), ("parse", "O", 100), ("lex", "O", 200), ("lex", "C", 300),
+            ("parse", "C", 300), ("eval", "O", 300), ("eval", "C", 400), ("main", "C", 400),
+        ];
+        let expected: Vec<(String, &str, u64)> = expected.iter().map(|(n, t, at)| (n.to_string(), *t, *at)).collect();
+        assert_eq!(events, expected);
+    }
+
+    #[test]
+    fn test_sampled_from_folded() {
+        let mut aggregator = Aggregator::new();
+        aggregator.read_folded("main;eval 5\nmain;parse (parser.c:42) 2\n".as_bytes()).unwrap();
+        let mut out = Vec::new();
+        write_speedscope(&aggregator.generate_report(), "test", &mut out).unwrap();
+        let file: Value = serde_json::from_slice(&out).unwrap();
+        let profile = &file["profiles"][0];
+        assert_eq!(profile["type"], "sampled");
+        assert_eq!(profile["samples"], json!([[0, 1], [0, 2]]));
+        assert_eq!(profile["weights"], json!([5, 2]));
+        assert_eq!(file["shared"]["frames"][2], json!({ "name": "parse (parser.c:42)", "file": "parser.c", "line": 42 }));
+    }
+}
@@ -1,10 +1,21 @@
-use crate::maps;
+use crate::elf::{LoadSegments, SymbolTable};
+use crate::gopclntab::GoFunctionTable;
+use crate::jit::JitSymbols;
+use crate::maps::Mapping;
+use crate::modules::{AddressNormalizer, Module, ModuleAddress, ModuleId, ModuleTable, SharedModules};
+use crate::symcache::{IndexEntry, InlineFrame, SymbolCache, SymbolIndex};
 use addr2line::Context;
 use anyhow::{anyhow, Context as _, Result};
 use gimli::{EndianReader, RunTimeEndian};
+use lru::LruCache;
+use std::collections::hash_map::Entry;
 use std::collections::HashMap;
 use std::fs;
+use std::num::NonZeroUsize;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
 use std::rc::Rc;
+use std::sync::Arc;
 
 // Type alias for the complex Context type from addr2line
 type Addr2LineContext = Context<EndianReader<RunTimeEndian, Rc<[u8]>>>;
@@ -14,116 +25,328 @@ pub struct SymbolInfo {
     pub function: Option<String>,
     pub file: Option<String>,
     pub line: Option<u32>,
+    /// Functions the code was inlined into, innermost first
+    pub inlined_into: Vec<InlineFrame>,
 }
 
+impl SymbolInfo {
+    fn from_frames(frames: &[InlineFrame]) -> Self {
+        let innermost = frames.first().cloned().unwrap_or(InlineFrame { function: None, file: None, line: None });
+        Self {
+            function: innermost.function,
+            file: innermost.file,
+            line: innermost.line,
+            inlined_into: frames.iter().skip(1).cloned().collect(),
+        }
+    }
+
+    fn fallback(function: String, pathname: &str) -> Self {
+        Self {
+            function: Some(function),
+            file: Some(pathname.to_string()),
+            line: None,
+            inlined_into: Vec::new(),
+        }
+    }
+}
+
+/// Machine code of one function, as stored in its binary
+#[derive(Debug, Clone)]
+pub struct FunctionCode {
+    /// Link-time address of the first instruction
+    pub address: u64,
+    /// File offset of the first instruction
+    pub offset: u64,
+    pub bytes: Vec<u8>,
+}
+
+/// Everything needed to symbolize addresses inside one binary
+struct LoadedModule {
+    context: Addr2LineContext,
+    symbols: SymbolTable,
+    segments: LoadSegments,
+    /// Go's own tables, preferred over DWARF for Go binaries
+    go: Option<GoFunctionTable>,
+}
+
+impl LoadedModule {
+    /// Resolves a file offset to its inline chain and the range of offsets sharing that chain.
+    fn find_frames(&self, offset: u64) -> Result<Option<(Vec<InlineFrame>, u64, u64)>> {
+        let Some(addr) = self.segments.offset_to_vaddr(offset) else {
+            return Ok(None);
+        };
+        let to_offsets = |start: u64, end: u64| (offset - (addr - start), offset + (end - addr));
+
+        if let Some((frames, start, end)) = self.go.as_ref().and_then(|go| go.find_frames(addr)) {
+            let (start, end) = to_offsets(start, end);
+            return Ok(Some((frames, start, end)));
+        }
+
+        // addr2line 0.21.0 find_frames returns LookupResult
+        let mut iter = match self.context.find_frames(addr) {
+            addr2line::LookupResult::Output(result) => result?,
+            _ => return Err(anyhow!("Deferred loading not supported")),
+        };
+
+        let symbol = self.symbols.lookup(addr);
+        let mut frames = Vec::new();
+        while let Some(frame) = iter.next()? {
+            // Names stay mangled here; the report decides how to show them
+            let function = frame.function.as_ref().and_then(|f| f.raw_name().ok().map(|n| n.to_string()));
+            let (file, line) = match frame.location {
+                Some(loc) => (loc.file.map(|f| f.to_string()), loc.line),
+                None => (None, None),
+            };
+            frames.push(InlineFrame { function, file, line });
+        }
+
+        if frames.is_empty() {
+            return Ok(symbol.map(|s| {
+                let frame = InlineFrame { function: Some(s.name.clone()), file: None, line: None };
+                // Zero-sized symbols are only known to reach as far as the address itself
+                let end = if s.size == 0 { addr + 1 } else { s.address + s.size };
+                let (start, end) = to_offsets(s.address, end);
+                (vec![frame], start, end)
+            }));
+        }
+        if frames[0].function.is_none() {
+            frames[0].function = symbol.map(|s| s.name.clone());
+        }
+
+        // The line table row containing the address shares file, line and inlining
+        let (start, end) = self
+            .context
+            .find_location_range(addr, addr + 1)?
+            .find(|(start, len, _)| addr >= *start && addr < start + len)
+            .map_or((addr, addr + 1), |(start, len, _)| (start, start + len));
+        let (start, end) = to_offsets(start, end);
+        Ok(Some((frames, start, end)))
+    }
+
+    /// Link-time address range of the function containing `addr`.
+    fn function_bounds(&self, addr: u64) -> Option<(u64, u64)> {
+        if let Some(bounds) = self.go.as_ref().and_then(|go| go.function_bounds(addr)) {
+            return Some(bounds);
+        }
+        self.symbols.lookup(addr).filter(|s| s.size > 0).map(|s| (s.address, s.address + s.size))
+    }
+}
+
+/// Upper bound on resolved addresses kept in memory
+const CACHE_CAPACITY: usize = 50_000;
+
 pub struct SymbolResolver {
-    contexts: HashMap<String, Addr2LineContext>,
-    cache: HashMap<(u32, u64), SymbolInfo>,
+    modules: SharedModules,
+    normalizer: AddressNormalizer,
+    loaded: HashMap<ModuleId, LoadedModule>,
+    disk_cache: Option<SymbolCache>,
+    indexes: HashMap<Vec<u8>, SymbolIndex>,
+    cache: LruCache<ModuleAddress, SymbolInfo>,
+    jit: HashMap<u32, JitSymbols>,
+}
+
+impl Default for SymbolResolver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for SymbolResolver {
+    fn drop(&mut self) {
+        let _ = self.flush_disk_cache();
+    }
 }
 
 impl SymbolResolver {
     pub fn new() -> Self {
+        let modules = ModuleTable::global();
         Self {
-            contexts: HashMap::new(),
-            cache: HashMap::new(),
+            normalizer: AddressNormalizer::new(Arc::clone(&modules)),
+            modules,
+            loaded: HashMap::new(),
+            disk_cache: None,
+            indexes: HashMap::new(),
+            cache: LruCache::new(NonZeroUsize::new(CACHE_CAPACITY).unwrap()),
+            jit: HashMap::new(),
         }
     }
 
-    fn load_binary(&self, path: &str) -> Result<Addr2LineContext> {
+    /// Reuses symbolization results of earlier runs, keyed by build-id.
+    pub fn with_disk_cache(mut self, cache: SymbolCache) -> Self {
+        self.disk_cache = Some(cache);
+        self
+    }
+
+    /// Persists what was resolved in this run. Also happens when the resolver is dropped.
+    pub fn flush_disk_cache(&mut self) -> Result<()> {
+        if let Some(cache) = &self.disk_cache {
+            for (build_id, index) in self.indexes.iter_mut() {
+                cache.store(build_id, index)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn load_binary(path: &Path) -> Result<LoadedModule> {
         let file = fs::File::open(path)
             .with_context(|| format!("Failed to open binary {:?}", path))?;
         
         let data = unsafe { memmap2::Mmap::map(&file)? };
-        let object = object::File::parse(&*data)?;
+        Self::parse_module(&data)
+    }
+
+    fn parse_module(data: &[u8]) -> Result<LoadedModule> {
+        let object = object::File::parse(data)?;
 
+        // Compressed .debug_* sections (zlib, zstd) are inflated by addr2line through object
         let context = Context::new(&object)?;
-        Ok(context)
+        // Stripped binaries may still carry a symbol table in MiniDebugInfo
+        let symbols = SymbolTable::from_object(&object);
+        // Samples carry file offsets; DWARF and symbol tables use link-time addresses
+        let segments = LoadSegments::from_object(&object);
+        let go = GoFunctionTable::from_object(&object)?;
+        Ok(LoadedModule { context, symbols, segments, go })
     }
 
-    fn check_cache(&self, pid: u32, addr: u64) -> Option<SymbolInfo> {
-        if let Some(info) = self.cache.get(&(pid, addr)).cloned() {
-            return Some(info);
+    fn get_module(&mut self, id: ModuleId, module: &Module) -> Result<&LoadedModule> {
+        match self.loaded.entry(id) {
+            Entry::Occupied(entry) => Ok(entry.into_mut()),
+            Entry::Vacant(entry) => {
+                // The vDSO has no backing file; its image was copied out of the target's memory
+                let loaded = match (&module.image, &module.path) {
+                    (Some(image), _) => Self::parse_module(image)?,
+                    (None, Some(path)) => Self::load_binary(path)?,
+                    (None, None) => return Err(anyhow!("Binary {:?} is not accessible", module.name)),
+                };
+                Ok(entry.insert(loaded))
+            }
         }
-        None
     }
 
-    fn update_cache(&mut self, pid: u32, addr: u64, info: SymbolInfo) {
-        if self.cache.len() > 50_000 {
-            self.cache.clear();
-        }
-        self.cache.insert((pid, addr), info);
+    fn index(&mut self, build_id: &[u8]) -> &mut SymbolIndex {
+        let cache = &self.disk_cache;
+        self.indexes
+            .entry(build_id.to_vec())
+            .or_insert_with(|| cache.as_ref().map(|c| c.load(build_id)).unwrap_or_default())
     }
 
-    fn get_context(&mut self, path: &str) -> Result<&Addr2LineContext> {
-        if !self.contexts.contains_key(path) {
-            let context = self.load_binary(path)?;
-            self.contexts.insert(path.to_string(), context);
+    /// Looks the address up in the JIT symbol maps of the process.
+    ///
+    /// JIT code is never cached by address since runtimes reuse code memory;
+    /// the maps are re-read when an address is not found in them, at most
+    /// every few milliseconds.
+    pub fn resolve_jit(&mut self, pid: u32, addr: u64) -> Option<SymbolInfo> {
+        let jit = self.jit.entry(pid).or_insert_with(|| JitSymbols::new(pid));
+        if jit.lookup(addr).is_none() {
+            jit.refresh_if_stale().ok()?;
         }
-        self.contexts.get(path).ok_or_else(|| anyhow!("Failed to get context after insertion"))
+        let symbol = jit.lookup(addr)?;
+        let location = symbol.location(addr);
+        Some(SymbolInfo {
+            function: Some(symbol.name.clone()),
+            file: location.map(|l| l.file.clone()),
+            line: location.map(|l| l.line),
+            inlined_into: Vec::new(),
+        })
     }
 
+    /// Snapshots the mappings of a process so its addresses still resolve after it exits.
+    pub fn track(&mut self, pid: u32) -> Result<()> {
+        self.normalizer.track(pid)
+    }
 
+    /// Mappings of a tracked process, as last seen; they outlive the process.
+    pub fn mappings(&self, pid: u32) -> Option<&[Mapping]> {
+        self.normalizer.mappings(pid)
+    }
+
+    /// The binary a normalized address belongs to.
+    pub fn module(&self, id: ModuleId) -> Option<Arc<Module>> {
+        self.modules.lock().unwrap().get(id)
+    }
+
+    /// Normalizes an absolute address in a process to its binary.
+    pub fn locate(&mut self, pid: u32, addr: u64) -> Option<ModuleAddress> {
+        self.normalizer.normalize(pid, addr)
+    }
+
+    /// Resolves an absolute address in a process, using the mappings it had when tracked.
     pub fn resolve(&mut self, pid: u32, addr: u64) -> Result<SymbolInfo> {
-        if let Some(info) = self.check_cache(pid, addr) {
+        if let Some(location) = self.normalizer.normalize(pid, addr) {
+            return self.resolve_location(location);
+        }
+        if let Some(info) = self.resolve_jit(pid, addr) {
             return Ok(info);
         }
-        
-        // Find mapping to calculate relative address
-        let mapping = maps::find_mapping_for_address(pid, addr)?
-            .ok_or_else(|| anyhow!("No executable mapping found for address 0x{:x}", addr))?;
-        
-        let relative_addr = addr - mapping.start + mapping.offset;
-
-        let info = {
-            let context = match self.get_context(&mapping.pathname) {
-                Ok(ctx) => ctx,
-                Err(_e) => {
-                    // This is expected for [vdso], [vvar], anonymous mappings, or inaccessible files
-                    // We don't want to error out the whole resolution, just return a fallback info later
-                    return Ok(SymbolInfo {
-                        function: Some(format!("unknown_offset_0x{:x}", relative_addr)),
-                        file: Some(mapping.pathname),
-                        line: None,
-                    });
-                }
-            };
-            
-            // addr2line 0.21.0 find_frames returns LookupResult
-            let mut frames = match context.find_frames(relative_addr) {
-                addr2line::LookupResult::Output(result) => result?,
-                _ => return Err(anyhow!("Deferred loading not supported")),
-            };
+        Err(anyhow!("No executable mapping found for address 0x{:x}", addr))
+    }
 
-            if let Some(frame) = frames.next()? {
-                let function = frame.function.as_ref().and_then(|f| {
-                    f.demangle().ok().map(|d| d.to_string())
-                });
-                
-                let (file, line) = if let Some(loc) = frame.location {
-                    (loc.file.map(|f| f.to_string()), loc.line)
-                } else {
-                    (None, None)
-                };
-                
-                Some(SymbolInfo {
-                    function,
-                    file,
-                    line,
-                })
-            } else {
-                Some(SymbolInfo {
-                    function: Some(format!("{}+0x{:x}", mapping.pathname, relative_addr)),
-                    file: Some(mapping.pathname),
-                    line: None,
-                })
+    /// Resolves an address already normalized to its module; works after the process exited.
+    pub fn resolve_location(&mut self, location: ModuleAddress) -> Result<SymbolInfo> {
+        if let Some(info) = self.cache.get(&location) {
+            return Ok(info.clone());
+        }
+        let module = self
+            .modules
+            .lock()
+            .unwrap()
+            .get(location.module)
+            .ok_or_else(|| anyhow!("Unknown module {:?}", location.module))?;
+        let offset = location.offset;
+
+        // A previous run may already have resolved this address
+        if let Some(build_id) = &module.build_id {
+            if let Some(entry) = self.index(build_id).lookup(offset) {
+                let info = SymbolInfo::from_frames(&entry.frames);
+                self.cache.put(location, info.clone());
+                return Ok(info);
             }
+        }
+
+        let found = match self.get_module(location.module, &module) {
+            Ok(loaded) => loaded.find_frames(offset)?,
+            // This is expected for [vsyscall] or inaccessible files
+            // We don't want to error out the whole resolution, just return a fallback info
+            Err(_) => return Ok(SymbolInfo::fallback(format!("unknown_offset_0x{:x}", offset), &module.name)),
         };
 
-        if let Some(info) = info {
-            self.update_cache(pid, addr, info.clone());
-            Ok(info)
-        } else {
-            Err(anyhow!("No frame found for address 0x{:x}", addr))
+        let info = match found {
+            Some((frames, start, end)) => {
+                if let (Some(build_id), true) = (&module.build_id, self.disk_cache.is_some()) {
+                    self.index(build_id).insert(IndexEntry { start, end, frames: frames.clone() });
+                }
+                let mut info = SymbolInfo::from_frames(&frames);
+                if info.file.is_none() && info.line.is_none() {
+                    info.file = Some(module.name.clone());
+                }
+                info
+            }
+            None => SymbolInfo::fallback(format!("{}+0x{:x}", module.name, offset), &module.name),
+        };
+        self.cache.put(location, info.clone());
+        Ok(info)
+    }
+
+    /// Reads the machine code of the function containing a normalized address.
+    pub fn function_code(&mut self, location: ModuleAddress) -> Result<FunctionCode> {
+        let module = self.module(location.module).ok_or_else(|| anyhow!("Unknown module {:?}", location.module))?;
+        let loaded = self.get_module(location.module, &module)?;
+        let (start, end) = loaded
+            .segments
+            .offset_to_vaddr(location.offset)
+            .and_then(|vaddr| loaded.function_bounds(vaddr))
+            .with_context(|| format!("No function found at offset 0x{:x} of {}", location.offset, module.name))?;
+        let offset = loaded.segments.vaddr_to_offset(start).context("Function is not in a loaded segment")?;
+
+        let mut bytes = vec![0; (end - start) as usize];
+        match (&module.image, &module.path) {
+            (Some(image), _) => {
+                let code = image.get(offset as usize..offset as usize + bytes.len()).context("Function is outside of the image")?;
+                bytes.copy_from_slice(code);
+            }
+            (None, Some(path)) => fs::File::open(path)?.read_exact_at(&mut bytes, offset)?,
+            (None, None) => return Err(anyhow!("Binary {:?} is not accessible", module.name)),
         }
+        Ok(FunctionCode { address: start, offset, bytes })
     }
 }
@@ -0,0 +1,324 @@
+use anyhow::{bail, Context, Result};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+// Version 2: ranges are file offsets rather than link-time addresses
+// Version 3: function names are stored mangled
+const MAGIC: &[u8; 8] = b"RRSYMIX3";
+const NONE: u32 = u32::MAX;
+
+/// One frame of a resolved address; several of them form an inline chain
+#[derive(Debug, Clone, PartialEq)]
+pub struct InlineFrame {
+    pub function: Option<String>,
+    pub file: Option<String>,
+    pub line: Option<u32>,
+}
+
+/// Range of file offsets of a binary that resolves to the same inline chain
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndexEntry {
+    pub start: u64,
+    pub end: u64,
+    /// Innermost frame first
+    pub frames: Vec<InlineFrame>,
+}
+
+/// Symbolization results of one binary, keyed by address ranges.
+///
+/// The index is filled as addresses get resolved, so a binary that was profiled
+/// before usually does not need its DWARF loaded at all.
+#[derive(Debug, Default)]
+pub struct SymbolIndex {
+    entries: Vec<IndexEntry>,
+    dirty: bool,
+}
+
+impl SymbolIndex {
+    pub fn lookup(&self, address: u64) -> Option<&IndexEntry> {
+        let idx = self.entries.partition_point(|e| e.start <= address);
+        let entry = self.entries.get(idx.checked_sub(1)?)?;
+        (address < entry.end).then_some(entry)
+    }
+
+    /// Adds a range unless it overlaps one that is already known.
+    pub fn insert(&mut self, entry: IndexEntry) {
+        if entry.start >= entry.end {
+            return;
+        }
+        let idx = self.entries.partition_point(|e| e.start < entry.start);
+        let overlaps_prev = idx > 0 && self.entries[idx - 1].end > entry.start;
+        let overlaps_next = self.entries.get(idx).is_some_and(|e| e.start < entry.end);
+        if !overlaps_prev && !overlaps_next {
+            self.entries.insert(idx, entry);
+            self.dirty = true;
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn write_to(&self, out: &mut impl Write) -> Result<()> {
+        // Function and file names repeat a lot, so they go into a string table
+        let mut strings: Vec<&str> = Vec::new();
+        let mut ids: HashMap<&str, u32> = HashMap::new();
+        let frames = self.entries.iter().flat_map(|e| &e.frames);
+        for s in frames.flat_map(|f| [&f.function, &f.file]).flatten() {
+            ids.entry(s.as_str()).or_insert_with(|| {
+                strings.push(s.as_str());
+                strings.len() as u32 - 1
+            });
+        }
+        let intern = |s: &Option<String>| s.as_ref().map_or(NONE, |s| ids[s.as_str()]);
+
+        let mut body = Vec::new();
+        let mut entry_count: u32 = 0;
+        for entry in &self.entries {
+            // Lengths are stored in 32 bits; longer ranges are written as several entries
+            let mut start = entry.start;
+            while start < entry.end {
+                let len = (entry.end - start).min(u32::MAX as u64);
+                body.extend_from_slice(&start.to_le_bytes());
+                body.extend_from_slice(&(len as u32).to_le_bytes());
+                body.extend_from_slice(&(entry.frames.len() as u16).to_le_bytes());
+                for frame in &entry.frames {
+                    body.extend_from_slice(&intern(&frame.function).to_le_bytes());
+                    body.extend_from_slice(&intern(&frame.file).to_le_bytes());
+                    body.extend_from_slice(&frame.line.unwrap_or(NONE).to_le_bytes());
+                }
+                entry_count += 1;
+                start += len;
+            }
+        }
+
+        out.write_all(MAGIC)?;
+        out.write_all(&(strings.len() as u32).to_le_bytes())?;
+        for s in &strings {
+            out.write_all(&(s.len() as u32).to_le_bytes())?;
+            out.write_all(s.as_bytes())?;
+        }
+        out.write_all(&entry_count.to_le_bytes())?;
+        out.write_all(&body)?;
+        Ok(())
+    }
+
+    fn read_from(data: &[u8]) -> Result<Self> {
+        let mut reader = Reader { data, pos: 0 };
+        if reader.bytes(MAGIC.len())? != MAGIC {
+            bail!("Not an rrstat symbol index");
+        }
+        // Counts come from the file, so they only size allocations as far as
+        // the bytes left could hold: a string takes at least 4, an entry 14
+        let string_count = reader.u32()? as usize;
+        let mut strings = Vec::with_capacity(string_count.min(reader.remaining() / 4));
+        for _ in 0..string_count {
+            let len = reader.u32()? as usize;
+            strings.push(String::from_utf8_lossy(reader.bytes(len)?).into_owned());
+        }
+        let string = |id: u32| -> Option<String> { strings.get(id as usize).cloned() };
+
+        let entry_count = reader.u32()? as usize;
+        let mut entries = Vec::with_capacity(entry_count.min(reader.remaining() / 14));
+        for _ in 0..entry_count {
+            let start = reader.u64()?;
+            let end = start.checked_add(reader.u32()? as u64).context("Symbol index entry out of range")?;
+            let frame_count = reader.u16()?;
+            let mut frames = Vec::with_capacity(frame_count as usize);
+            for _ in 0..frame_count {
+                let function = string(reader.u32()?);
+                let file = string(reader.u32()?);
+                let line = Some(reader.u32()?).filter(|&l| l != NONE);
+                frames.push(InlineFrame { function, file, line });
+            }
+            entries.push(IndexEntry { start, end, frames });
+        }
+        Ok(Self { entries, dirty: false })
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn remaining(&self) -> usize {
+        self.data.len().saturating_sub(self.pos)
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        let bytes = self.data.get(self.pos..self.pos + len).context("Truncated symbol index")?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.bytes(2)?.try_into()?))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into()?))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into()?))
+    }
+}
+
+/// Directory of symbol indexes named after the build-id of the binary they describe.
+///
+/// Build-ids identify the exact binary, so the directory can be shared between runs
+/// and copied between machines. The least recently used indexes are deleted once
+/// the directory grows past `max_bytes`.
+#[derive(Debug, Clone)]
+pub struct SymbolCache {
+    dir: PathBuf,
+    max_bytes: u64,
+}
+
+impl SymbolCache {
+    pub fn new(dir: impl Into<PathBuf>, max_bytes: u64) -> Self {
+        Self { dir: dir.into(), max_bytes }
+    }
+
+    /// `$RRSTAT_CACHE_DIR`, else `$XDG_CACHE_HOME/rrstat`, else `~/.cache/rrstat`
+    pub fn default_dir() -> Option<PathBuf> {
+        if let Some(dir) = std::env::var_os("RRSTAT_CACHE_DIR") {
+            return Some(PathBuf::from(dir));
+        }
+        if let Some(dir) = std::env::var_os("XDG_CACHE_HOME") {
+            return Some(Path::new(&dir).join("rrstat"));
+        }
+        std::env::var_os("HOME").map(|home| Path::new(&home).join(".cache").join("rrstat"))
+    }
+
+    fn path_for(&self, build_id: &[u8]) -> PathBuf {
+        let name: String = build_id.iter().map(|b| format!("{:02x}", b)).collect();
+        self.dir.join(format!("{}.symidx", name))
+    }
+
+    /// Loads the index of a binary, or an empty one if it was never cached.
+    pub fn load(&self, build_id: &[u8]) -> SymbolIndex {
+        let path = self.path_for(build_id);
+        let mut data = Vec::new();
+        let read = File::open(&path).and_then(|mut file| {
+            file.read_to_end(&mut data)?;
+            // Mark as recently used for eviction; a shared read-only cache is fine too
+            let _ = file.set_modified(SystemTime::now());
+            Ok(())
+        });
+        match read {
+            Ok(()) => SymbolIndex::read_from(&data).unwrap_or_default(),
+            Err(_) => SymbolIndex::default(),
+        }
+    }
+
+    /// Writes the index if it gained entries, then trims the cache directory.
+    pub fn store(&self, build_id: &[u8], index: &mut SymbolIndex) -> Result<()> {
+        if !index.dirty {
+            return Ok(());
+        }
+        fs::create_dir_all(&self.dir)
+            .with_context(|| format!("Failed to create symbol cache dir {:?}", self.dir))?;
+
+        // Write to a temporary file first so concurrent runs never read half an index
+        let path = self.path_for(build_id);
+        let tmp = path.with_extension(format!("tmp{}", std::process::id()));
+        let mut out = BufWriter::new(File::create(&tmp)?);
+        index.write_to(&mut out)?;
+        out.into_inner()?.sync_all()?;
+        fs::rename(&tmp, &path)?;
+        index.dirty = false;
+
+        self.evict()
+    }
+
+    /// Deletes the least recently used indexes until the directory fits in `max_bytes`.
+    pub fn evict(&self) -> Result<()> {
+        let mut files = Vec::new();
+        let mut total = 0;
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            if entry.path().extension().is_some_and(|e| e == "symidx") {
+                let meta = entry.metadata()?;
+                total += meta.len();
+                files.push((meta.modified().unwrap_or(SystemTime::UNIX_EPOCH), meta.len(), entry.path()));
+            }
+        }
+        files.sort();
+        for (_, len, path) in files {
+            if total <= self.max_bytes {
+                break;
+            }
+            if fs::remove_file(&path).is_ok() {
+                total -= len;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(function: &str, line: u32) -> InlineFrame {
+        InlineFrame { function: Some(function.to_string()), file: Some("main.c".to_string()), line: Some(line) }
+    }
+
+    #[test]
+    fn test_index_round_trip() {
+        let mut index = SymbolIndex::default();
+        index.insert(IndexEntry { start: 0x1010, end: 0x1020, frames: vec![frame("inner", 3), frame("outer", 10)] });
+        index.insert(IndexEntry { start: 0x1000, end: 0x1010, frames: vec![frame("outer", 9)] });
+        index.insert(IndexEntry { start: 0x1018, end: 0x1030, frames: vec![frame("overlap", 1)] });
+        assert_eq!(index.len(), 2);
+
+        let mut data = Vec::new();
+        index.write_to(&mut data).unwrap();
+        let loaded = SymbolIndex::read_from(&data).unwrap();
+        assert_eq!(loaded.entries, index.entries);
+        assert_eq!(loaded.lookup(0x101f).unwrap().frames[1].function.as_deref(), Some("outer"));
+        assert!(loaded.lookup(0x1020).is_none());
+    }
+
+    #[test]
+    fn test_corrupt_and_large_indexes() {
+        // Counts far beyond what the file holds fail to read instead of allocating
+        let mut data = MAGIC.to_vec();
+        data.extend_from_slice(&u32::MAX.to_le_bytes());
+        assert!(SymbolIndex::read_from(&data).is_err());
+
+        // Ranges over 4 GiB are split rather than truncated
+        let mut index = SymbolIndex::default();
+        index.insert(IndexEntry { start: 0, end: 0x1_0000_0010, frames: vec![frame("huge", 1)] });
+        let mut data = Vec::new();
+        index.write_to(&mut data).unwrap();
+        let loaded = SymbolIndex::read_from(&data).unwrap();
+        assert_eq!(loaded.len(), 2);
+        assert_eq!(loaded.lookup(0x1_0000_0000).unwrap().frames[0].function.as_deref(), Some("huge"));
+    }
+
+    #[test]
+    fn test_eviction_keeps_cache_under_limit() {
+        let dir = std::env::temp_dir().join(format!("rrstat-symcache-{}", std::process::id()));
+        let cache = SymbolCache::new(&dir, 200);
+        for id in 0u8..4 {
+            let mut index = SymbolIndex::default();
+            index.insert(IndexEntry { start: 0, end: 16, frames: vec![frame(&"f".repeat(40), id as u32)] });
+            cache.store(&[id; 20], &mut index).unwrap();
+        }
+        let total: u64 = fs::read_dir(&dir).unwrap().map(|e| e.unwrap().metadata().unwrap().len()).sum();
+        assert!(total <= 200);
+        assert!(cache.path_for(&[3; 20]).exists());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,537 @@
+use anyhow::Result;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Modifier, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Cell, Paragraph, Row, Table, TableState};
+use ratatui::{DefaultTerminal, Frame};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use crate::aggregator::{Aggregator, FrameKey, FrameKind};
+use crate::calltree::{CallTree, CallerCallee};
+use crate::demangle::NameStyle;
+use crate::ringbuffer::RingBuffer;
+use crate::types::Sample;
+
+/// Time after which a function's recent cost has halved
+const HALF_LIFE: Duration = Duration::from_secs(2);
+/// Rows whose recent cost decayed below this share of all recent cost are
+/// dropped, totals included, so functions that stopped running don't pile up
+const EVICT_SHARE: f64 = 1e-4;
+/// Nodes a thread's call tree may grow to before a new one is started
+const MAX_TREE_NODES: usize = 50_000;
+
+/// How the functions of the live view are ordered
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SortKey {
+    /// By the decayed cost, i.e. what runs right now
+    #[default]
+    Recent,
+    /// By the cost since the view was started
+    Total,
+    Name,
+}
+
+impl SortKey {
+    fn next(self) -> Self {
+        match self {
+            SortKey::Recent => SortKey::Total,
+            SortKey::Total => SortKey::Name,
+            SortKey::Name => SortKey::Recent,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            SortKey::Recent => "recent",
+            SortKey::Total => "total",
+            SortKey::Name => "name",
+        }
+    }
+}
+
+/// A function of the live view, over the threads and modules the filters let through
+#[derive(Debug, Clone, PartialEq)]
+pub struct TopRow {
+    pub frame: FrameKey,
+    /// The frame as shown, in the view's name style
+    pub label: String,
+    pub module: String,
+    /// Events decayed by age, so old samples fade out
+    pub recent: f64,
+    pub events: usize,
+    pub samples: usize,
+}
+
+#[derive(Debug, Default)]
+struct Counts {
+    recent: f64,
+    events: usize,
+    samples: usize,
+}
+
+/// What `rrstat top` shows: a decaying per-function cost, like `perf top`,
+/// with the call trees to zoom into a function's callers.
+#[derive(Debug, Default)]
+pub struct TopState {
+    /// Self cost by thread, module and function
+    counts: HashMap<(i32, String, FrameKey), Counts>,
+    /// Call trees of each thread
+    trees: HashMap<i32, CallTree>,
+    /// The trees filled before those in `trees`, until they grow too big in turn
+    old_trees: HashMap<i32, CallTree>,
+    /// Labels of the frames in `counts`, in `name_style`
+    labels: HashMap<FrameKey, String>,
+    thread_names: BTreeMap<i32, Option<String>>,
+    modules: BTreeSet<String>,
+    pub sort: SortKey,
+    /// How function names are shown; frames stay apart by their full names
+    pub name_style: NameStyle,
+    /// Only show this thread
+    pub thread: Option<i32>,
+    /// Only show this module
+    pub module: Option<String>,
+    /// Only show functions whose name contains this
+    pub filter: String,
+}
+
+impl TopState {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a sample of a thread, outermost frame first.
+    pub fn add(&mut self, tid: i32, thread_name: Option<&str>, module: &str, stack: &[FrameKey], weight: usize) {
+        let Some(leaf) = stack.last() else {
+            return;
+        };
+        if !self.labels.contains_key(leaf) {
+            self.labels.insert(leaf.clone(), leaf.styled(self.name_style).label());
+        }
+        let counts = self.counts.entry((tid, module.to_string(), leaf.clone())).or_default();
+        counts.recent += weight as f64;
+        counts.events += weight;
+        counts.samples += 1;
+        let tree = self.trees.entry(tid).or_default();
+        tree.add(stack, weight);
+        if tree.node_count() > MAX_TREE_NODES {
+            let full = std::mem::take(tree);
+            self.old_trees.insert(tid, full);
+        }
+        self.thread_names.insert(tid, thread_name.map(str::to_string));
+        self.modules.insert(module.to_string());
+    }
+
+    /// Ages the recent costs by `elapsed`, and forgets the functions and
+    /// threads that have hardly run lately.
+    pub fn tick(&mut self, elapsed: Duration) {
+        let factor = 0.5f64.powf(elapsed.as_secs_f64() / HALF_LIFE.as_secs_f64());
+        let mut total = 0.0;
+        for counts in self.counts.values_mut() {
+            counts.recent *= factor;
+            total += counts.recent;
+        }
+        self.counts.retain(|_, counts| counts.recent >= total * EVICT_SHARE);
+
+        let frames: HashSet<&FrameKey> = self.counts.keys().map(|(_, _, frame)| frame).collect();
+        self.labels.retain(|frame, _| frames.contains(frame));
+        let tids: HashSet<i32> = self.counts.keys().map(|(tid, _, _)| *tid).collect();
+        self.trees.retain(|tid, _| tids.contains(tid));
+        self.old_trees.retain(|tid, _| tids.contains(tid));
+    }
+
+    /// The functions the filters let through, in the chosen order.
+    pub fn rows(&self) -> Vec<TopRow> {
+        let filter = self.filter.to_lowercase();
+        let mut rows: HashMap<(&str, &FrameKey), TopRow> = HashMap::new();
+        for ((tid, module, frame), counts) in &self.counts {
+            let label = &self.labels[frame];
+            if self.thread.is_some_and(|thread| thread != *tid)
+                || self.module.as_ref().is_some_and(|m| m != module)
+                || !label.to_lowercase().contains(&filter)
+            {
+                continue;
+            }
+            let row = rows.entry((module, frame)).or_insert_with(|| TopRow {
+                frame: frame.clone(),
+                label: label.clone(),
+                module: module.clone(),
+                recent: 0.0,
+                events: 0,
+                samples: 0,
+            });
+            row.recent += counts.recent;
+            row.events += counts.events;
+            row.samples += counts.samples;
+        }
+        let mut rows: Vec<TopRow> = rows.into_values().collect();
+        rows.sort_by(|a, b| {
+            let order = match self.sort {
+                SortKey::Recent => b.recent.total_cmp(&a.recent),
+                SortKey::Total => b.events.cmp(&a.events),
+                SortKey::Name => std::cmp::Ordering::Equal,
+            };
+            order.then_with(|| a.label.cmp(&b.label)).then_with(|| a.module.cmp(&b.module))
+        });
+        rows
+    }
+
+    /// Callers and callees of a function, in the thread filtered on or in all threads.
+    pub fn caller_callee(&self, frame: &FrameKey) -> CallerCallee {
+        let matches = |f: &FrameKey| f.name == frame.name && f.file == frame.file;
+        let mut merged = CallerCallee { self_count: 0, total_count: 0, callers: Vec::new(), callees: Vec::new() };
+        let mut callers: HashMap<FrameKey, usize> = HashMap::new();
+        let mut callees: HashMap<FrameKey, usize> = HashMap::new();
+        for (tid, tree) in self.trees.iter().chain(&self.old_trees) {
+            if self.thread.is_some_and(|thread| thread != *tid) {
+                continue;
+            }
+            let view = tree.caller_callee(matches);
+            merged.self_count += view.self_count;
+            merged.total_count += view.total_count;
+            for (caller, count) in view.callers {
+                *callers.entry(caller).or_insert(0) += count;
+            }
+            for (callee, count) in view.callees {
+                *callees.entry(callee).or_insert(0) += count;
+            }
+        }
+        let sorted = |map: HashMap<FrameKey, usize>| {
+            let mut entries: Vec<(FrameKey, usize)> = map.into_iter().collect();
+            entries.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.name.cmp(&b.0.name)));
+            entries
+        };
+        merged.callers = sorted(callers);
+        merged.callees = sorted(callees);
+        merged
+    }
+
+    /// Shows the next thread, or all of them after the last one.
+    pub fn next_thread(&mut self) {
+        self.thread = match self.thread {
+            None => self.thread_names.keys().next().copied(),
+            Some(tid) => self.thread_names.range(tid + 1..).next().map(|(&tid, _)| tid),
+        };
+    }
+
+    /// Shows the next module, or all of them after the last one.
+    pub fn next_module(&mut self) {
+        self.module = match &self.module {
+            None => self.modules.iter().next().cloned(),
+            Some(module) => self.modules.iter().find(|m| *m > module).cloned(),
+        };
+    }
+
+    fn thread_label(&self) -> String {
+        match self.thread {
+            None => "all".to_string(),
+            Some(tid) => match self.thread_names.get(&tid).cloned().flatten() {
+                Some(name) => format!("{} ({})", name, tid),
+                None => tid.to_string(),
+            },
+        }
+    }
+}
+
+/// Module a sample's leaf frame is in, by file name
+fn leaf_module(aggregator: &mut Aggregator, sample: &Sample, leaf: &FrameKey) -> String {
+    match leaf.kind {
+        FrameKind::Kernel => return "[kernel]".to_string(),
+        FrameKind::Python => return "[python]".to_string(),
+        _ => {}
+    }
+    sample
+        .location
+        .and_then(|location| aggregator.resolver_mut().module(location.module))
+        .map(|module| match Path::new(&module.name).file_name() {
+            Some(name) => name.to_string_lossy().into_owned(),
+            None => module.name.clone(),
+        })
+        .unwrap_or_else(|| "[unknown]".to_string())
+}
+
+/// The live view: what it shows and where the user is in it
+struct App {
+    state: TopState,
+    table: TableState,
+    paused: bool,
+    /// Typing a new name filter
+    editing: bool,
+    /// The function whose callers and callees are shown
+    zoom: Option<FrameKey>,
+    samples: usize,
+    rate: f64,
+}
+
+impl App {
+    /// Handles a key; returns false when the view should close.
+    fn key(&mut self, code: KeyCode, modifiers: KeyModifiers) -> bool {
+        if code == KeyCode::Char('c') && modifiers.contains(KeyModifiers::CONTROL) {
+            return false;
+        }
+        if self.editing {
+            match code {
+                KeyCode::Enter | KeyCode::Esc => self.editing = false,
+                KeyCode::Backspace => {
+                    self.state.filter.pop();
+                }
+                KeyCode::Char(c) => self.state.filter.push(c),
+                _ => {}
+            }
+            return true;
+        }
+        match code {
+            KeyCode::Char('q') => return false,
+            KeyCode::Esc | KeyCode::Backspace if self.zoom.is_some() => self.zoom = None,
+            KeyCode::Esc => return false,
+            KeyCode::Char('p') | KeyCode::Char(' ') => self.paused = !self.paused,
+            KeyCode::Char('s') => self.state.sort = self.state.sort.next(),
+            KeyCode::Char('t') => self.state.next_thread(),
+            KeyCode::Char('m') => self.state.next_module(),
+            KeyCode::Char('/') => {
+                self.editing = true;
+                self.state.filter.clear();
+            }
+            KeyCode::Up => self.table.select_previous(),
+            KeyCode::Down => self.table.select_next(),
+            KeyCode::Enter => {
+                let rows = self.state.rows();
+                self.zoom = self.table.selected().and_then(|i| rows.get(i)).map(|row| row.frame.clone());
+            }
+            _ => {}
+        }
+        true
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [header, body, footer] =
+            Layout::vertical([Constraint::Length(2), Constraint::Min(0), Constraint::Length(1)]).areas(frame.area());
+        let mut status = format!(
+            "{} samples, {:.0} samples/s | sort: {} | thread: {} | module: {} | filter: {}",
+            self.samples,
+            self.rate,
+            self.state.sort.name(),
+            self.state.thread_label(),
+            self.state.module.as_deref().unwrap_or("all"),
+            if self.editing { format!("{}_", self.state.filter) } else { self.state.filter.clone() },
+        );
+        if self.paused {
+            status.push_str(" | PAUSED");
+        }
+        frame.render_widget(Paragraph::new(vec![Line::from("rrstat top"), Line::from(status)]), header);
+
+        let bold = Style::default().add_modifier(Modifier::BOLD);
+        let help = match &self.zoom {
+            Some(_) => "Esc back  p pause  t thread  q quit",
+            None => "Up/Down select  Enter callers  s sort  t thread  m module  / filter  p pause  q quit",
+        };
+        frame.render_widget(Paragraph::new(help), footer);
+
+        if let Some(zoomed) = &self.zoom {
+            let view = self.state.caller_callee(zoomed);
+            let label = |frame: &FrameKey| frame.styled(self.state.name_style).label();
+            let total = view.total_count.max(1) as f64;
+            let mut rows = vec![Row::new(vec![
+                Cell::from(format!("{:.1}%", 100.0)),
+                Cell::from(label(zoomed)).style(bold),
+            ])];
+            rows.push(Row::new(vec![Cell::from(""), Cell::from(format!("self: {} events", view.self_count))]));
+            rows.push(Row::new(vec![Cell::from(""), Cell::from("called from:").style(bold)]));
+            for (caller, count) in &view.callers {
+                rows.push(Row::new(vec![
+                    Cell::from(format!("{:.1}%", *count as f64 / total * 100.0)),
+                    Cell::from(format!("  {}", label(caller))),
+                ]));
+            }
+            rows.push(Row::new(vec![Cell::from(""), Cell::from("calls:").style(bold)]));
+            for (callee, count) in &view.callees {
+                rows.push(Row::new(vec![
+                    Cell::from(format!("{:.1}%", *count as f64 / total * 100.0)),
+                    Cell::from(format!("  {}", label(callee))),
+                ]));
+            }
+            frame.render_widget(Table::new(rows, [Constraint::Length(8), Constraint::Min(10)]), body);
+            return;
+        }
+
+        let rows = self.state.rows();
+        let recent_total = rows.iter().map(|row| row.recent).sum::<f64>().max(f64::MIN_POSITIVE);
+        let events_total = rows.iter().map(|row| row.events).sum::<usize>().max(1) as f64;
+        let table = Table::new(
+            rows.iter().map(|row| {
+                Row::new(vec![
+                    format!("{:.2}%", row.recent / recent_total * 100.0),
+                    format!("{:.2}%", row.events as f64 / events_total * 100.0),
+                    row.samples.to_string(),
+                    row.module.clone(),
+                    row.label.clone(),
+                ])
+            }),
+            [Constraint::Length(8), Constraint::Length(8), Constraint::Length(8), Constraint::Length(20), Constraint::Min(10)],
+        )
+        .header(Row::new(vec!["Recent", "Total", "Samples", "Module", "Function"]).style(bold))
+        .row_highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+        if self.table.selected().is_none() && !rows.is_empty() {
+            self.table.select(Some(0));
+        }
+        frame.render_stateful_widget(table, body, &mut self.table);
+    }
+}
+
+/// Runs the live view until the user quits, draining the collector's samples
+/// every `refresh`. The terminal is restored however the view ends.
+pub fn run(aggregator: Aggregator, buffer: &RingBuffer, refresh: Duration) -> Result<()> {
+    let mut terminal = ratatui::try_init()?;
+    let result = run_app(&mut terminal, aggregator, buffer, refresh);
+    ratatui::try_restore()?;
+    result
+}
+
+fn run_app(terminal: &mut DefaultTerminal, mut aggregator: Aggregator, buffer: &RingBuffer, refresh: Duration) -> Result<()> {
+    let mut app = App {
+        state: TopState { name_style: aggregator.name_style(), ..TopState::new() },
+        table: TableState::default(),
+        paused: false,
+        editing: false,
+        zoom: None,
+        samples: 0,
+        rate: 0.0,
+    };
+    let mut last = Instant::now();
+    loop {
+        terminal.draw(|frame| app.draw(frame))?;
+        let deadline = last + refresh;
+        while let Some(timeout) = deadline.checked_duration_since(Instant::now()) {
+            if !event::poll(timeout)? {
+                break;
+            }
+            if let Event::Key(key) = event::read()? {
+                if key.kind == KeyEventKind::Press {
+                    if !app.key(key.code, key.modifiers) {
+                        return Ok(());
+                    }
+                    terminal.draw(|frame| app.draw(frame))?;
+                }
+            }
+        }
+
+        // Keep draining while paused so the buffer doesn't fill with stale samples
+        let samples = buffer.drain();
+        let elapsed = last.elapsed();
+        last = Instant::now();
+        if app.paused {
+            continue;
+        }
+        app.rate = samples.len() as f64 / elapsed.as_secs_f64();
+        app.samples += samples.len();
+        app.state.tick(elapsed);
+        for sample in &samples {
+            let stack = aggregator.sample_frames(sample);
+            let Some(leaf) = stack.last() else {
+                continue;
+            };
+            let module = leaf_module(&mut aggregator, sample, leaf);
+            app.state.add(sample.tid, sample.thread_name.as_deref(), &module, &stack, sample.period as usize);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stack(names: &str) -> Vec<FrameKey> {
+        names.split(';').map(FrameKey::function).collect()
+    }
+
+    fn names(rows: &[TopRow]) -> Vec<&str> {
+        rows.iter().map(|row| row.frame.name.as_str()).collect()
+    }
+
+    #[test]
+    fn test_decay_and_sort() {
+        let mut state = TopState::new();
+        state.add(1, Some("main"), "app", &stack("main;parse"), 8);
+        state.tick(HALF_LIFE * 2);
+        state.add(1, Some("main"), "app", &stack("main;eval"), 3);
+
+        let rows = state.rows();
+        assert_eq!(names(&rows), ["eval", "parse"]);
+        assert!((rows[1].recent - 2.0).abs() < 1e-9);
+        assert_eq!(rows[1].events, 8);
+
+        state.sort = SortKey::Total;
+        assert_eq!(names(&state.rows()), ["parse", "eval"]);
+        state.sort = SortKey::Name;
+        assert_eq!(names(&state.rows()), ["eval", "parse"]);
+    }
+
+    #[test]
+    fn test_eviction() {
+        let mut state = TopState::new();
+        state.add(1, None, "app", &stack("main;parse"), 1);
+        state.add(2, None, "app", &stack("work;eval"), 100_000);
+        state.tick(Duration::ZERO);
+        assert_eq!(names(&state.rows()), ["eval"]);
+        assert!(state.labels.len() == 1 && !state.trees.contains_key(&1));
+
+        // Call trees start over once they get too big, but still answer for a while
+        for i in 0..MAX_TREE_NODES {
+            state.add(2, None, "app", &stack(&format!("work;f{}", i)), 1);
+        }
+        assert!(state.trees[&2].node_count() < MAX_TREE_NODES);
+        assert_eq!(state.caller_callee(&FrameKey::function("eval")).callers, vec![(FrameKey::function("work"), 100_000)]);
+    }
+
+    #[test]
+    fn test_filters() {
+        let mut state = TopState::new();
+        state.add(1, Some("main"), "app", &stack("main;parse"), 4);
+        state.add(2, Some("worker"), "app", &stack("work;parse"), 2);
+        state.add(2, Some("worker"), "libc.so.6", &stack("work;memcpy"), 1);
+
+        // Threads are merged unless one is picked
+        assert_eq!(state.rows()[0].events, 6);
+        state.next_thread();
+        assert_eq!(state.thread, Some(1));
+        state.next_thread();
+        assert_eq!(names(&state.rows()), ["parse", "memcpy"]);
+        state.next_thread();
+        assert_eq!(state.thread, None);
+
+        state.next_module();
+        assert_eq!(state.module.as_deref(), Some("app"));
+        state.next_module();
+        assert_eq!(names(&state.rows()), ["memcpy"]);
+        state.next_module();
+        assert_eq!(state.module, None);
+
+        state.filter = "PARSE".to_string();
+        assert_eq!(names(&state.rows()), ["parse"]);
+    }
+
+    #[test]
+    fn test_overloads_stay_apart() {
+        let mut state = TopState { name_style: NameStyle::Simplified, ..TopState::new() };
+        state.add(1, None, "app", &stack("main;_Z5parsei"), 2);
+        state.add(1, None, "app", &stack("main;_Z5parsed"), 1);
+        let labels: Vec<String> = state.rows().into_iter().map(|row| row.label).collect();
+        assert_eq!(labels, ["parse", "parse"]);
+    }
+
+    #[test]
+    fn test_zoom_callers() {
+        let mut state = TopState::new();
+        state.add(1, None, "app", &stack("main;parse"), 4);
+        state.add(2, None, "app", &stack("work;parse;lex"), 2);
+        let view = state.caller_callee(&FrameKey::function("parse"));
+        assert_eq!((view.self_count, view.total_count), (4, 6));
+        assert_eq!(view.callers, vec![(FrameKey::function("main"), 4), (FrameKey::function("work"), 2)]);
+        assert_eq!(view.callees, vec![(FrameKey::function("lex"), 2)]);
+
+        state.thread = Some(2);
+        assert_eq!(state.caller_callee(&FrameKey::function("parse")).callers, vec![(FrameKey::function("work"), 2)]);
+    }
+}
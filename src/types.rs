@@ -1,34 +1,319 @@
+use serde::Serialize;
+use std::collections::HashMap;
 use std::fmt;
+use std::fs;
+
+use crate::aggregator::FrameKey;
+use crate::calltree::{CallTree, CallerCallee};
+use crate::maps::MemorySummary;
+use crate::modules::ModuleAddress;
+
+/// One frame of a sampled call stack
+#[derive(Debug, Clone, PartialEq)]
+pub enum StackFrame {
+    /// Machine code: the sampled instruction pointer for the innermost frame,
+    /// return addresses for the others
+    Native {
+        address: u64,
+        /// Normalized address of the instruction being executed, i.e. the call
+        /// instruction for return addresses
+        location: Option<ModuleAddress>,
+    },
+    /// A function running in the CPython interpreter
+    Python {
+        function: String,
+        file: String,
+        line: Option<u32>,
+    },
+}
 
 /// A single sample of the measured event
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Default)]
 pub struct Sample {
+    /// Reading of the sampled thread's counter
     pub value: u64,
     pub pid: i32,
+    /// Thread the sample was taken from
+    pub tid: i32,
+    /// Name of the thread when the sample was taken (`/proc/<pid>/task/<tid>/comm`)
+    pub thread_name: Option<String>,
+    /// Events counted on the thread since its previous sample, i.e. the weight of this one
+    pub period: u64,
     pub timestamp: u64,
     pub instruction_pointer: u64,
+    /// The instruction pointer normalized to its binary when the sample was taken
+    pub location: Option<ModuleAddress>,
+    /// Call stack, innermost frame first; empty if it could not be unwound
+    pub stack: Vec<StackFrame>,
 }
 
+/// A sample with its stack resolved, kept in the order samples were taken
 #[derive(Debug, Clone)]
+pub struct TimedSample {
+    /// Milliseconds since profiling started
+    pub timestamp: u64,
+    pub pid: i32,
+    pub tid: i32,
+    pub thread_name: Option<String>,
+    /// Events the sample stands for
+    pub weight: usize,
+    /// Frames, outermost first
+    pub stack: Vec<FrameKey>,
+}
+
+/// The samples of one thread, in the order they were taken
+#[derive(Debug)]
+pub struct ThreadTimeline<'a> {
+    pub pid: i32,
+    pub tid: i32,
+    /// Last name the thread had
+    pub name: Option<&'a str>,
+    pub samples: Vec<&'a TimedSample>,
+}
+
+/// Events and samples of one thread, with the functions it stopped in
+#[derive(Debug, Clone, Serialize)]
+pub struct ThreadStats {
+    pub pid: i32,
+    pub tid: i32,
+    pub name: Option<String>,
+    pub samples: usize,
+    pub events: usize,
+    /// Share of all events
+    pub percentage: f64,
+    /// Events of each frame the thread's samples stopped in, highest first
+    pub functions: Vec<FrameEvents>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FrameEvents {
+    pub name: String,
+    pub events: usize,
+}
+
+/// What was profiled, and where
+#[derive(Debug, Clone, Serialize)]
+pub struct RunMetadata {
+    /// Command line of the target, from `/proc/<pid>/cmdline`
+    pub command: Option<String>,
+    pub pid: i32,
+    pub event: String,
+    /// How long the target was sampled, in milliseconds
+    pub duration_ms: u64,
+    /// Samples taken per second, over all threads
+    pub sample_rate: f64,
+    /// Kernel release, as shown by `uname -r`
+    pub kernel: Option<String>,
+    pub host: Option<String>,
+}
+
+impl RunMetadata {
+    /// Reads what describes the target and the machine; duration and sample
+    /// rate are left to fill in once sampling is over.
+    pub fn read(pid: i32, event: &str) -> Self {
+        let command = fs::read(format!("/proc/{}/cmdline", pid)).ok().map(|cmdline| {
+            let args: Vec<String> = cmdline
+                .split(|&b| b == 0)
+                .filter(|arg| !arg.is_empty())
+                .map(|arg| String::from_utf8_lossy(arg).into_owned())
+                .collect();
+            args.join(" ")
+        });
+        let read_trimmed = |path: &str| fs::read_to_string(path).ok().map(|text| text.trim().to_string());
+        Self {
+            command: command.filter(|command| !command.is_empty()),
+            pid,
+            event: event.to_string(),
+            duration_ms: 0,
+            sample_rate: 0.0,
+            kernel: read_trimmed("/proc/sys/kernel/osrelease"),
+            host: read_trimmed("/proc/sys/kernel/hostname"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
 pub struct FunctionStats {
     pub name: String,
+    /// Weight of the samples with this entry anywhere on their stack; `count`
+    /// only has those that stopped in it
+    pub total_count: usize,
+    /// Source position, when aggregating by line or address
+    pub file: Option<String>,
+    pub line: Option<u32>,
+    /// Offset in the binary, when aggregating by address
+    pub address: Option<u64>,
+    /// Events counted in samples that stopped here
     pub count: usize,
+    /// Number of samples that stopped here
+    pub samples: usize,
     pub percentage: f64,
 }
 
+impl FunctionStats {
+    /// `file:line`, or just the file when the line is unknown
+    pub fn source(&self) -> Option<String> {
+        let file = self.file.as_ref()?;
+        Some(match self.line {
+            Some(line) => format!("{}:{}", file, line),
+            None => file.clone(),
+        })
+    }
+}
+
+/// Events of one slice of the run, and the share the top functions had in it
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TimeBucket {
+    /// Milliseconds since profiling started
+    pub start_ms: u64,
+    pub samples: usize,
+    pub events: usize,
+    /// Percentage of the bucket's events that stopped in each of the series' functions
+    pub shares: Vec<f64>,
+}
+
+/// How the top functions' share of events changes over the run
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TimeSeries {
+    pub bucket_ms: u64,
+    /// The functions with the most self events over the whole run, highest first
+    pub functions: Vec<String>,
+    pub buckets: Vec<TimeBucket>,
+}
+
 #[derive(Debug)]
 pub struct Report {
-    pub total_samples: usize,
+    /// Events counted over all samples; percentages are relative to it
+    pub total_events: usize,
+    /// Number of samples taken
+    pub sample_count: usize,
     pub stats: Vec<FunctionStats>,
-    pub folded_stacks: Vec<String>,
+    /// Weight of each call stack, folded as `root;...;leaf`, ordered by stack
+    pub folded_stacks: Vec<(String, usize)>,
+    /// The samples the report was made from, in the order they were taken;
+    /// empty for reports read back from folded stacks
+    pub samples: Vec<Sample>,
+    /// The same samples resolved to frames; empty for reports read back from folded stacks
+    pub timeline: Vec<TimedSample>,
+    /// All sampled stacks, with self and total cost per node
+    pub call_tree: CallTree,
+    /// Memory usage of the target from smaps, when requested
+    pub memory: Option<MemorySummary>,
+    /// The run the samples come from; `None` for reports read back from folded stacks
+    pub metadata: Option<RunMetadata>,
+}
+
+impl Report {
+    /// The timeline split by thread, ordered by thread id.
+    pub fn threads(&self) -> Vec<ThreadTimeline<'_>> {
+        let mut threads: Vec<ThreadTimeline> = Vec::new();
+        for sample in &self.timeline {
+            let index = match threads.iter().position(|t| t.pid == sample.pid && t.tid == sample.tid) {
+                Some(index) => index,
+                None => {
+                    threads.push(ThreadTimeline { pid: sample.pid, tid: sample.tid, name: None, samples: Vec::new() });
+                    threads.len() - 1
+                }
+            };
+            let thread = &mut threads[index];
+            thread.name = sample.thread_name.as_deref().or(thread.name);
+            thread.samples.push(sample);
+        }
+        threads.sort_by_key(|t| (t.pid, t.tid));
+        threads
+    }
+
+    /// Events and samples of each thread, ordered by thread id.
+    pub fn thread_stats(&self) -> Vec<ThreadStats> {
+        self.threads()
+            .into_iter()
+            .map(|thread| {
+                let mut functions: HashMap<String, usize> = HashMap::new();
+                for sample in &thread.samples {
+                    if let Some(leaf) = sample.stack.last() {
+                        *functions.entry(leaf.label()).or_insert(0) += sample.weight;
+                    }
+                }
+                let mut functions: Vec<FrameEvents> =
+                    functions.into_iter().map(|(name, events)| FrameEvents { name, events }).collect();
+                functions.sort_by(|a, b| b.events.cmp(&a.events).then_with(|| a.name.cmp(&b.name)));
+                let events = thread.samples.iter().map(|s| s.weight).sum();
+                ThreadStats {
+                    pid: thread.pid,
+                    tid: thread.tid,
+                    name: thread.name.map(str::to_string),
+                    samples: thread.samples.len(),
+                    events,
+                    percentage: events as f64 / self.total_events.max(1) as f64 * 100.0,
+                    functions,
+                }
+            })
+            .collect()
+    }
+
+    /// Splits the run into at most `buckets` equal slices of time and gives the
+    /// share of the `top` functions with the most self events in each of them.
+    /// Empty for reports read back from folded stacks, which have no times.
+    pub fn time_series(&self, buckets: usize, top: usize) -> TimeSeries {
+        let mut totals: HashMap<&str, usize> = HashMap::new();
+        for sample in &self.timeline {
+            if let Some(leaf) = sample.stack.last() {
+                *totals.entry(&leaf.name).or_insert(0) += sample.weight;
+            }
+        }
+        let mut functions: Vec<(&str, usize)> = totals.into_iter().collect();
+        functions.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+        let functions: Vec<String> = functions.into_iter().take(top).map(|(name, _)| name.to_string()).collect();
+
+        let (Some(first), Some(last)) = (
+            self.timeline.iter().map(|s| s.timestamp).min(),
+            self.timeline.iter().map(|s| s.timestamp).max(),
+        ) else {
+            return TimeSeries { bucket_ms: 0, functions, buckets: Vec::new() };
+        };
+        let bucket_ms = (last - first + 1).div_ceil(buckets.max(1) as u64);
+        let count = ((last - first) / bucket_ms + 1) as usize;
+        let mut events = vec![vec![0usize; functions.len()]; count];
+        let mut series: Vec<TimeBucket> = (0..count)
+            .map(|i| TimeBucket { start_ms: first + i as u64 * bucket_ms, samples: 0, events: 0, shares: Vec::new() })
+            .collect();
+        for sample in &self.timeline {
+            let index = ((sample.timestamp - first) / bucket_ms) as usize;
+            series[index].samples += 1;
+            series[index].events += sample.weight;
+            let leaf = sample.stack.last().map(|leaf| leaf.name.as_str());
+            if let Some(function) = functions.iter().position(|f| Some(f.as_str()) == leaf) {
+                events[index][function] += sample.weight;
+            }
+        }
+        for (bucket, events) in series.iter_mut().zip(events) {
+            bucket.shares = events.iter().map(|e| *e as f64 / bucket.events.max(1) as f64 * 100.0).collect();
+        }
+        TimeSeries { bucket_ms, functions, buckets: series }
+    }
+
+    /// Everything `function` called, merged over all the places it was called from.
+    pub fn top_down(&self, function: &str) -> CallTree {
+        self.call_tree.callees_of(|frame| frame.name == function)
+    }
+
+    /// The chains of callers leading to `function`.
+    pub fn bottom_up(&self, function: &str) -> CallTree {
+        self.call_tree.callers_of(|frame| frame.name == function)
+    }
+
+    /// Direct callers and callees of `function`.
+    pub fn callers_callees(&self, function: &str) -> CallerCallee {
+        self.call_tree.caller_callee(|frame| frame.name == function)
+    }
 }
 
 impl fmt::Display for Sample {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
             f,
-            "Sample {{ ts: {}, pid: {}, val: {}, ip: {:#x} }}",
-            self.timestamp, self.pid, self.value, self.instruction_pointer
+            "Sample {{ ts: {}, pid: {}, tid: {}, val: {}, period: {}, ip: {:#x} }}",
+            self.timestamp, self.pid, self.tid, self.value, self.period, self.instruction_pointer
         )
     }
 }
\ No newline at end of file
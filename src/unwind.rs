@@ -0,0 +1,221 @@
+use anyhow::{anyhow, Context, Result};
+use gimli::{
+    BaseAddresses, CfaRule, EhFrame, EhFrameHdr, LittleEndian, RegisterRule, UnwindContext, UnwindSection, X86_64,
+};
+use object::{Object, ObjectSection};
+use std::collections::HashMap;
+use std::fs;
+
+use crate::elf::LoadSegments;
+use crate::modules::{Module, ModuleAddress, ModuleId, SharedModules};
+use crate::types::StackFrame;
+
+/// Stacks deeper than this are cut, which also stops loops on corrupt stacks
+const MAX_FRAMES: usize = 128;
+
+/// The registers unwinding starts from, as read with `PTRACE_GETREGS`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Registers {
+    pub ip: u64,
+    pub sp: u64,
+    pub bp: u64,
+}
+
+/// `.eh_frame` of one binary, with what is needed to search it
+struct UnwindTable {
+    eh_frame: Vec<u8>,
+    eh_frame_hdr: Option<Vec<u8>>,
+    bases: BaseAddresses,
+    segments: LoadSegments,
+}
+
+impl UnwindTable {
+    fn load(module: &Module) -> Result<Self> {
+        match (&module.image, &module.path) {
+            (Some(image), _) => Self::parse(image),
+            (None, Some(path)) => {
+                let file = fs::File::open(path).with_context(|| format!("Failed to open binary {:?}", path))?;
+                let data = unsafe { memmap2::Mmap::map(&file)? };
+                Self::parse(&data)
+            }
+            (None, None) => Err(anyhow!("Binary {:?} is not accessible", module.name)),
+        }
+    }
+
+    fn parse(data: &[u8]) -> Result<Self> {
+        let object = object::File::parse(data)?;
+        let eh_frame = object.section_by_name(".eh_frame").context("No .eh_frame section")?;
+        let mut bases = BaseAddresses::default().set_eh_frame(eh_frame.address());
+        let eh_frame_hdr = match object.section_by_name(".eh_frame_hdr") {
+            Some(hdr) => {
+                bases = bases.set_eh_frame_hdr(hdr.address());
+                Some(hdr.data()?.to_vec())
+            }
+            None => None,
+        };
+        // Pointer encodings in CIEs may be relative to these
+        if let Some(text) = object.section_by_name(".text") {
+            bases = bases.set_text(text.address());
+        }
+        if let Some(got) = object.section_by_name(".got") {
+            bases = bases.set_got(got.address());
+        }
+        Ok(Self { eh_frame: eh_frame.data()?.to_vec(), eh_frame_hdr, bases, segments: LoadSegments::from_object(&object) })
+    }
+
+    /// Computes the caller's registers from the CFI row covering `vaddr`.
+    ///
+    /// Only the rules compilers emit for ordinary x86-64 code are supported: a CFA
+    /// relative to `rsp` or `rbp`, and registers saved at an offset from it.
+    fn step(&self, vaddr: u64, regs: &Registers, read: &mut impl FnMut(u64) -> Option<u64>) -> Option<Registers> {
+        let eh_frame = EhFrame::new(&self.eh_frame, LittleEndian);
+        let fde = match &self.eh_frame_hdr {
+            Some(hdr) => EhFrameHdr::new(hdr, LittleEndian)
+                .parse(&self.bases, 8)
+                .ok()?
+                .table()?
+                .fde_for_address(&eh_frame, &self.bases, vaddr, EhFrame::cie_from_offset)
+                .ok()?,
+            None => eh_frame.fde_for_address(&self.bases, vaddr, EhFrame::cie_from_offset).ok()?,
+        };
+        let mut ctx = UnwindContext::new();
+        let row = fde.unwind_info_for_address(&eh_frame, &self.bases, &mut ctx, vaddr).ok()?;
+
+        let cfa = match row.cfa() {
+            CfaRule::RegisterAndOffset { register, offset } => match *register {
+                X86_64::RSP => regs.sp.wrapping_add_signed(*offset),
+                X86_64::RBP => regs.bp.wrapping_add_signed(*offset),
+                _ => return None,
+            },
+            CfaRule::Expression(_) => return None,
+        };
+        let ip = match row.register(X86_64::RA) {
+            RegisterRule::Offset(offset) => read(cfa.wrapping_add_signed(offset))?,
+            _ => return None,
+        };
+        let bp = match row.register(X86_64::RBP) {
+            RegisterRule::Offset(offset) => read(cfa.wrapping_add_signed(offset))?,
+            // Callee-saved registers without a rule still hold the caller's value
+            RegisterRule::Undefined | RegisterRule::SameValue => regs.bp,
+            _ => return None,
+        };
+        Some(Registers { ip, sp: cfa, bp })
+    }
+}
+
+/// Walks native call stacks using `.eh_frame` unwind info.
+///
+/// Frames in code without unwind info (JIT code, hand-written assembly) are
+/// unwound through the frame pointer instead.
+pub struct NativeUnwinder {
+    modules: SharedModules,
+    /// `None` for binaries whose unwind info could not be read, so they are not retried
+    tables: HashMap<ModuleId, Option<UnwindTable>>,
+}
+
+impl NativeUnwinder {
+    pub fn new(modules: SharedModules) -> Self {
+        Self { modules, tables: HashMap::new() }
+    }
+
+    /// Unwinds the stack of a stopped thread, innermost frame first.
+    ///
+    /// `locate` normalizes an address to its binary and `read` reads a word of the
+    /// target's memory.
+    pub fn unwind(
+        &mut self,
+        regs: Registers,
+        mut locate: impl FnMut(u64) -> Option<ModuleAddress>,
+        mut read: impl FnMut(u64) -> Option<u64>,
+    ) -> Vec<StackFrame> {
+        let mut frames = Vec::new();
+        let mut regs = regs;
+        while frames.len() < MAX_FRAMES {
+            // Return addresses point after the call; the call itself is what ran
+            let lookup = if frames.is_empty() { regs.ip } else { regs.ip - 1 };
+            let location = locate(lookup);
+            frames.push(StackFrame::Native { address: regs.ip, location });
+
+            let caller = location
+                .and_then(|location| self.step_cfi(location, &regs, &mut read))
+                .or_else(|| step_frame_pointer(&regs, &mut read));
+            match caller {
+                // The stack grows down, so every caller frame has to be above the last one
+                Some(caller) if caller.ip != 0 && caller.sp > regs.sp => regs = caller,
+                _ => break,
+            }
+        }
+        frames
+    }
+
+    fn step_cfi(
+        &mut self,
+        location: ModuleAddress,
+        regs: &Registers,
+        read: &mut impl FnMut(u64) -> Option<u64>,
+    ) -> Option<Registers> {
+        let modules = &self.modules;
+        let table = self
+            .tables
+            .entry(location.module)
+            .or_insert_with(|| {
+                let module = modules.lock().unwrap().get(location.module)?;
+                UnwindTable::load(&module).ok()
+            })
+            .as_ref()?;
+        let vaddr = table.segments.offset_to_vaddr(location.offset)?;
+        table.step(vaddr, regs, read)
+    }
+}
+
+/// Unwinds one frame assuming the standard `push rbp; mov rbp, rsp` prologue.
+fn step_frame_pointer(regs: &Registers, read: &mut impl FnMut(u64) -> Option<u64>) -> Option<Registers> {
+    if regs.bp < regs.sp || !regs.bp.is_multiple_of(8) {
+        return None;
+    }
+    Some(Registers { ip: read(regs.bp + 8)?, sp: regs.bp + 16, bp: read(regs.bp)? })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frame_pointer_chain() {
+        // Three frames linked through saved rbp values, the outermost with rbp = 0
+        let memory: HashMap<u64, u64> = [
+            (0x7000, 0x7040),
+            (0x7008, 0x401234),
+            (0x7040, 0x7100),
+            (0x7048, 0x401567),
+            (0x7100, 0),
+            (0x7108, 0),
+        ]
+        .into();
+        let mut unwinder = NativeUnwinder::new(SharedModules::default());
+        let frames = unwinder.unwind(Registers { ip: 0x401000, sp: 0x6ff0, bp: 0x7000 }, |_| None, |addr| {
+            memory.get(&addr).copied()
+        });
+        let addresses: Vec<u64> = frames
+            .iter()
+            .map(|f| match f {
+                StackFrame::Native { address, .. } => *address,
+                _ => unreachable!(),
+            })
+            .collect();
+        assert_eq!(addresses, vec![0x401000, 0x401234, 0x401567]);
+    }
+
+    #[test]
+    fn test_cfi_of_own_code() {
+        // Unwinding through this test binary's .eh_frame must find some caller frames
+        let exe = fs::read("/proc/self/exe").unwrap();
+        let table = UnwindTable::parse(&exe).unwrap();
+        let object = object::File::parse(&*exe).unwrap();
+        let text = object.section_by_name(".text").unwrap();
+        let found = (0..64).map(|i| text.address() + i * 0x100).filter(|&vaddr| {
+            table.step(vaddr, &Registers { ip: vaddr, sp: 0x1000, bp: 0x2000 }, &mut |_| Some(0x1234)).is_some()
+        });
+        assert!(found.count() > 0);
+    }
+}